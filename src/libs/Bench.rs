@@ -0,0 +1,277 @@
+/*!
+ *  Throughput benchmarks
+ *
+ *  Runs guest workloads that each stress one part of the emulator and reports guest
+ *  MIPS: millions of instructions retired per second of wall-clock time spent in
+ *  Core::run. Building and loading the Cores is not timed.
+ *
+ *      perf_test   testbins/perf_test_newcompile.relf, once per 4096 iterations
+ *      arith       a loop of ALU instructions
+ *      memory      word and byte loads and stores walking a 16K array
+ *      syscall     a syscall per iteration, into a handler that returns at once
+ *      interrupts  the arith loop with a virtual clock interrupt every 20 cycles
+ *      devices     stores to the keyboard's mode register, through device dispatch
+ *
+ *  The synthetic workloads are raw images, like a .bin: a handler at 0 that halts on
+ *  syscall 10 and returns from anything else, the program at 0x4000 and its data at
+ *  0x8000. Every one but interrupts runs without a clock
+ */
+
+use super::Core::Core;
+use super::Definitions::Arch::{self, OP};
+use super::Definitions::Arch::RegNames::*;
+use super::Definitions::Utils::{self, Word};
+use super::Devices::Interruptor::ClockMode;
+
+use std::fmt::Write;
+use std::time::Instant;
+
+pub const PERF_TEST: &str = "testbins/perf_test_newcompile.relf";
+
+const PROGRAM: usize = 0x4000;
+const DATA: usize    = 0x8000;
+const IMAGE: usize   = 0xc000;
+
+pub struct Workload {
+    pub name: &'static str,
+    // the Cores to run, one after the other, for a number of iterations
+    cores: fn(u32) -> Result<Vec<Core>, String>
+}
+
+pub const WORKLOADS: [Workload; 6] = [
+    Workload { name: "perf_test",  cores: perf_test },
+    Workload { name: "arith",      cores: |n| Ok(vec![synthetic(&arith(n), ClockMode::Off)]) },
+    Workload { name: "memory",     cores: |n| Ok(vec![synthetic(&memory(n), ClockMode::Off)]) },
+    Workload { name: "syscall",    cores: |n| Ok(vec![synthetic(&syscall(n), ClockMode::Off)]) },
+    Workload { name: "interrupts", cores: |n| Ok(vec![synthetic(&arith(n), ClockMode::Virtual(20))]) },
+    Workload { name: "devices",    cores: |n| Ok(vec![synthetic(&devices(n), ClockMode::Off)]) }
+];
+
+/**
+ * The runs of a workload
+ */
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub name: &'static str,
+    // retired per run
+    pub instructions: u64,
+    pub seconds: Vec<f64>
+}
+
+impl Measurement {
+
+    /**
+     * Guest MIPS of the median run
+     */
+    pub fn mips(&self) -> f64 {
+        let mut sorted = self.seconds.clone();
+        sorted.sort_by(f64::total_cmp);
+        Self::rate(self.instructions, sorted[sorted.len() / 2])
+    }
+
+    /**
+     * Guest MIPS of the fastest run
+     */
+    pub fn best(&self) -> f64 {
+        Self::rate(self.instructions, self.seconds.iter().copied().fold(f64::INFINITY, f64::min))
+    }
+
+    fn rate(instructions: u64, seconds: f64) -> f64 {
+        if seconds > 0.0 { instructions as f64 / seconds / 1e6 } else { 0.0 }
+    }
+}
+
+/**
+ * Runs a workload
+ *
+ * ARGS:
+ *
+ *  name: One of WORKLOADS
+ *
+ *  iterations: Loop iterations of the synthetic workloads; perf_test runs once per 4096
+ *
+ *  runs: Times the workload is run, each on fresh Cores
+ *
+ *  configure: Called on every Core before it runs, to turn on the JIT or caches
+ *
+ * RETURNS:
+ *
+ *  The instructions and time of every run, or why a Core could not be built or faulted
+ */
+pub fn measure(name: &str, iterations: u32, runs: usize, configure: &dyn Fn(&mut Core)) -> Result<Measurement, String> {
+
+    let workload = WORKLOADS.iter().find(|w| w.name == name)
+        .ok_or_else(|| format!("Unknown workload '{name}'; expected one of {}", names().join(", ")))?;
+
+    let mut m = Measurement { name: workload.name, instructions: 0, seconds: Vec::new() };
+
+    for _ in 0..runs.max(1) {
+        let mut cores = (workload.cores)(iterations.max(1))?;
+        let (mut instructions, mut seconds) = (0, 0.0);
+
+        for cpu in &mut cores {
+            configure(cpu);
+            let start = Instant::now();
+            cpu.run().map_err(|eobj| format!("{name} faulted: {eobj}"))?;
+            seconds += start.elapsed().as_secs_f64();
+            instructions += cpu.instr_count();
+        }
+
+        m.instructions = instructions;
+        m.seconds.push(seconds);
+    }
+
+    Ok(m)
+}
+
+pub fn names() -> Vec<&'static str> {
+    WORKLOADS.iter().map(|w| w.name).collect()
+}
+
+/**
+ * The table printed by the bench command
+ */
+pub fn report(results: &[Measurement]) -> String {
+    let mut out = format!("{:<12} {:>14} {:>10} {:>10} {:>10}\n", "workload", "instructions", "median s", "MIPS", "best MIPS");
+    for m in results {
+        let mut sorted = m.seconds.clone();
+        sorted.sort_by(f64::total_cmp);
+        let _ = writeln!(out, "{:<12} {:>14} {:>10.4} {:>10.2} {:>10.2}", m.name, m.instructions, sorted[sorted.len() / 2], m.mips(), m.best());
+    }
+    out
+}
+
+/**
+ * The results as a JSON object by workload, for --json
+ */
+pub fn to_json(results: &[Measurement]) -> String {
+    let rows: Vec<String> = results.iter().map(|m| {
+        let seconds: Vec<String> = m.seconds.iter().map(f64::to_string).collect();
+        format!("  \"{}\": {{ \"instructions\": {}, \"seconds\": [{}], \"mips\": {}, \"best_mips\": {} }}", m.name, m.instructions, seconds.join(", "), m.mips(), m.best())
+    }).collect();
+    format!("{{\n{}\n}}", rows.join(",\n"))
+}
+
+fn perf_test(iterations: u32) -> Result<Vec<Core>, String> {
+    (0..(iterations / 4096).max(1)).map(|_| {
+        let mut cpu = Core::with_clock(ClockMode::Virtual(Arch::CLOCK_PERIOD_CYCLES));
+        cpu.load_RELF(PERF_TEST).map_err(|eobj| format!("Could not load {PERF_TEST}: {eobj}"))?;
+        Ok(cpu)
+    }).collect()
+}
+
+fn synthetic(program: &[Word], clock: ClockMode) -> Core {
+
+    let handler = [
+        OP::NOP,                        //never run, exceptions enter the handler at 0 past it
+        i(OP::I::ADDIU, ZERO, K0, 10),
+        i(OP::I::BEQ, V0, K0, 1),       //syscall 10 halts
+        OP::RFE,
+        OP::HLT
+    ];
+
+    let mut image = Utils::image_of(&[(0, &handler[..]), (PROGRAM, program)]);
+    image.resize(IMAGE, 0);
+
+    let mut cpu = Core::with_clock(clock);
+    cpu.load_image(&image, PROGRAM as u32);
+    cpu
+}
+
+fn arith(iterations: u32) -> Vec<Word> {
+    let body = [
+        r(OP::R::ADDU, T0, S0, T0, 0),
+        r(OP::R::XOR, T1, T0, T1, 0),
+        r(OP::R::SLL, ZERO, T1, T2, 3),
+        r(OP::R::OR, T2, T0, T3, 0),
+        r(OP::R::AND, T3, T1, T4, 0)
+    ];
+    counted(iterations, &[], &body)
+}
+
+fn memory(iterations: u32) -> Vec<Word> {
+    let data = DATA as u16;
+    let body = [
+        i(OP::I::ADDIU, T1, T1, 4),
+        i(OP::I::ANDI, T1, T2, 0x3ffc),
+        i(OP::I::LW, T2, T0, data),
+        r(OP::R::ADDU, T0, S0, T0, 0),
+        i(OP::I::SW, T2, T0, data),
+        i(OP::I::LBU, T2, T3, data + 1),
+        i(OP::I::SB, T2, T3, data + 2)
+    ];
+    counted(iterations, &[], &body)
+}
+
+fn syscall(iterations: u32) -> Vec<Word> {
+    counted(iterations, &[i(OP::I::ADDIU, ZERO, V0, 1)], &[OP::SYSCALL])
+}
+
+fn devices(iterations: u32) -> Vec<Word> {
+    let setup = [
+        i(OP::I::ADDIU, ZERO, S2, 0x8000),
+        r(OP::R::SLL, ZERO, S2, S2, 16),
+        i(OP::I::ORI, S2, S2, 8)            //the keyboard
+    ];
+    let body = [
+        i(OP::I::SB, S2, S0, 4),            //mode register
+        i(OP::I::SB, S2, T0, 4)
+    ];
+    counted(iterations, &setup, &body)
+}
+
+/**
+ * A program running body iterations times, counting down in $s0, then syscall 10
+ */
+fn counted(iterations: u32, setup: &[Word], body: &[Word]) -> Vec<Word> {
+    let mut code = vec![
+        i(OP::I::ADDIU, ZERO, S0, (iterations >> 16) as u16),
+        r(OP::R::SLL, ZERO, S0, S0, 16),
+        i(OP::I::ORI, S0, S0, iterations as u16),
+        i(OP::I::ADDIU, ZERO, S1, 1)
+    ];
+    code.extend_from_slice(setup);
+    code.extend_from_slice(body);
+    code.push(r(OP::R::SUBU, S0, S1, S0, 0));
+    //back to the first instruction of body
+    code.push(i(OP::I::BNE, S0, ZERO, (-(body.len() as i32 + 2)) as u16));
+    code.push(i(OP::I::ADDIU, ZERO, V0, 10));
+    code.push(OP::SYSCALL);
+    code
+}
+
+fn r(func: u32, rs: usize, rt: usize, rd: usize, sham: u32) -> Word {
+    (rs as u32) << 21 | (rt as u32) << 16 | (rd as u32) << 11 | sham << 6 | func
+}
+
+fn i(op: u32, rs: usize, rt: usize, imm: u16) -> Word {
+    op << 26 | (rs as u32) << 21 | (rt as u32) << 16 | imm as u32
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn workloads_run() {
+    for name in names() {
+        let m = measure(name, 100, 2, &|_| {}).unwrap();
+        assert!(m.instructions >= 200, "{name} retired {}", m.instructions);
+        assert_eq!(m.seconds.len(), 2);
+        assert!(m.best() >= m.mips());
+    }
+    assert!(measure("nothing", 1, 1, &|_| {}).is_err());
+
+    //the loops run as many times as asked
+    let mut c = synthetic(&syscall(100), ClockMode::Off);
+    c.run().unwrap();
+    assert_eq!(c.stats().syscalls, 101);
+
+    let mut c = synthetic(&arith(100), ClockMode::Off);
+    c.run().unwrap();
+    assert_eq!(c.instr_count(), 4 + 7*100 + 2 + 3);
+
+    let mut c = synthetic(&arith(1000), ClockMode::Virtual(20));
+    c.run().unwrap();
+    assert!(c.stats().interrupts > 100);
+}
@@ -0,0 +1,343 @@
+/*!
+ *  Cache hierarchy simulator
+ *
+ *  Caches only keep tags: data is always read from and written to Memory, so caching
+ *  never changes what a program computes, only the cycles it takes and the hit and
+ *  miss counts in Stats. Device addresses are uncached
+ *
+ *  A hierarchy has an optional instruction cache and data cache in front of an
+ *  optional unified L2. An access costs the latency of every level it reaches, plus
+ *  the memory latency if it misses everywhere. Line fills, dirty write-backs and
+ *  write-through stores are charged to the level below
+ *
+ *  Caches are described by comma separated "key=value" specs:
+ *
+ *      size:    total size in bytes, with an optional K or M suffix
+ *      ways:    associativity, 1 for direct mapped
+ *      line:    line size in bytes
+ *      policy:  replacement policy, lru, fifo or random
+ *      write:   back or through
+ *      alloc:   yes or no, whether a store miss fills the line
+ *      latency: cycles to access the cache
+ */
+
+use super::Definitions::Stats::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub size: usize,
+    pub ways: usize,
+    pub line: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    pub write_allocate: bool,
+    pub latency: u64
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { size: 4096, ways: 1, line: 16, replacement: Replacement::Lru, write: WritePolicy::WriteBack, write_allocate: true, latency: 1 }
+    }
+}
+
+impl CacheConfig {
+
+    /**
+     * Reads a cache spec such as "size=8K,ways=2,line=32,policy=lru", starting from the defaults
+     */
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+
+        let mut config = CacheConfig::default();
+
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+
+            let (key, value) = item.split_once('=').ok_or_else(|| format!("Cache spec: expected 'key=value', got '{item}'"))?;
+            let (key, value) = (key.trim(), value.trim().to_lowercase());
+
+            let number = |v: &str| -> Result<usize, String> {
+                let (digits, scale) = match v.strip_suffix('k') {
+                    Some(d) => (d, 1 << 10),
+                    None => match v.strip_suffix('m') { Some(d) => (d, 1 << 20), None => (v, 1) }
+                };
+                digits.parse::<usize>().map(|n| n * scale).map_err(|_| format!("Cache spec: invalid {key} '{v}'"))
+            };
+
+            match key {
+                "size"    => config.size = number(&value)?,
+                "ways"    => config.ways = number(&value)?,
+                "line"    => config.line = number(&value)?,
+                "latency" => config.latency = number(&value)? as u64,
+                "policy"  => config.replacement = match value.as_str() {
+                    "lru" => Replacement::Lru,
+                    "fifo" => Replacement::Fifo,
+                    "random" => Replacement::Random,
+                    _ => return Err(format!("Cache spec: unknown policy '{value}'"))
+                },
+                "write"   => config.write = match value.as_str() {
+                    "back" => WritePolicy::WriteBack,
+                    "through" => WritePolicy::WriteThrough,
+                    _ => return Err(format!("Cache spec: unknown write policy '{value}'"))
+                },
+                "alloc"   => config.write_allocate = match value.as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(format!("Cache spec: alloc is yes or no, got '{value}'"))
+                },
+                _ => return Err(format!("Cache spec: unknown key '{key}'"))
+            }
+        }
+
+        if !config.line.is_power_of_two() || config.line < 4 {
+            return Err(format!("Cache spec: line size {} is not a power of two of at least 4", config.line));
+        }
+        if config.ways == 0 || config.size % (config.ways * config.line) != 0 || !(config.size / (config.ways * config.line)).is_power_of_two() {
+            return Err(format!("Cache spec: {} bytes do not split into a power of two sets of {} ways of {} bytes", config.size, config.ways, config.line));
+        }
+
+        Ok(config)
+    }
+
+    pub fn sets(&self) -> usize {
+        self.size / (self.ways * self.line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    tag: u32,
+    valid: bool,
+    dirty: bool,
+    // last use for LRU, fill for FIFO
+    stamp: u64
+}
+
+/**
+ * What a single cache access did
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheAccess {
+    pub hit: bool,
+    // a miss that brought the line in
+    pub filled: bool,
+    pub evicted: bool,
+    // address of the dirty line that was evicted
+    pub writeback: Option<u32>
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    pub name: &'static str,
+    pub config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    // xorshift state for random replacement, fixed so runs are reproducible
+    seed: u32
+}
+
+impl Cache {
+
+    pub fn new(name: &'static str, config: CacheConfig) -> Cache {
+        let sets = vec![vec![Line::default(); config.ways]; config.sets()];
+        Cache { name, config, sets, clock: 0, seed: 0x9e3779b9 }
+    }
+
+    /**
+     * Looks addr up, filling and evicting lines as the policies say
+     *
+     * ARGS:
+     *
+     *  write: The access is a store
+     */
+    pub fn access(&mut self, addr: u32, write: bool) -> CacheAccess {
+
+        self.clock += 1;
+
+        let nsets = self.sets.len() as u32;
+        let block = addr / self.config.line as u32;
+        let (index, tag) = ((block % nsets) as usize, block / nsets);
+        let write_back = self.config.write == WritePolicy::WriteBack;
+
+        let set = &mut self.sets[index];
+
+        if let Some(line) = set.iter_mut().find(|l| l.valid && l.tag == tag) {
+            if self.config.replacement == Replacement::Lru { line.stamp = self.clock; }
+            if write && write_back { line.dirty = true; }
+            return CacheAccess { hit: true, ..Default::default() };
+        }
+
+        if write && !self.config.write_allocate {
+            return CacheAccess::default();
+        }
+
+        let victim = match set.iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None => match self.config.replacement {
+                Replacement::Random => {
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 17;
+                    self.seed ^= self.seed << 5;
+                    self.seed as usize % set.len()
+                }
+                _ => (0..set.len()).min_by_key(|w| set[*w].stamp).unwrap()
+            }
+        };
+
+        let old = set[victim];
+        set[victim] = Line { tag, valid: true, dirty: write && write_back, stamp: self.clock };
+
+        CacheAccess {
+            hit: false,
+            filled: true,
+            evicted: old.valid,
+            writeback: (old.valid && old.dirty).then(|| (old.tag * nsets + index as u32) * self.config.line as u32)
+        }
+    }
+
+    /**
+     * Drops every line, dirty ones included
+     */
+    pub fn invalidate(&mut self) {
+        for line in self.sets.iter_mut().flatten() { *line = Line::default(); }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    Fetch,
+    Load,
+    Store
+}
+
+/**
+ * Instruction and data caches over an optional unified L2
+ */
+#[derive(Debug, Clone)]
+pub struct Hierarchy {
+    pub icache: Option<Cache>,
+    pub dcache: Option<Cache>,
+    pub l2: Option<Cache>,
+    // cycles to reach memory past the last level
+    pub memory_latency: u64
+}
+
+impl Hierarchy {
+
+    /**
+     * Cycles taken by an access through the hierarchy, counting hits and misses in stats
+     */
+    pub fn access(&mut self, port: Port, addr: u32, stats: &mut Stats) -> u64 {
+
+        let Hierarchy { icache, dcache, l2, memory_latency } = self;
+        let memory_latency = *memory_latency;
+        let write = port == Port::Store;
+
+        let mut below = |addr: u32, write: bool, stats: &mut Stats| match l2 {
+            Some(l2) => level(l2, addr, write, stats, &mut |_, _, _| memory_latency),
+            None => memory_latency
+        };
+
+        match if port == Port::Fetch { icache } else { dcache } {
+            Some(l1) => level(l1, addr, write, stats, &mut below),
+            None => below(addr, write, stats)
+        }
+    }
+
+    pub fn invalidate(&mut self) {
+        for cache in [&mut self.icache, &mut self.dcache, &mut self.l2].into_iter().flatten() { cache.invalidate(); }
+    }
+}
+
+/**
+ * Accesses one level, charging fills, write-backs and write-through stores to below
+ */
+fn level(cache: &mut Cache, addr: u32, write: bool, stats: &mut Stats, below: &mut dyn FnMut(u32, bool, &mut Stats) -> u64) -> u64 {
+
+    let access = cache.access(addr, write);
+    stats.cache(cache.name, &access);
+
+    let mut cycles = cache.config.latency;
+    if let Some(victim) = access.writeback { cycles += below(victim, true, stats); }
+    if access.filled { cycles += below(addr, false, stats); }
+    if write && (cache.config.write == WritePolicy::WriteThrough || !(access.hit || access.filled)) {
+        cycles += below(addr, true, stats);
+    }
+    cycles
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn parse_spec() {
+    let c = CacheConfig::parse("size=8K, ways=2, line=32, policy=fifo, write=through, alloc=no, latency=2").unwrap();
+    assert_eq!((c.size, c.ways, c.line, c.sets()), (8192, 2, 32, 128));
+    assert_eq!((c.replacement, c.write, c.write_allocate, c.latency), (Replacement::Fifo, WritePolicy::WriteThrough, false, 2));
+
+    assert_eq!(CacheConfig::parse("").unwrap(), CacheConfig::default());
+    assert!(CacheConfig::parse("line=24").is_err());
+    assert!(CacheConfig::parse("size=4K,ways=3").is_err());
+    assert!(CacheConfig::parse("policy=mru").is_err());
+    assert!(CacheConfig::parse("colour=blue").is_err());
+}
+
+#[test]
+fn replacement() {
+    //two sets of two 16 byte lines; 0x00, 0x20 and 0x40 all map to set 0
+    let config = CacheConfig { size: 64, ways: 2, ..Default::default() };
+
+    let mut lru = Cache::new("L1D", config.clone());
+    assert!(!lru.access(0x00, false).hit);
+    assert!(!lru.access(0x20, false).hit);
+    assert!(lru.access(0x04, false).hit);
+    let a = lru.access(0x40, false);
+    assert!(a.evicted && a.writeback.is_none());
+    //LRU evicted 0x20, FIFO evicts 0x00
+    assert!(lru.access(0x00, false).hit);
+
+    let mut fifo = Cache::new("L1D", CacheConfig { replacement: Replacement::Fifo, ..config.clone() });
+    for addr in [0x00, 0x20, 0x04, 0x40] { fifo.access(addr, false); }
+    assert!(fifo.access(0x20, false).hit);
+    assert!(!fifo.access(0x00, false).hit);
+
+    //a dirty line is written back when evicted
+    let mut wb = Cache::new("L1D", config);
+    wb.access(0x24, true);
+    wb.access(0x00, false);
+    wb.access(0x00, false);
+    assert_eq!(wb.access(0x40, false).writeback, Some(0x20));
+}
+
+#[test]
+fn hierarchy_latency() {
+    use super::Definitions::Stats;
+
+    let mut stats = Stats::new();
+    let l1 = CacheConfig { size: 64, ways: 1, latency: 1, ..Default::default() };
+    let l2 = CacheConfig { size: 256, ways: 2, latency: 10, ..Default::default() };
+    let mut h = Hierarchy { icache: None, dcache: Some(Cache::new("L1D", l1)), l2: Some(Cache::new("L2", l2)), memory_latency: 100 };
+
+    assert_eq!(h.access(Port::Load, 0x100, &mut stats), 111);
+    assert_eq!(h.access(Port::Load, 0x104, &mut stats), 1);
+    //0x140 evicts 0x100 from L1, which is still in L2
+    assert_eq!(h.access(Port::Load, 0x140, &mut stats), 111);
+    assert_eq!(h.access(Port::Load, 0x100, &mut stats), 11);
+    //no instruction cache: fetches go to L2
+    assert_eq!(h.access(Port::Fetch, 0x140, &mut stats), 10);
+
+    assert_eq!((stats.caches["L1D"].hits, stats.caches["L1D"].misses, stats.caches["L1D"].evictions), (1, 3, 2));
+    assert_eq!((stats.caches["L2"].hits, stats.caches["L2"].misses), (2, 2));
+}
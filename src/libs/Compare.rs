@@ -0,0 +1,204 @@
+use super::Core::Core;
+use super::Definitions::Disasm;
+use super::Definitions::Trace::{Tracer, TraceFormat, TraceReader, TraceRecord, reg_name};
+use super::Definitions::Disasm::{REG_HI, REG_LO};
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, Write};
+
+// matching instructions shown before a divergence
+const CONTEXT: usize = 5;
+
+/**
+ * How a lockstep comparison ended
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    // the runs agreed for this many instructions until both ended
+    Match(u64),
+    // the runs diverged at this instruction count
+    Diverged(u64)
+}
+
+/**
+ * Runs core in lockstep with a reference trace and stops at the first divergence
+ *
+ * After every instruction, the emulator's trace record is checked against the
+ * reference's. If the reference carries effects, the whole register file is checked
+ * too, against the initial registers of core updated with the reference's writes.
+ * SPIM/MARS-style listings only allow checking the PC and instruction word
+ *
+ * ARGS:
+ *
+ *  core: A loaded Core. A trace is started on it if it is not tracing already
+ *
+ *  reference: The reference trace
+ *
+ *  out: Where the divergence report is written
+ */
+pub fn compare<R: BufRead, W: Write>(core: &mut Core, reference: &mut TraceReader<R>, out: &mut W) -> io::Result<Outcome> {
+
+    if core.trace_record().is_none() {
+        core.start_trace(Tracer::new(Box::new(io::sink()), TraceFormat::Binary)?);
+    }
+
+    let mut expected = registers(core);
+    let mut context: VecDeque<(u64, u32, u32)> = VecDeque::new();
+
+    loop {
+        let res = core.step();
+        let count = core.instr_count() + res.is_err() as u64;
+        let ours = core.trace_record().cloned().unwrap_or_default();
+
+        let theirs = match reference.next_record()? {
+            Some(theirs) => theirs,
+            None => {
+                writeln!(out, "Diverged at instruction {count}: the reference trace ended, the emulator executed {:08x} {}", ours.pc, Disasm::disassemble(ours.code))?;
+                return Ok(Outcome::Diverged(count));
+            }
+        };
+
+        for (r, v) in &theirs.regs { expected[*r as usize] = *v; }
+        let actual = registers(core);
+
+        let same = ours.pc == theirs.pc && ours.code == theirs.code
+            && (!reference.has_effects() || (ours.mem == theirs.mem && ours.exception == theirs.exception && actual == expected));
+
+        if !same {
+            writeln!(out, "Diverged at instruction {count}\n")?;
+            report(out, &theirs, &ours, reference.has_effects().then_some((&expected, &actual)))?;
+            if !context.is_empty() { writeln!(out, "\nLast matching instructions:")?; }
+            for (n, pc, code) in &context {
+                writeln!(out, "  #{n:<8} {pc:08x}  {}", Disasm::disassemble(*code))?;
+            }
+            return Ok(Outcome::Diverged(count));
+        }
+
+        match res {
+            Err(eobj) => {
+                writeln!(out, "Both runs faulted at instruction {count}: {eobj}")?;
+                return Ok(Outcome::Match(count));
+            }
+            Ok(true) => {
+                if let Some(next) = reference.next_record()? {
+                    writeln!(out, "Diverged at instruction {}: the emulator finished, the reference executed {:08x} {}", count + 1, next.pc, Disasm::disassemble(next.code))?;
+                    return Ok(Outcome::Diverged(count + 1));
+                }
+                writeln!(out, "Traces match for all {count} instructions")?;
+                return Ok(Outcome::Match(count));
+            }
+            Ok(false) => {}
+        }
+
+        context.push_back((count, ours.pc, ours.code));
+        if context.len() > CONTEXT { context.pop_front(); }
+    }
+}
+
+/**
+ * GPRs, HI and LO of core, indexed like trace records
+ */
+fn registers(core: &Core) -> [u32; 34] {
+    let ctx = core.snapshot(core.get_PC(), 0);
+    let mut regs = [0; 34];
+    regs[..32].copy_from_slice(&ctx.regs);
+    regs[REG_HI as usize] = ctx.hi;
+    regs[REG_LO as usize] = ctx.lo;
+    regs
+}
+
+/**
+ * Prints the reference and emulator records side by side, marking differences with <<
+ */
+fn report<W: Write>(out: &mut W, theirs: &TraceRecord, ours: &TraceRecord, regs: Option<(&[u32; 34], &[u32; 34])>) -> io::Result<()> {
+
+    writeln!(out, "             reference                            emulator")?;
+
+    let mut row = |name: &str, t: String, o: String| {
+        let mark = if t != o { "  <<" } else { "" };
+        writeln!(out, "  {name:<10} {t:<36} {o}{mark}")
+    };
+
+    row("pc", format!("{:08x}", theirs.pc), format!("{:08x}", ours.pc))?;
+    row("word", format!("{:08x} {}", theirs.code, Disasm::disassemble(theirs.code)), format!("{:08x} {}", ours.code, Disasm::disassemble(ours.code)))?;
+
+    let (expected, actual) = match regs {
+        Some(regs) => regs,
+        None => return Ok(())
+    };
+
+    for r in 0..34 {
+        if expected[r] != actual[r] {
+            row(&format!("${}", reg_name(r as u32)), format!("{:08x}", expected[r]), format!("{:08x}", actual[r]))?;
+        }
+    }
+
+    let accesses = |t: &TraceRecord| match t.mem.is_empty() {
+        true => String::from("-"),
+        false => TraceRecord { regs: Vec::new(), exception: None, ..t.clone() }.to_string()
+    };
+    row("accesses", accesses(theirs), accesses(ours))?;
+
+    let exception = |t: &TraceRecord| t.exception.map(|e| e.to_string()).unwrap_or_else(|| String::from("-"));
+    row("exception", exception(theirs), exception(ours))
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn lockstep_match() {
+    use super::Devices::Interruptor::ClockMode;
+
+    let reference = reference_of("testbins/testingLS.s.relf");
+    let lines = reference.lines().count() as u64;
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    let mut out = Vec::new();
+    assert_eq!(compare(&mut c, &mut TraceReader::new(reference.as_bytes()).unwrap(), &mut out).unwrap(), Outcome::Match(lines));
+}
+
+#[test]
+fn lockstep_divergence() {
+    use super::Devices::Interruptor::ClockMode;
+
+    //the reference says the 4th instruction left 5 in $v0
+    let reference = reference_of("testbins/testingLS.s.relf").replacen("$v0=00000004", "$v0=00000005", 1);
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    let mut out = Vec::new();
+    assert_eq!(compare(&mut c, &mut TraceReader::new(reference.as_bytes()).unwrap(), &mut out).unwrap(), Outcome::Diverged(4));
+
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.starts_with("Diverged at instruction 4\n"));
+    assert!(report.contains("$v0        00000005                             00000004  <<"));
+    assert!(report.contains("#3        00400008  ori $a0, $at, -1"));
+
+    //a listing only checks the PC and word
+    let listing = "[0x00400000]\t0x24011000  addiu $1, $0, 4096\n[0x00400008]\t0x3424ffff\n";
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    let mut out = Vec::new();
+    assert_eq!(compare(&mut c, &mut TraceReader::new(listing.as_bytes()).unwrap(), &mut out).unwrap(), Outcome::Diverged(2));
+}
+
+#[cfg(test)]
+fn reference_of(path: &str) -> String {
+    use super::Devices::Interruptor::ClockMode;
+
+    let file = std::env::temp_dir().join(format!("mips_cmp_{}_{}.txt", std::process::id(), path.replace('/', "_")));
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF(path).unwrap();
+    c.start_trace(Tracer::new(Box::new(std::fs::File::create(&file).unwrap()), TraceFormat::Text).unwrap());
+    c.run().unwrap();
+    c.finish_trace().unwrap();
+
+    let trace = std::fs::read_to_string(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    trace
+}
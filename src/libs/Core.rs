@@ -1,20 +1,42 @@
 use super::Memory::Memory;
+use super::Cache::{Hierarchy, Port};
+use super::Predictor::BranchUnit;
+#[cfg(feature = "jit")]
+use super::Jit::{Jit, GuestState};
 use super::Definitions::Utils::{Byte, Half, Word};
 use super::Definitions::{Utils, Stats};
 use super::Definitions::Arch;
-use super::Definitions::Arch::{OP, RegNames};
-
-use super::Definitions::Errors::{ExecutionError, HeaderError};
-
-use super::Devices::{MemoryMapped,Console,Keyboard,Interruptor};
+use super::Definitions::Arch::{OP, RegNames, CP0};
+
+use super::Definitions::CallStack::{CallStack, Frame, FrameKind};
+use super::Definitions::Journal::{Journal, SharedJournal};
+use super::Definitions::Snapshot;
+use super::Definitions::Disasm;
+use super::Definitions::Decode::{self, Decoded};
+use super::Definitions::Log;
+use super::Definitions::Timing::{TimingModel, TimingState, Retired};
+use super::Definitions::Trace::{Tracer, TraceException, TraceRecord};
+use super::Definitions::Snapshot::{SnapshotWriter, SnapshotReader, Payload, Fields, FaultRecord};
+use super::Definitions::Errors::{ExecutionError, ExecContext, Access, HeaderError, SnapshotError};
+
+use super::Devices::{MemoryMapped,Console,Keyboard,Interruptor,Pic,Timer};
+use super::Devices::Pic::{PicState, SharedPic};
+use super::Devices::Timer::{TimerUnit, SharedTimer, TimeBase, TIMER_LINE};
+use super::Devices::Interruptor::{ClockMode, VirtualInterruptor, InterruptLines, CLOCK_LINE};
 
 use crate::to_signed;
 use crate::to_signed_cond;
+use crate::log;
+#[cfg(feature = "jit")]
+use crate::log_enabled;
 
-use std::time::Duration;
-use std::sync::mpsc;
+use std::io::{BufRead, Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 
 pub struct Core {
@@ -28,19 +50,63 @@ pub struct Core {
     irq_handler_addr: u32,
     EPC: u32,
     IntEnableOnNext: bool,
-    verbose: bool,
-    interrupt_ch: mpsc::Receiver<u32>,
-    interrupt_ch_open: Arc<AtomicBool>
+    // raised by interruptor threads and devices
+    lines: InterruptLines,
+    // cleared to stop the interruptor threads, see shutdown
+    interruptors_open: Arc<AtomicBool>,
+    interruptors: Vec<JoinHandle<()>>,
+    symbols: Vec<(u32, String)>,
+    stats: Stats::Stats,
+    // whether step fills the opcode histogram of stats
+    count_opcodes: bool,
+    // set if the previous instruction was RFE, see step
+    iter_flag: bool,
+    call_stack: CallStack,
+    journal: SharedJournal,
+    // interrupt sources polled against the cycle count, see step
+    timers: Vec<VirtualInterruptor>,
+    // cycle count before which no timer fires; 0 to poll them all again, see poll_timers
+    next_timer: u64,
+    clock: ClockMode,
+    // the interrupt controller, also mapped as a device; timers and lines raise on it
+    pic: SharedPic,
+    // of the last exception taken, for Cause
+    exc_code: u32,
+    // the guest-programmable timer, also mapped as a device
+    timer: SharedTimer,
+    tracer: Option<Tracer>,
+    timing: TimingModel,
+    // timing.is_flat(), so step can skip costing instructions
+    flat_timing: bool,
+    timing_state: TimingState,
+    // loads and stores of the instruction being executed, to memory and to devices
+    accesses: (u64, u64),
+    caches: Option<Hierarchy>,
+    // spent in caches by the instruction being executed
+    cache_cycles: u64,
+    predictors: Option<BranchUnit>,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>
 }
 
 
 
 impl Core {
 
-    pub fn new(v: bool) -> Core {
+    pub fn new() -> Core {
+        Core::with_clock(ClockMode::Virtual(Arch::CLOCK_PERIOD_CYCLES))
+    }
+
+    /**
+     * Creates a Core whose clock interrupt is generated as given by clock
+     *
+     * A virtual clock fires at fixed cycle counts, so runs are reproducible.
+     * A real-time clock spawns an Interruptor thread instead
+     */
+    pub fn with_clock(clock: ClockMode) -> Core {
         //everything is supposed to be ok in this constructor, no need to use Result
     
-        let mut mem = Memory::new(v);
+        let mut mem = Memory::new();
         let mut reg = [0; 32];
 
         //init default irq_handler
@@ -48,13 +114,13 @@ impl Core {
         let DEFAULT_irq = Arch::DEFAULT_IRQH;
         let irq_addr: u32 = 0x0;
     
-        if v { println!("[CORE]: Setting up default IRQH with address 0x{:08x}",irq_addr) }
+        log!(Info, "CORE", "Setting up default IRQH with address 0x{:08x}",irq_addr);
     
         let stackbase = DEFAULT_irq.len() as u32 + 8;
         mem.store(irq_addr as usize, DEFAULT_irq.len(),&DEFAULT_irq).unwrap();
         mem.protect(irq_addr,stackbase - 4);
 
-        if v { println!("[CORE]: Setting up stack from 0x{:08x} to 0x{:08x}",stackbase, stackbase + Arch::STACKSIZE); }
+        log!(Info, "CORE", "Setting up stack from 0x{:08x} to 0x{:08x}",stackbase, stackbase + Arch::STACKSIZE);
 
         mem.protect(stackbase, stackbase + Arch::STACKSIZE);
         reg[RegNames::SP] = stackbase;
@@ -62,11 +128,18 @@ impl Core {
     
         //add basic mapped devices
         let console  = Box::new(Console::new() );
-        let keyboard = Box::new(Keyboard::new() );
+        let journal: SharedJournal = Rc::new(RefCell::new(Journal::default()));
+        let keyboard = Box::new(Keyboard::with_journal(journal.clone()) );
         mem.map_device( console.range_lower,console.range_upper, console  );
         mem.map_device( keyboard.range_lower, keyboard.range_upper, keyboard);
-    
-        let (send, recv) = mpsc::channel();
+
+        let pic_state: SharedPic = Rc::new(RefCell::new(PicState::default()));
+        let pic = Box::new(Pic::new(pic_state.clone()) );
+        mem.map_device( pic.range_lower, pic.range_upper, pic);
+
+        let timer: SharedTimer = Arc::new(TimerUnit::new(TimeBase::Cycles));
+        let timer_dev = Box::new(Timer::new(timer.clone(), Some(journal.clone())) );
+        mem.map_device( timer_dev.range_lower, timer_dev.range_upper, timer_dev);
     
         let mut core = Core {
             reg: reg,
@@ -78,15 +151,52 @@ impl Core {
             irq_handler_addr: irq_addr,
             EPC: 0,
             IntEnableOnNext: false,
-            verbose: v,
-            interrupt_ch: recv,
-            interrupt_ch_open: Arc::new(AtomicBool::new(true))
+            lines: InterruptLines::default(),
+            interruptors_open: Arc::new(AtomicBool::new(true)),
+            interruptors: Vec::new(),
+            symbols: vec![(irq_addr, String::from("__irq_handler"))],
+            stats: Stats::new(),
+            count_opcodes: false,
+            iter_flag: false,
+            call_stack: CallStack::default(),
+            journal,
+            timers: Vec::new(),
+            next_timer: 0,
+            clock,
+            tracer: None,
+            timing: TimingModel::default(),
+            flat_timing: false,
+            timing_state: TimingState::default(),
+            accesses: (0, 0),
+            caches: None,
+            cache_cycles: 0,
+            predictors: None,
+            pic: pic_state,
+            exc_code: 0,
+            timer,
+            #[cfg(feature = "jit")]
+            jit: None
         };
         core.set_flag(true, Arch::IENABLE_FLAG);
 
-        Interruptor::new_default("Clock", Duration::new(1, 0), &send, core.interrupt_ch_open.clone(), v);
+        match clock {
+            ClockMode::Virtual(period) => {
+                log!(Info, "CORE", "Virtual clock every {} cycles", period);
+                core.add_timer(VirtualInterruptor::periodic("Clock", period));
+            }
+            ClockMode::RealTime(period) => {
+                let clock = Interruptor::new_default("Clock", period, &core.lines, CLOCK_LINE, core.interruptors_open.clone());
+                core.interruptors.push(clock);
+
+                //the timer counts microseconds, polled from its own thread
+                core.timer.rebase(TimeBase::RealTime(Instant::now()));
+                let poller = Timer::poller(&core.timer, &core.lines, core.interruptors_open.clone());
+                core.interruptors.push(poller);
+            }
+            ClockMode::Off => {}
+        }
         
-        if v { println!("[CORE]: Created successfully!\n"); }
+        log!(Info, "CORE", "Created successfully!");
         
         core
     }
@@ -105,6 +215,8 @@ impl Core {
             Err(eobj) => return Err(eobj) //propagate
         }
 
+        self.add_symbol(self.PC, "_start");
+
         Ok(())
     }
 
@@ -123,20 +235,49 @@ impl Core {
     pub fn load_bin(&mut self, path: &str, entry: u32) -> Result<(), std::io::Error> {
 
         self.PC = entry;
+        self.add_symbol(entry, "_start");
         self.mem.load_bin(path)
 
     }
 
+    /**
+     * Loads a raw image into memory from address 0 and sets PC, like load_bin
+     *
+     * ARGS:
+     *
+     *  image: Contents of memory, IRQ handler included
+     *
+     *  entry: PC to start execution
+    */
+    pub fn load_image(&mut self, image: &[Byte], entry: u32) {
+
+        self.PC = entry;
+        self.add_symbol(entry, "_start");
+        self.mem.load_image(image);
+
+    }
+
     /**
      * Interrupts current execution and jumps to irqH
      *
+     * Pushes the KU/IE stack, so RFE returns to the mode and interrupt enable of the
+     * interrupted code. An enable still deferred after RFE counts as enabled
      */
 
     pub fn interrupt(&mut self) {
+        let deferred = std::mem::take(&mut self.IntEnableOnNext) | std::mem::take(&mut self.iter_flag);
+        let enabled = (self.flags & Arch::IENABLE_FLAG) != 0 || deferred;
+        let privileged = (self.flags & Arch::MODE_FLAG) != 0;
+        self.set_flag((self.flags & Arch::PREV_IENABLE_FLAG) != 0, Arch::OLD_IENABLE_FLAG);
+        self.set_flag((self.flags & Arch::PREV_MODE_FLAG) != 0, Arch::OLD_MODE_FLAG);
+        self.set_flag(enabled, Arch::PREV_IENABLE_FLAG);
+        self.set_flag(privileged, Arch::PREV_MODE_FLAG);
+
         self.set_flag(true, Arch::MODE_FLAG); //enter privileged mode
         self.mem.set_privileged(true);
         self.set_flag(false, Arch::IENABLE_FLAG); //disable interrupts
         self.EPC = self.PC;
+        self.call_stack.exception(self.EPC, self.irq_handler_addr);
         self.PC = u32::saturating_sub(self.irq_handler_addr, 4);
     }
 
@@ -152,7 +293,7 @@ impl Core {
     pub fn set_flag(&mut self,set: bool, flag: u32) {
 
 
-        if self.verbose { println!("[CORE]: Setting flag {flag} to {set}"); }
+        log!(Debug, "CORE", "Setting flag {flag} to {set}");
 
         if set {
 
@@ -194,6 +335,171 @@ impl Core {
         self.irq_handler_addr = irq_pc
     }
 
+    /**
+     * Adds a named symbol at addr, used to annotate addresses in reports
+     *
+     * ARGS:
+     *
+     *  addr: The address of the symbol
+     *
+     *  name: The name of the symbol
+     */
+    pub fn add_symbol(&mut self, addr: u32, name: &str) {
+        self.symbols.push( (addr, name.to_string()) );
+    }
+
+    /**
+     * Loads symbols from a text file with one symbol per line, in the
+     * form `<hex address> <name>`. `nm`-style lines with a type column
+     * in between are also accepted, and blank lines are skipped
+     *
+     * ARGS:
+     *
+     *  path: Path to the symbol file
+     */
+    pub fn load_symbols(&mut self, path: &str) -> Result<(), std::io::Error> {
+
+        let contents = std::fs::read_to_string(path)?;
+
+        for line in contents.lines() {
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 { continue; }
+
+            let addr = u32::from_str_radix(fields[0].trim_start_matches("0x").trim_start_matches("0X"), 16)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad symbol address '{}': {e}", fields[0])))?;
+
+            self.add_symbol(addr, fields[fields.len()-1]);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Finds the closest symbol at or below addr
+     *
+     * ARGS:
+     *
+     *  addr: The address to look up
+     *
+     * RETURNS:
+     *
+     *  The symbol name and the offset of addr from it, if any symbol precedes addr
+     */
+    pub fn nearest_symbol(&self, addr: u32) -> Option<(&str, u32)> {
+
+        self.symbols.iter()
+            .filter(|(sym_addr, _)| *sym_addr <= addr)
+            .max_by_key(|(sym_addr, _)| *sym_addr)
+            .map(|(sym_addr, name)| (name.as_str(), addr - sym_addr))
+    }
+
+    /**
+     * Formats an address with its nearest symbol, as in `0x00400010 <_start+0x10>`
+     */
+    pub fn symbolize(&self, addr: u32) -> String {
+
+        match self.nearest_symbol(addr) {
+            Some((name, 0)) => format!("0x{:08x} <{name}>", addr),
+            Some((name, off)) => format!("0x{:08x} <{name}+0x{:x}>", addr, off),
+            None => format!("0x{:08x}", addr)
+        }
+    }
+
+    /**
+     * Finds the address of a symbol by name
+     */
+    pub fn symbol_addr(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().rev().find(|(_, sym)| sym == name).map(|(addr, _)| *addr)
+    }
+
+    pub fn get_PC(&self) -> u32 {
+        self.PC
+    }
+
+    #[allow(dead_code)]
+    pub fn get_reg(&self, r: usize) -> Word {
+        self.reg[r]
+    }
+
+    /**
+     * Reads the instruction word at addr without going through protection or devices
+     *
+     * RETURNS:
+     *
+     *  The word, or None if addr is outside of allocated memory
+     */
+    pub fn fetch(&self, addr: u32) -> Option<Word> {
+        self.mem.peek(addr, 4).map(Utils::from_word)
+    }
+
+    /**
+     * Reads size bytes at addr without going through protection or devices
+     */
+    pub fn peek(&self, addr: u32, size: usize) -> Option<&[Byte]> {
+        self.mem.peek(addr, size)
+    }
+
+    /**
+     * Captures the architectural state of the Core
+     *
+     * ARGS:
+     *
+     *  pc: The PC to record
+     *
+     *  code: The instruction word to record
+     */
+    pub fn snapshot(&self, pc: u32, code: Word) -> ExecContext {
+        ExecContext { pc, code, regs: self.reg, hi: self.HI, lo: self.LO, flags: self.flags, epc: self.EPC }
+    }
+
+    /**
+     * Works out what an ALU instruction or a conditional branch computes from the given
+     * source values, with the same decoder and ALU as step, leaving the Core as it was
+     *
+     * Loads, stores, jumps, syscall, rfe and hlt have side effects outside the registers
+     * and must not be evaluated. Coprocessor 0 moves may be: Status, EPC, the deferred
+     * interrupt enables and the memory privilege they change are put back too
+     *
+     * ARGS:
+     *
+     *  operands: (register, value) of the registers code reads, HI and LO numbered as
+     *  Disasm::REG_HI and Disasm::REG_LO
+     *
+     * RETURNS:
+     *
+     *  The values written to Disasm::destinations(code), and whether a branch was taken
+     */
+    pub fn evaluate(&mut self, code: Word, operands: &[(u32, Word)]) -> Result<([Option<Word>; 2], bool), ExecutionError> {
+
+        let saved = (self.reg, self.HI, self.LO, self.PC, self.flags, self.EPC, self.IntEnableOnNext, self.iter_flag);
+
+        for (r, v) in operands {
+            match *r {
+                Disasm::REG_HI => self.HI = *v,
+                Disasm::REG_LO => self.LO = *v,
+                r => self.reg[r as usize] = *v
+            }
+        }
+        self.reg[RegNames::ZERO] = 0;
+
+        let d = Decode::decode(code);
+        let res = Log::quiet(|| if d.op == 0 { self.handoff_R(&d) } else { self.handoff_I(&d) });
+        let taken = self.PC != saved.3;
+        let values = Disasm::destinations(code).map(|d| d.map(|r| match r {
+            Disasm::REG_HI => self.HI,
+            Disasm::REG_LO => self.LO,
+            r => self.reg[r as usize]
+        }));
+
+        (self.reg, self.HI, self.LO, self.PC, self.flags, self.EPC, self.IntEnableOnNext, self.iter_flag) = saved;
+        if code & OP::MFC0_MASK == OP::MTC0 {
+            let privileged = (self.flags & Arch::MODE_FLAG) != 0;
+            Log::quiet(|| self.mem.set_privileged(privileged));
+        }
+        res.map(|_| (values, taken))
+    }
+
     /**
      * Starts running code at PC.
      *
@@ -201,118 +507,772 @@ impl Core {
     */
     pub fn run(&mut self) -> Result<(), ExecutionError> {
 
-        let mut stat = Stats::new();
+        loop {
+            #[cfg(feature = "jit")]
+            if self.run_block() { continue; }
 
-        let mut iter_flag = false;
+            if self.step()? { break; }
+        }
 
-        loop {
-            if self.verbose { println!("------------------"); }
+        let stat = &self.stats;
+        log!(Info, "CORE", "Finished execution in T={} s\n        CPI of {}. Executed {} instructions in {} cycles.",stat.exec_total_time().as_secs_f64(),stat.CPI(),stat.instr_count, stat.cycl_count);
 
-            self.run_handoff(self.PC)?;
-            stat.cycle_incr();
-            stat.instr_incr();
+        Ok(())
+    }
+
+    /**
+     * Executes the instruction at PC, then handles pending interrupts
+     *
+     * RETURNS:
+     *
+     *  true if the FIN_FLAG was set and execution is over
+    */
+    pub fn step(&mut self) -> Result<bool, ExecutionError> {
 
-            //increment pc, set $0 to constant
-            self.PC += 4;
-            self.reg[RegNames::ZERO] = 0;
+        log!(Trace, "CORE", "------------------");
 
-            // end of instruction routines
+        let pc = self.PC;
+        let privileged = (self.flags & Arch::MODE_FLAG) != 0;
 
-            //check if FIN_FLAG is set
-            if (self.flags & Arch::FIN_FLAG) != 0 {
-                if self.verbose { println!("------------------\n[CORE]: FIN_FLAG set; Flags={:08x}",self.flags) }
-                stat.mark_finished();
-                break;
+        let d = match self.run_handoff(pc) {
+            Ok(d) => d,
+            Err(eobj) => {
+                self.accesses = (0, 0);
+                self.cache_cycles = 0;
+                if let Some(tracer) = &mut self.tracer { tracer.exception(TraceException::Fault); }
+                self.trace_retire(pc, eobj.context().code, true);
+                return Err(eobj);
             }
+        };
 
-            // flag set if the previous instruction was RFE. We ensure progress by allowing
-            // at least one instruction executes before the interrupt handler fires again.
-            // There's probably a better way to do this
+        //branches that are taken have moved PC to target - 4
+        let code = d.code;
+        if self.count_opcodes { self.stats.opcode(d.opcode); }
+        if d.branch { self.stats.branch(self.PC != pc); }
 
-            if iter_flag {
-                iter_flag = false;
-                self.set_flag(true, Arch::IENABLE_FLAG);
-            }
+        let control = d.branch || d.jump;
+        let mispredicted = match &mut self.predictors {
+            Some(unit) if control => unit.resolve(pc, code, self.PC.wrapping_add(4)),
+            _ => None
+        };
 
-            //else, check if INTERR_FLAG is set in channel only if not privileged
-            if (self.flags & Arch::IENABLE_FLAG) != 0 && (self.flags & Arch::MODE_FLAG) == 0 {
-                match self.interrupt_ch.try_recv() {
-                    Ok(_) => { self.set_flag(true, Arch::INTERR_FLAG); },
-                    Err(_) => {},
-                }
+        let (mem_accesses, device_accesses) = std::mem::take(&mut self.accesses);
+        let retired = Retired {
+            taken: self.PC != pc && control,
+            mispredicted,
+            mem_accesses,
+            device_accesses,
+            cache_cycles: std::mem::take(&mut self.cache_cycles)
+        };
+        let cycles = match self.flat_timing {
+            true => 1 + retired.cache_cycles,
+            false => self.timing.cost(&mut self.timing_state, self.stats.cycl_count as u64, &d, &retired)
+        };
 
-                //interrupt flag set in channel
-                if (self.flags & Arch::INTERR_FLAG) != 0 {
-                    if self.verbose { println!("[CORE]: INTERR_FLAG set; Flags={:08x}",self.flags) }
-                    self.set_flag(true, Arch::INTERR_FLAG);
-                    // This is a horrible hack
-                    // This is only needed here because the interrupt happens *after* pc has been incremented, instead of in every interrupt(like syscalls)
-                    self.PC -= 4;
-                    self.interrupt();
-                }
-            }
+        self.stats.cycle_add(cycles);
+        self.stats.instr_incr();
+        if privileged { self.stats.privileged(cycles); }
+
+        //advance virtual time; a fired timer stays pending in the controller until the interrupt is taken
+        let cycles = self.stats.cycl_count as u64;
+        if cycles >= self.next_timer { self.poll_timers(cycles); }
 
-            if self.IntEnableOnNext {
-                self.IntEnableOnNext = false;
-                iter_flag = true;
+        //increment pc, set $0 to constant
+        self.PC += 4;
+        self.reg[RegNames::ZERO] = 0;
+
+        // end of instruction routines
+
+        //check if FIN_FLAG is set
+        if self.is_finished() {
+            self.trace_retire(pc, code, false);
+            log!(Debug, "CORE", "FIN_FLAG set; Flags={:08x}",self.flags);
+            self.stats.mark_finished();
+            return Ok(true);
+        }
+
+        // flag set if the previous instruction was RFE. We ensure progress by allowing
+        // at least one instruction executes before the interrupt handler fires again.
+        // There's probably a better way to do this
+
+        if self.iter_flag {
+            self.iter_flag = false;
+            self.set_flag(true, Arch::IENABLE_FLAG);
+        }
+
+        self.check_interrupts();
+        self.trace_retire(pc, code, false);
+
+        if self.IntEnableOnNext {
+            self.IntEnableOnNext = false;
+            self.iter_flag = true;
+        }
+
+        Ok(false)
+    }
+
+    /**
+     * Fires the timers due by cycles and finds the cycle count the next one is due at
+     */
+    #[inline(never)]
+    fn poll_timers(&mut self, cycles: u64) {
+
+        for timer in &mut self.timers {
+            if timer.poll(cycles) { self.pic.borrow_mut().raise(1 << timer.line); }
+        }
+        if cycles >= self.timer.due() {
+            self.timer.set_cycles(cycles);
+            if self.timer.poll() { self.pic.borrow_mut().raise(1 << TIMER_LINE); }
+        }
+
+        self.next_timer = self.timers.iter().fold(self.timer.due(), |next, t| next.min(t.next));
+    }
+
+    /**
+     * Takes a pending interrupt, if interrupts are enabled
+     *
+     * The interrupt controller interrupts the Core once per raised unmasked line;
+     * the handler finds which in Cause.IP or the controller's registers. Handlers
+     * that enable interrupts are only interrupted by lines of higher priority
+     */
+    fn check_interrupts(&mut self) {
+
+        //handlers run with interrupts disabled unless they enable them
+        if (self.flags & Arch::IENABLE_FLAG) != 0 {
+            //timers are deterministic. Thread interrupts are not, so the journal
+            //decides when replaying, otherwise take the raised lines, if any
+            let lines = &self.lines;
+            let raised = match lines.pending() == 0 && !self.journal.borrow().replaying() {
+                true => 0,
+                false => self.journal.borrow_mut().interrupt(self.stats.instr_count as u64, || lines.take())
+            };
+
+            let mut pic = self.pic.borrow_mut();
+            if raised != 0 { pic.raise(raised); }
+            let signalled = pic.signalled();
+            if signalled { pic.deliver(); }
+            drop(pic);
+
+            //only newly signalled lines interrupt; INTERR_FLAG stays set until RFE
+            if signalled {
+                self.set_flag(true, Arch::INTERR_FLAG);
+                log!(Debug, "CORE", "INTERR_FLAG set; Flags={:08x}",self.flags);
+                // This is a horrible hack
+                // This is only needed here because the interrupt happens *after* pc has been incremented, instead of in every interrupt(like syscalls)
+                self.PC -= 4;
+                self.exc_code = CP0::EXC_INT;
+                self.interrupt();
+                self.stats.interrupt();
+                if let Some(tracer) = &mut self.tracer { tracer.exception(TraceException::Interrupt); }
             }
+        }
+    }
+
+    /**
+     * Runs the translated block at PC, if there is one and it can run
+     *
+     * Blocks only run where they would count and time their instructions like step:
+     * unprivileged, with no tracer, caches or predictors, not replaying, and with no
+     * timer firing before they end. Interrupts are taken after the block
+     *
+     * RETURNS:
+     *
+     *  true if the block retired any instruction
+     */
+    #[cfg(feature = "jit")]
+    fn run_block(&mut self) -> bool {
+
+        let jit = match &mut self.jit {
+            Some(jit) => jit,
+            None => return false
+        };
+
+        if log_enabled!(Trace, "CORE") || log_enabled!(Trace, "MEM") || self.tracer.is_some() || self.caches.is_some() || self.predictors.is_some()
+            || (self.flags & Arch::MODE_FLAG) != 0 || self.iter_flag || self.IntEnableOnNext || self.journal.borrow().replaying() {
+            return false;
+        }
+
+        let block = match jit.lookup(self.PC, &mut self.mem, &self.timing) {
+            Some(block) => block,
+            None => return false
+        };
+
+        let now = self.stats.cycl_count as u64;
+        let end = now + self.timing_state.hilo_ready.saturating_sub(now) + block.bound;
+        if self.next_timer <= end { return false; }
+
+        let mut state = GuestState { reg: self.reg, hi: self.HI, lo: self.LO, flags: self.flags, pc: self.PC, mem: &mut self.mem };
+        let retired = block.run(&mut state);
+        (self.reg, self.HI, self.LO, self.flags, self.PC) = (state.reg, state.hi, state.lo, state.flags, state.pc);
+
+        //count them as step does
+        for (i, instr) in block.instrs[..retired].iter().enumerate() {
+
+            let pc = block.start.wrapping_add(4 * i as u32);
+            let next = if i + 1 == retired { self.PC } else { pc.wrapping_add(4) };
+            let d = &instr.decoded;
+            let taken = instr.control && next != pc.wrapping_add(4);
+
+            if self.count_opcodes { self.stats.opcode(d.opcode); }
+            if d.branch { self.stats.branch(taken); }
+            if instr.load > 0 { self.stats.load(instr.load); }
+            if instr.store > 0 { self.stats.store(instr.store); }
+
+            let retired = Retired { taken, mem_accesses: (instr.load + instr.store > 0) as u64, ..Default::default() };
+            let cycles = match self.flat_timing {
+                true => 1,
+                false => self.timing.cost(&mut self.timing_state, self.stats.cycl_count as u64, d, &retired)
+            };
+            self.stats.cycle_add(cycles);
+            self.stats.instr_incr();
+        }
+
+        jit.count(retired);
+        if retired == 0 { return false; }
+
+        //the block ended before the next timer is due, so this only catches up on a late one
+        let cycles = self.stats.cycl_count as u64;
+        if cycles >= self.next_timer { self.poll_timers(cycles); }
+
+        self.check_interrupts();
+        true
+    }
+
+    /**
+     * Writes the trace record of the instruction code at pc, with the registers it writes
+     * and their new values, none if it faulted
+     */
+    fn trace_retire(&mut self, pc: u32, code: Word, faulted: bool) {
+
+        if self.tracer.is_none() { return; }
+
+        let written: Vec<(u8, u32)> = match faulted {
+            true => Vec::new(),
+            false => Disasm::destinations(code).iter().flatten().map(|r| (*r as u8, match *r {
+                Disasm::REG_HI => self.HI,
+                Disasm::REG_LO => self.LO,
+                r => self.reg[r as usize]
+            })).collect()
+        };
+
+        if let Some(tracer) = &mut self.tracer { tracer.retire(pc, code, written); }
+    }
+
+    /**
+     * Starts writing a record per retired instruction to tracer
+     */
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /**
+     * The trace record of the last retired instruction, None if not tracing
+     */
+    pub fn trace_record(&self) -> Option<&TraceRecord> {
+        self.tracer.as_ref().map(|t| t.last())
+    }
 
+    /**
+     * Stops tracing and flushes the trace
+     *
+     * RETURNS:
+     *
+     *  The first error writing the trace, if any
+     */
+    pub fn finish_trace(&mut self) -> std::io::Result<()> {
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.finish(),
+            None => Ok(())
         }
+    }
 
-        if self.verbose {
-            println!("[CORE]: Finished execution in T={} s\n        CPI of {}. Executed {} instructions in {} cycles.",stat.exec_total_time().as_secs_f64(),stat.CPI(),stat.instr_count, stat.cycl_count);
+    /**
+     * Adds an interrupt source driven by the cycle count
+     */
+    pub fn add_timer(&mut self, timer: VirtualInterruptor) {
+        self.timers.push(timer);
+        self.next_timer = 0;
+    }
+
+    /**
+     * Puts a cache hierarchy in front of memory, or removes it with None
+     */
+    pub fn set_caches(&mut self, caches: Option<Hierarchy>) {
+        self.caches = caches;
+    }
+
+    /**
+     * Runs branch predictors over every branch and jump, or stops with None
+     */
+    pub fn set_predictors(&mut self, unit: Option<BranchUnit>) {
+        self.predictors = unit;
+    }
+
+    pub fn predictors(&self) -> Option<&BranchUnit> {
+        self.predictors.as_ref()
+    }
+
+    /**
+     * Runs hot blocks translated by jit from run, or only interprets if None
+     */
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, jit: Option<Jit>) {
+        self.mem.track_code_writes(jit.is_some());
+        self.jit = jit;
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn jit(&self) -> Option<&Jit> {
+        self.jit.as_ref()
+    }
+
+    /**
+     * Replaces the timing model used to count cycles
+     */
+    pub fn set_timing(&mut self, timing: TimingModel) {
+        self.flat_timing = timing.is_flat();
+        self.timing = timing;
+        self.timing_state = TimingState::default();
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit { jit.flush(); }
+    }
+
+    /**
+     * Execution statistics so far
+     */
+    pub fn stats(&self) -> &Stats::Stats {
+        &self.stats
+    }
+
+    /**
+     * Number of instructions executed so far
+     */
+    pub fn instr_count(&self) -> u64 {
+        self.stats.instr_count as u64
+    }
+
+    /**
+     * Enables or disables counting retired instructions by opcode, for the histogram of stats
+     */
+    pub fn set_opcode_counts(&mut self, on: bool) {
+        self.count_opcodes = on;
+    }
+
+    /**
+     * Enables or disables recording interrupts and keyboard input in the journal
+     */
+    pub fn set_journaling(&mut self, on: bool) {
+        self.journal.borrow_mut().recording = on;
+    }
+
+    /**
+     * Writes the journal to a log file that load_journal can replay on another machine
+     */
+    pub fn save_journal<W: Write>(&self, w: W) -> Result<(), SnapshotError> {
+        let period = match self.clock { ClockMode::Virtual(period) => Some(period), _ => None };
+        self.journal.borrow().save(w, period)
+    }
+
+    /**
+     * Loads a log file written by save_journal, so the next run replays its inputs
+     *
+     * The recorded virtual clock replaces this Core's timers. The Core should be created
+     * with ClockMode::Off, or live clock interrupts get mixed with the replayed ones
+     */
+    pub fn load_journal<R: BufRead>(&mut self, r: R) -> Result<(), SnapshotError> {
+
+        let (mut journal, period) = Journal::load(r)?;
+
+        journal.recording = self.journal.borrow().recording;
+        *self.journal.borrow_mut() = journal;
+
+        self.timers.retain(|t| t.name != "Clock");
+        self.next_timer = 0;
+        if let Some(period) = period {
+            self.add_timer(VirtualInterruptor::periodic("Clock", period));
+            self.clock = ClockMode::Virtual(period);
+            self.timer.rebase(TimeBase::Cycles);
+        } else {
+            self.clock = ClockMode::Off;
+            //the timer counted wall-clock time, so its reads and interrupts are in the log
+            self.timer.rebase(TimeBase::Replayed);
         }
 
         Ok(())
     }
 
-    #[inline(always)]
-    fn run_handoff(&mut self, PC: u32) -> Result<(), ExecutionError> {
+    /**
+     * Replays journaled inputs from instruction count at, after restoring a snapshot taken there
+     */
+    pub fn seek_journal(&mut self, at: u64) {
+        self.journal.borrow_mut().seek(at);
+    }
+
+    /**
+     * The interrupt lines of this Core, for devices and threads that raise interrupts
+     */
+    #[allow(dead_code)]
+    pub fn interrupt_lines(&self) -> InterruptLines {
+        self.lines.clone()
+    }
+
+    /**
+     * Stops the interruptor threads and waits for them to end
+     *
+     * They are woken up, so this does not wait for their period to end
+     */
+    fn shutdown(&mut self) {
 
-        //avoid weird tuples in vec::align_to, we know it'll always be aligned
-        let code: Word = Utils::from_word(self.mem.load(PC,4)?);
+        if self.interruptors.is_empty() { return; }
 
+        log!(Debug, "CORE", "Stopping {} interruptor threads", self.interruptors.len());
+        self.interruptors_open.store(false, Ordering::Relaxed);
 
-        if self.verbose {
-            println!("[CORE]: Code: 0x{:08x?} at PC=0x{:08x}",code,PC);
+        for handle in self.interruptors.drain(..) {
+            handle.thread().unpark();
+            // a panicked interruptor has nothing left to stop
+            let _ = handle.join();
         }
+    }
+
+    /**
+     * Returns true once the guest has executed HLT
+     */
+    pub fn is_finished(&self) -> bool {
+        (self.flags & Arch::FIN_FLAG) != 0
+    }
+
+    /**
+     * Formats the shadow call stack, innermost frame first
+     *
+     * Frames the guest returned from through an unexpected $ra are flagged,
+     * which usually means the saved return address was overwritten on the stack
+    */
+    pub fn backtrace(&self) -> String {
+
+        let mut out = format!("#0  {}", self.symbolize(self.PC));
+
+        for (i, frame) in self.call_stack.frames().iter().rev().enumerate() {
+
+            out += &match frame.kind {
+                FrameKind::Call      => format!("\n#{:<2} {}  called {}", i+1, self.symbolize(frame.call_site), self.symbolize(frame.target)),
+                FrameKind::Exception => format!("\n#{:<2} {}  <exception: entered irqH>", i+1, self.symbolize(frame.call_site))
+            };
+
+            if let Some(ra) = frame.ra_mismatch {
+                out += &format!("\n    !! returned through $ra={}, expected {} (stack smashed?)", self.symbolize(ra), self.symbolize(frame.ret_addr));
+            }
+        }
+
+        out
+    }
+
+    /**
+     * Saves the full state of the machine: registers, HI/LO, PC, EPC, flags, pending
+     * interrupt enables, memory contents, protected ranges, mapped device state, symbols,
+     * the shadow call stack and instruction/cycle counts, in the format described in
+     * Definitions::Snapshot
+     *
+     * ARGS:
+     *
+     *  w: Where to write the snapshot
+     */
+    pub fn save_snapshot<W: Write>(&self, w: W) -> Result<(), SnapshotError> {
+        self.write_snapshot(w, None)
+    }
+
+    /**
+     * Restores a snapshot written by save_snapshot or dump_core, replacing the
+     * current machine state
+     *
+     * Mapped devices are not created by the snapshot, only restored, so the Core
+     * must have devices mapped to the same ranges as the one that was saved
+     *
+     * ARGS:
+     *
+     *  r: Where to read the snapshot from
+     */
+    pub fn load_snapshot<R: Read>(&mut self, r: R) -> Result<(), SnapshotError> {
+        self.read_snapshot(r).map(|_| ())
+    }
+
+    /**
+     * Writes a core dump: a snapshot plus the fault that stopped execution
+     *
+     * ARGS:
+     *
+     *  w: Where to write the dump
+     *
+     *  fault: The error that stopped execution, if any
+     */
+    pub fn dump_core<W: Write>(&self, w: W, fault: Option<&ExecutionError>) -> Result<(), SnapshotError> {
+        self.write_snapshot(w, fault)
+    }
+
+    /**
+     * Loads a core dump written by dump_core, replacing the current machine state
+     *
+     * RETURNS:
+     *
+     *  The fault that caused the dump, if one was recorded
+     */
+    pub fn load_core<R: Read>(&mut self, r: R) -> Result<Option<FaultRecord>, SnapshotError> {
+        self.read_snapshot(r)
+    }
+
+    fn write_snapshot<W: Write>(&self, w: W, fault: Option<&ExecutionError>) -> Result<(), SnapshotError> {
+
+        let mut out = SnapshotWriter::new(w)?;
+        self.timer.set_cycles(self.stats.cycl_count as u64);
+
+        let mut cpu = Payload::default();
+        cpu.u32(self.PC);
+        for r in self.reg { cpu.u32(r); }
+        cpu.u32(self.HI).u32(self.LO).u32(self.flags).u32(self.EPC).u32(self.irq_handler_addr);
+        cpu.u32(self.IntEnableOnNext as u32).u32(self.iter_flag as u32).u32(self.exc_code);
+        out.section(Snapshot::TAG_CPU, &cpu.bytes)?;
+
+        let mut prot = Payload::default();
+        for (lo, hi) in self.mem.protected_ranges() { prot.u32(*lo).u32(*hi); }
+        out.section(Snapshot::TAG_PROT, &prot.bytes)?;
+
+        out.section(Snapshot::TAG_STAT, &Payload::default().u64(self.stats.instr_count as u64).u64(self.stats.cycl_count as u64).bytes)?;
+
+        let mut time = Payload::default();
+        for timer in &self.timers { time.str(timer.name).u64(timer.next); }
+        out.section(Snapshot::TAG_TIME, &time.bytes)?;
+
+        let loaded = self.timing_state.loaded.unwrap_or(0);
+        out.section(Snapshot::TAG_PIPE, &Payload::default().u64(self.timing_state.hilo_ready).u32(loaded).bytes)?;
+
+        for (addr, page) in self.mem.pages() {
+            out.section(Snapshot::TAG_PAGE, &Payload::default().u32(addr).raw(page).bytes)?;
+        }
+
+        for (lower, upper, state) in self.mem.device_states() {
+            out.section(Snapshot::TAG_DEV, &Payload::default().u32(lower).u32(upper).raw(&state).bytes)?;
+        }
+
+        for (addr, name) in &self.symbols {
+            out.section(Snapshot::TAG_SYM, &Payload::default().u32(*addr).raw(name.as_bytes()).bytes)?;
+        }
+
+        let mut stk = Payload::default();
+        for frame in self.call_stack.frames() {
+            stk.u32(if frame.kind == FrameKind::Call { 0 } else { 1 }).u32(frame.call_site).u32(frame.target).u32(frame.ret_addr)
+                .u32(frame.ra_mismatch.is_some() as u32).u32(frame.ra_mismatch.unwrap_or(0));
+        }
+        out.section(Snapshot::TAG_STK, &stk.bytes)?;
+
+        if let Some(eobj) = fault {
+            let ctx = eobj.context();
+            out.section(Snapshot::TAG_FAULT, &Payload::default().str(eobj.kind()).str(&eobj.to_string()).u32(ctx.pc).u32(ctx.code).bytes)?;
+        }
+
+        out.finish()
+    }
+
+    fn read_snapshot<R: Read>(&mut self, r: R) -> Result<Option<FaultRecord>, SnapshotError> {
+
+        let mut input = SnapshotReader::new(r)?;
+        let mut fault = None;
+
+        //memory not present in the snapshot is zero
+        self.mem.reset();
+        self.symbols.clear();
+        self.call_stack = CallStack::default();
+        self.timing_state = TimingState::default();
+        //caches are not saved, resumed runs start with them cold
+        if let Some(caches) = &mut self.caches { caches.invalidate(); }
+        self.IntEnableOnNext = false;
+        self.iter_flag = false;
+        self.exc_code = 0;
+        *self.pic.borrow_mut() = PicState::default();
+        self.timer.reset();
+        self.next_timer = 0;
+
+        while let Some((tag, payload)) = input.next_section()? {
+
+            let mut f = Fields::new(&payload);
+
+            match tag {
+                Snapshot::TAG_CPU => {
+                    self.PC = f.u32()?;
+                    for r in 0..32 { self.reg[r] = f.u32()?; }
+                    self.HI = f.u32()?;
+                    self.LO = f.u32()?;
+                    self.flags = f.u32()?;
+                    self.EPC = f.u32()?;
+                    self.irq_handler_addr = f.u32()?;
+                    self.IntEnableOnNext = f.u32()? != 0;
+                    self.iter_flag = f.u32()? != 0;
+                    self.exc_code = f.u32()?;
+                }
+                Snapshot::TAG_PROT => {
+                    while !f.is_empty() {
+                        let (lo, hi) = (f.u32()?, f.u32()?);
+                        self.mem.protect(lo, hi);
+                    }
+                }
+                Snapshot::TAG_STAT => {
+                    self.stats.instr_count = f.u64()? as usize;
+                    self.stats.cycl_count = f.u64()? as usize;
+                    self.timer.set_cycles(self.stats.cycl_count as u64);
+                }
+                Snapshot::TAG_TIME => {
+                    while !f.is_empty() {
+                        let (name, next) = (f.str()?, f.u64()?);
+                        if let Some(timer) = self.timers.iter_mut().find(|t| t.name == name) { timer.next = next; }
+                    }
+                }
+                Snapshot::TAG_PIPE => {
+                    self.timing_state.hilo_ready = f.u64()?;
+                    self.timing_state.loaded = Some(f.u32()?).filter(|r| *r != 0);
+                }
+                Snapshot::TAG_PAGE => {
+                    let addr = f.u32()?;
+                    self.mem.restore(addr, f.rest());
+                }
+                Snapshot::TAG_DEV => {
+                    let (lower, upper) = (f.u32()?, f.u32()?);
+                    self.mem.restore_device_state(lower, upper, f.rest())?;
+                }
+                Snapshot::TAG_SYM => {
+                    let addr = f.u32()?;
+                    self.add_symbol(addr, &String::from_utf8_lossy(f.rest()));
+                }
+                Snapshot::TAG_STK => {
+                    while !f.is_empty() {
+                        let kind = if f.u32()? == 0 { FrameKind::Call } else { FrameKind::Exception };
+                        let (call_site, target, ret_addr) = (f.u32()?, f.u32()?, f.u32()?);
+                        let ra_mismatch = match (f.u32()?, f.u32()?) {
+                            (0, _) => None,
+                            (_, ra) => Some(ra)
+                        };
+                        self.call_stack.push( Frame { kind, call_site, target, ret_addr, ra_mismatch } );
+                    }
+                }
+                Snapshot::TAG_FAULT => {
+                    fault = Some( FaultRecord { kind: f.str()?, message: f.str()?, pc: f.u32()?, code: f.u32()? } );
+                }
+                _ => {
+                    log!(Warn, "CORE", "Skipping unknown snapshot section {:?}", String::from_utf8_lossy(&tag));
+                }
+            }
+        }
+
+        self.mem.set_privileged((self.flags & Arch::MODE_FLAG) != 0);
 
-        let maskOP = (code & 0xfc000000) >> 26;
-        if maskOP == 0 {
+        Ok(fault)
+    }
+
+    #[inline(always)]
+    fn run_handoff(&mut self, PC: u32) -> Result<Decoded, ExecutionError> {
+
+        if let Some(caches) = &mut self.caches { self.cache_cycles += caches.access(Port::Fetch, PC, &mut self.stats); }
+
+        let d = match self.mem.fetch(PC) {
+            Ok(d) => d,
+            Err(eobj) => return Err(ExecutionError::mem(eobj, PC, 4, Access::Fetch).with_context(self.snapshot(PC, 0)))
+        };
+        let code = d.code;
+
+
+        log!(Trace, "CORE", "Code: 0x{:08x?} at PC=0x{:08x}",code,PC);
+
+        let res = if d.op == 0 {
             //is an R-type instruction
-            self.handoff_R(code)?;
+            self.handoff_R(&d)
 
-        } else if ! (maskOP == 0b000010 || maskOP == 0b000011 || maskOP == 0b011010) {
+        } else if ! (d.op == 0b000010 || d.op == 0b000011 || d.op == 0b011010) {
             // is an I-type instruction
-            self.handoff_I(code)?;
+            self.handoff_I(&d)
         } else {
             // is a J-type instruction
-            self.handoff_J(code)?;
-        }
+            self.handoff_J(&d)
+        };
+
+        res.map(|_| d).map_err(|eobj| eobj.with_context(self.snapshot(PC, code)))
+    }
+
+    /**
+     * Loads size bytes at addr for the instruction being executed, tagging failures as guest loads
+     */
+    #[inline(always)]
+    fn load(&mut self, addr: u32, size: usize) -> Result<&[Byte], ExecutionError> {
+        if self.mem.is_device(addr) { self.device_access() } else { self.cached(Port::Load, addr) }
+        let contents = self.mem.load(addr, size).map_err(|eobj| ExecutionError::mem(eobj, addr, size, Access::Load))?;
+        self.stats.load(size);
+        if let Some(tracer) = &mut self.tracer { tracer.access(Access::Load, addr, contents); }
+        Ok(contents)
+    }
 
+    /**
+     * Stores size bytes at addr for the instruction being executed, tagging failures as guest stores
+     */
+    #[inline(always)]
+    fn store(&mut self, addr: u32, size: usize, contents: &[Byte]) -> Result<(), ExecutionError> {
+        let device = self.mem.is_device(addr);
+        if device { self.device_access() }
+        self.mem.store(addr as usize, size, contents).map_err(|eobj| ExecutionError::mem(eobj, addr, size, Access::Store))?;
+        self.stats.store(size);
+        if !device { self.cached(Port::Store, addr) }
+        if let Some(tracer) = &mut self.tracer { tracer.access(Access::Store, addr, &contents[..size]); }
         Ok(())
     }
 
 
-    fn handoff_R(&mut self,code: Word) -> Result<(), ExecutionError> {
+    /**
+     * Counts a load or store to a device, which sees the cycle count as of this instruction
+     * and journals its input at this instruction's count
+     */
+    #[inline(always)]
+    fn device_access(&mut self) {
+        self.accesses.1 += 1;
+        //the access may program the timer
+        self.next_timer = 0;
+        self.timer.set_cycles(self.stats.cycl_count as u64);
+        self.journal.borrow_mut().now = self.stats.instr_count as u64;
+    }
+
+    /**
+     * Counts a load or store to memory, going through the caches if there are any
+     */
+    #[inline(always)]
+    fn cached(&mut self, port: Port, addr: u32) {
+        self.accesses.0 += 1;
+        if let Some(caches) = &mut self.caches { self.cache_cycles += caches.access(port, addr, &mut self.stats); }
+    }
+
+    /**
+     * Checks a `jr $ra` against the shadow call stack
+     */
+    fn shadow_ret(&mut self, ra: u32) {
+
+        if let Some(frame) = self.call_stack.ret(ra) {
+            log!(Warn, "CORE", "Returning through $ra=0x{:08x} but the call from 0x{:08x} expected 0x{:08x}", ra, frame.call_site, frame.ret_addr);
+        }
+    }
+
+    fn handoff_R(&mut self, d: &Decoded) -> Result<(), ExecutionError> {
 
+        let code = d.code;
         if code == OP::NOP {
-            if self.verbose { println!("\tR-type: NOP"); }
+            log!(Trace, "CORE", "\tR-type: NOP");
             return Ok(());
         }
 
-        let rs   = self.reg[((code & 0b00000011111000000000000000000000) >> 21) as usize];
-        let rt   = self.reg[((code & 0b00000000000111110000000000000000) >> 16) as usize];
-        let rd   = ((code & 0b00000000000000001111100000000000) >> 11) as usize;
-        let sham = (code & 0b00000000000000000000011111000000) >> 6;
-        let func = code & 0b00000000000000000000000000111111;
+        let rs_n = d.rs;
+        let rs   = self.reg[rs_n];
+        let rt   = self.reg[d.rt];
+        let rd   = d.rd;
+        let sham = d.sham;
+        let func = d.func;
 
         let rt_sign_positive = rt & 0x80000000 == 0;
         let rs_sign_positive = rs & 0x80000000 == 0;
 
-        if self.verbose {
-            println!("\tR-type: rs={} rt={} rd={} sham={} func={:02x}; code =0x{:08x?}",rs,rt,rd,sham,func,code);
-        }
+        log!(Trace, "CORE", "\tR-type: rs={} rt={} rd={} sham={} func={:02x}; code =0x{:08x?}",rs,rt,rd,sham,func,code);
 
         //non-zero value for flag check after
         let mut res = 1;
@@ -335,15 +1295,15 @@ impl Core {
             OP::R::SRA   => {res = (rt as i32 >> sham as i32) as u32; self.reg[rd] = res;},//sra ; for rust to do shift aritmetic, use signed types
             OP::R::SRAV  => {res = (rt as i32 >> rs as i32) as u32; self.reg[rd] = res;},   //srav; for rust to do shift aritmetic, use signed types
             OP::R::SRLV  => {res = rt >> rs; self.reg[rd] = res;},//srlv
-            OP::R::JARL  => {self.reg[RegNames::RA] = self.PC; self.PC = if rs != 0 {rs-4} else {rs};},//jalr
-            OP::R::JR    => {self.PC = if rs != 0 {rs-4} else {rs}},//jr
+            OP::R::JARL  => {self.reg[RegNames::RA] = self.PC; self.call_stack.call(self.PC, rs, self.PC); self.PC = if rs != 0 {rs-4} else {rs};},//jalr
+            OP::R::JR    => {if rs_n == RegNames::RA { self.shadow_ret(rs); } self.PC = if rs != 0 {rs-4} else {rs}},//jr
             OP::R::MFHI  => {self.reg[rd] = self.HI;},//mfhi
             OP::R::MFLO  => {self.reg[rd] = self.LO;},//mflo
             OP::R::MTHI  => {self.HI = rs;},//mthi
             OP::R::MTLO  => {self.LO = rs;},//mtlo
 
 
-            _ => { return Err(ExecutionError::UnrecognizedOPError(format!("Unrecognized R type func {:x}",func))) ;}
+            _ => { return Err(ExecutionError::unrecognized('R', func)) ;}
 
         }
 
@@ -355,7 +1315,9 @@ impl Core {
     }
 
 
-    fn handoff_I(&mut self, code: Word) -> Result<(), ExecutionError> {
+    fn handoff_I(&mut self, d: &Decoded) -> Result<(), ExecutionError> {
+
+        let code = d.code;
 
         //special instruction: RFE
         if code == OP::RFE {
@@ -363,24 +1325,32 @@ impl Core {
             let privileged = (self.flags & Arch::MODE_FLAG) != 0;
 
 
-            if self.verbose { println!("\tRFE:EPC={:08x}; privilege status {}. Flags {:08x}",self.EPC, privileged,self.flags); }
+            log!(Debug, "CORE", "RFE: EPC={:08x}; privilege status {}. Flags {:08x}",self.EPC, privileged,self.flags);
 
             //panic if we are not privileged
             if  !privileged { 
-                return Err(ExecutionError::PrivilegeError(String::from("RFE")));
+                return Err(ExecutionError::privilege("RFE"));
                 //panic!("Tried to use privileged instruction 0x{:08x} but the mode bitflag was not set to 1; Flags=0x{:08x}",code, self.flags); 
             }
 
             //restore PC
             self.PC = self.EPC; 
-            //disable privileged
-            self.set_flag(false,Arch::MODE_FLAG);
-            self.IntEnableOnNext = true;
+            self.call_stack.rfe();
+            self.pic.borrow_mut().leave();
+
+            //pop the KU/IE stack. Interrupts are enabled after the next instruction, so it makes progress
+            let privileged = (self.flags & Arch::PREV_MODE_FLAG) != 0;
+            self.IntEnableOnNext = (self.flags & Arch::PREV_IENABLE_FLAG) != 0;
+            self.iter_flag = false;
+            self.set_flag(privileged, Arch::MODE_FLAG);
+            self.set_flag(false, Arch::IENABLE_FLAG);
+            self.set_flag((self.flags & Arch::OLD_MODE_FLAG) != 0, Arch::PREV_MODE_FLAG);
+            self.set_flag((self.flags & Arch::OLD_IENABLE_FLAG) != 0, Arch::PREV_IENABLE_FLAG);
             self.set_flag(false, Arch::INTERR_FLAG);
 
-            if self.verbose { println!("[CORE]: Changed privilege mode to false") }
+            log!(Debug, "CORE", "Changed privilege mode to {}", privileged);
 
-            self.mem.set_privileged(false);
+            self.mem.set_privileged(privileged);
 
             return Ok(());
 
@@ -391,40 +1361,76 @@ impl Core {
 
             let privileged = (self.flags & Arch::MODE_FLAG) != 0;
 
-            if self.verbose { println!("\tHLT: privilege status: {};",privileged ); }
+            log!(Debug, "CORE", "HLT: privilege status: {};",privileged );
 
             //panic if we are not privileged
             if  !privileged { 
-                return Err(ExecutionError::PrivilegeError(String::from("HLT")));
+                return Err(ExecutionError::privilege("HLT"));
                 //panic!("Tried to use privileged instruction 0x{:08x} but the mode bitflag was not set to 1; Flags=0x{:08x}",code, self.flags); 
             }
 
-            if self.verbose { println!("[CORE]: Sending interrupt channel close signal"); }
-            self.interrupt_ch_open.swap(false, Ordering::Relaxed);
-
             //set fin flag, disable privileged
             self.set_flag(false, Arch::MODE_FLAG);
             self.set_flag(true, Arch::FIN_FLAG);
             self.mem.set_privileged(false);
-            //"await" interrupt channel termination
-            while !self.interrupt_ch.recv_timeout(Duration::new(0, 1)).is_err() {}
+            self.shutdown();
 
             return Ok(());
 
         }
 
+        //special instruction: mfc0, reads a coprocessor 0 register into rt
+        if code & OP::MFC0_MASK == OP::MFC0 {
+
+            if (self.flags & Arch::MODE_FLAG) == 0 {
+                return Err(ExecutionError::privilege("MFC0"));
+            }
 
-        let func = (code & 0b11111100000000000000000000000000) >> 26;
-        let rs   = self.reg[((code & 0b00000011111000000000000000000000) >> 21) as usize];
-        let rt   = ((code &        0b00000000000111110000000000000000) >> 16) as usize;
-        let imm  = code &            0b00000000000000001111111111111111;
+            self.reg[d.rt] = match d.rd {
+                CP0::STATUS => self.flags,
+                CP0::CAUSE  => self.exc_code << 2 | self.pic.borrow().ip() << CP0::IP_SHIFT,
+                CP0::EPC    => self.EPC,
+                _ => 0
+            };
 
-        let imm_sign_positive  = (code & 0b00000000000000001000000000000000) == 0;
+            return Ok(());
+
+        }
+
+        //special instruction: mtc0, writes rt to a coprocessor 0 register
+        if code & OP::MFC0_MASK == OP::MTC0 {
+
+            if (self.flags & Arch::MODE_FLAG) == 0 {
+                return Err(ExecutionError::privilege("MTC0"));
+            }
+
+            let value = self.reg[d.rt];
+            match d.rd {
+                CP0::STATUS => {
+                    //an enable deferred by RFE is overridden
+                    self.IntEnableOnNext = false;
+                    self.iter_flag = false;
+                    self.flags = (self.flags & !CP0::STATUS_WRITABLE) | (value & CP0::STATUS_WRITABLE);
+                    self.mem.set_privileged((self.flags & Arch::MODE_FLAG) != 0);
+                }
+                CP0::EPC => self.EPC = value,
+                _ => {}
+            }
+
+            return Ok(());
 
-        if self.verbose {
-            println!("\tI-type: func={:02x} rs={} rt={} imm={}{} ; code =0x{:08x?}",func,rs,rt,if imm_sign_positive {"+"} else {"-"} ,to_signed!(imm,u16),code);
         }
 
+
+        let func = d.op;
+        let rs   = self.reg[d.rs];
+        let rt   = d.rt;
+        let imm  = d.imm;
+
+        let imm_sign_positive  = (code & 0b00000000000000001000000000000000) == 0;
+
+        log!(Trace, "CORE", "\tI-type: func={:02x} rs={} rt={} imm={}{} ; code =0x{:08x?}",func,rs,rt,if imm_sign_positive {"+"} else {"-"} ,to_signed!(imm,u16),code);
+
         match func {
             OP::I::ADDI  => {
                 //using signed, if number is negative subtract
@@ -436,38 +1442,38 @@ impl Core {
             OP::I::XORI  => {self.reg[rt] = rs ^ imm;}//xori
             OP::I::SLTI  => {if rs < imm { self.reg[rt] = 1;} else { self.reg[rt] = 0;} } //slti
             OP::I::SLTIU => {if rs < imm { self.reg[rt] = 1;} else { self.reg[rt] = 0;} }//sltiu
-            OP::I::LHI   => {println!("lhi");}//lhi
-            OP::I::LLO   => {println!("llo");}//llo
+            OP::I::LHI   => {log!(Warn, "CORE", "lhi is not implemented");}//lhi
+            OP::I::LLO   => {log!(Warn, "CORE", "llo is not implemented");}//llo
             OP::I::BEQ   => { if rs == self.reg[rt] { if imm_sign_positive { self.PC = self.PC.overflowing_add(imm << 2).0;} else { self.PC = self.PC.overflowing_sub(to_signed!(imm<<2, u16)).0 }}; }//beq
             OP::I::BNE   => { if rs != self.reg[rt] { if imm_sign_positive { self.PC = self.PC.overflowing_add(imm << 2).0;} else { self.PC = self.PC.overflowing_sub(to_signed!(imm<<2, u16)).0 }}; }//bne
             OP::I::BGTZ  => { if rs > 0             { if imm_sign_positive { self.PC = self.PC.overflowing_add(imm << 2).0;} else { self.PC = self.PC.overflowing_sub(to_signed!(imm<<2, u16)).0 }}; }//bgtz
             OP::I::BLEZ  => { if rs <= self.reg[rt] { if imm_sign_positive { self.PC = self.PC.overflowing_add(imm << 2).0;} else { self.PC = self.PC.overflowing_sub(to_signed!(imm<<2, u16)).0 }}; }//blez
-            OP::I::LB    => {self.reg[rt] = Utils::from_byte(self.mem.load(rs+imm, 1)? );}//lb
-            OP::I::LBU   => {self.reg[rt] = Utils::from_byte(self.mem.load(rs+imm, 1)? );}//lbu
-            OP::I::LH    => {self.reg[rt] = Utils::from_half(self.mem.load(rs+imm, 2)? );}//lh
-            OP::I::LHU   => {self.reg[rt] = Utils::from_half(self.mem.load(rs+imm, 2)? );}//lhu
-            OP::I::LW    => {self.reg[rt] = Utils::from_word(self.mem.load(rs+imm, 4)? );}//lw
+            OP::I::LB    => {self.reg[rt] = Utils::from_byte(self.load(rs+imm, 1)? );}//lb
+            OP::I::LBU   => {self.reg[rt] = Utils::from_byte(self.load(rs+imm, 1)? );}//lbu
+            OP::I::LH    => {self.reg[rt] = Utils::from_half(self.load(rs+imm, 2)? );}//lh
+            OP::I::LHU   => {self.reg[rt] = Utils::from_half(self.load(rs+imm, 2)? );}//lhu
+            OP::I::LW    => {self.reg[rt] = Utils::from_word(self.load(rs+imm, 4)? );}//lw
             OP::I::SB    => {
                 let b = self.reg[rt];
                 let v = vec![b as u8;1];
 
-                self.mem.store( (rs+imm) as usize , 1, &v)?;
+                self.store(rs+imm, 1, &v)?;
             }//sb
             OP::I::SH => {
                 let b = self.reg[rt];
                 let v = vec![(b >> 8) as u8, (b & 0x00ff) as u8];
 
-                self.mem.store( (rs+imm) as usize, 2, &v)?;
+                self.store(rs+imm, 2, &v)?;
             }//sh
             OP::I::SW => {
                 let b = self.reg[rt];
                 let v = vec![(b & 0xff000000 >> 24) as u8, (b & 0x00ff0000 >> 16) as u8,(b & 0x0000ff00 >> 8) as u8, (b & 0x000000ff) as u8];
 
-                self.mem.store( (rs +imm) as usize, 4, &v)?;
+                self.store(rs+imm, 4, &v)?;
             }//sw
 
 
-            _ => { return Err(ExecutionError::UnrecognizedOPError(format!("Unrecognized I type func {:x}",func))) }
+            _ => { return Err(ExecutionError::unrecognized('I', func)) }
 
 
         }
@@ -477,28 +1483,32 @@ impl Core {
     }
 
 
-    fn handoff_J(&mut self,code: Word) -> Result<(), ExecutionError> {
+    fn handoff_J(&mut self, d: &Decoded) -> Result<(), ExecutionError> {
 
         //special instruction, syscall
-        if code == OP::SYSCALL {
+        if d.code == OP::SYSCALL {
 
-            if self.verbose { println!("\tSyscall; v0={}, v1={}\n[CORE]: Changed privilege mode to true", self.reg[RegNames::V0], self.reg[RegNames::V1]); }
+            log!(Debug, "CORE", "Syscall; v0={}, v1={}. Changed privilege mode to true", self.reg[RegNames::V0], self.reg[RegNames::V1]);
 
             //save current pc, jump to IrqH, set privileged flag
+            self.exc_code = CP0::EXC_SYS;
+            self.pic.borrow_mut().enter(0);
             self.interrupt();
+            self.stats.syscall();
+            if let Some(tracer) = &mut self.tracer { tracer.exception(TraceException::Syscall); }
             return Ok(());
         }
 
-        let func          = (code & 0xfc000000) >> 26;
-        let jump_target   = (code & !0xfc000000) << 2 ;
+        let func          = d.op;
+        let jump_target   = d.target;
 
-        if self.verbose { println!("\tJ type: func=0x{:02x} jump_target=0x{:08x}",func,jump_target); }
+        log!(Trace, "CORE", "\tJ type: func=0x{:02x} jump_target=0x{:08x}",func,jump_target);
 
         match func {
             OP::J::J   => {self.PC = if jump_target != 0 {jump_target-4} else {jump_target};}
-            OP::J::JAL => {self.reg[RegNames::RA] = self.PC; self.PC = if jump_target != 0 {jump_target-4} else {jump_target};}
+            OP::J::JAL => {self.reg[RegNames::RA] = self.PC; self.call_stack.call(self.PC, jump_target, self.PC); self.PC = if jump_target != 0 {jump_target-4} else {jump_target};}
 
-            _ => { return Err(ExecutionError::UnrecognizedOPError(format!("Unrecognized J type func {:02x}",func))); }
+            _ => { return Err(ExecutionError::unrecognized('J', func)); }
         }
 
         Ok(())
@@ -507,6 +1517,12 @@ impl Core {
 
 }
 
+impl Drop for Core {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 /**
  *  TESTS
  */
@@ -515,7 +1531,7 @@ impl Core {
 #[test]
 fn basic() {
 
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
 
     match c.load_RELF("testbins/testingLS.s.relf") {
         Err(e) => { panic!("{}", e) }
@@ -526,7 +1542,7 @@ fn basic() {
 
 #[test]
 fn backwards_jumps() {
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
 
     let start = 0x00000010;
     let hlt = [0x42, 0x00, 0x00, 0x10]; //hlt
@@ -545,7 +1561,7 @@ fn backwards_jumps() {
 #[test]
 fn long_compute() {
 
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
 
     match c.load_RELF("testbins/perf_test_newcompile.relf") {
         Err(e) => { panic!("{}",e) }
@@ -559,7 +1575,7 @@ fn long_compute() {
 #[test]
 #[should_panic]
 fn unprivileged_rfe() {
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
 
     let code = [0x42, 0x00, 0x00, 0x01]; //rfe
     c.mem.store(0x00fff, 4, &code).unwrap();
@@ -568,10 +1584,29 @@ fn unprivileged_rfe() {
     c.run().unwrap();
 }
 
+#[test]
+fn fault_context() {
+    let mut c: Core = Core::new();
+
+    let code = [0x8c, 0x9b, 0x00, 0x00]; //lw $k1, 0($a0)
+    c.mem.store(0x00fff, 4, &code).unwrap();
+    c.PC = 0x00fff;
+    c.reg[RegNames::A0] = 0x10; //inside the protected irqH
+
+    match c.run() {
+        Err(ExecutionError::MemError { addr, size, access, ctx, .. }) => {
+            assert_eq!((addr, size, access), (0x10, 4, Access::Load));
+            assert_eq!((ctx.pc, ctx.code), (0x00fff, 0x8c9b0000));
+            assert_eq!(ctx.regs[RegNames::A0], 0x10);
+        }
+        _ => panic!("expected a MemError")
+    }
+}
+
 #[test]
 #[should_panic]
 fn unprivileged_hlt() {
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
 
     let code = [0x42, 0x00, 0x00, 0x10]; //hlt
     c.mem.store(0x00fff, 4, &code).unwrap();
@@ -581,9 +1616,611 @@ fn unprivileged_hlt() {
 
 }
 
+#[test]
+fn symbols() {
+    let mut c: Core = Core::new();
+
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    c.add_symbol(0x00400010, "loop");
+
+    assert_eq!(c.nearest_symbol(0x00400000), Some(("_start", 0)));
+    assert_eq!(c.nearest_symbol(0x00400018), Some(("loop", 8)));
+    assert_eq!(c.symbolize(0x00000008), "0x00000008 <__irq_handler+0x8>");
+    assert_eq!(c.fetch(0x00400000), Some(0x24011000));
+}
+
+#[test]
+fn shadow_call_stack() {
+    let mut c: Core = Core::new();
+
+    c.mem.store(0x1000, 4, &[0x0c, 0x00, 0x08, 0x00]).unwrap(); //jal 0x2000
+    c.mem.store(0x2000, 4, &[0x24, 0x1f, 0x30, 0x00]).unwrap(); //addiu $ra, $zero, 0x3000
+    c.mem.store(0x2004, 4, &[0x03, 0xe0, 0x00, 0x08]).unwrap(); //jr $ra
+    c.PC = 0x1000;
+    c.add_symbol(0x1000, "main");
+    c.add_symbol(0x2000, "f");
+
+    c.step().unwrap();
+    assert!(c.backtrace().contains("#1  0x00001000 <main>  called 0x00002000 <f>"));
+
+    c.step().unwrap();
+    c.step().unwrap();
+    assert_eq!(c.PC, 0x3000);
+    assert!(c.backtrace().contains("!! returned through $ra=0x00003000 <f+0x1000>, expected 0x00001000 <main>"));
+}
+
+#[test]
+fn self_modifying_code() {
+    let mut c: Core = Core::new();
+
+    c.mem.store(0x1000, 4, &[0x24, 0x02, 0x00, 0x01]).unwrap(); //addiu $v0, $zero, 1
+    c.mem.store(0x1004, 4, &[0x08, 0x00, 0x04, 0x00]).unwrap(); //j 0x1000
+    c.PC = 0x1000;
+
+    c.step().unwrap();
+    c.step().unwrap();
+    assert_eq!((c.PC, c.reg[RegNames::V0]), (0x1000, 1));
+
+    //rewriting an instruction that was already decoded
+    c.mem.store(0x1000, 4, &[0x24, 0x02, 0x00, 0x02]).unwrap(); //addiu $v0, $zero, 2
+    c.step().unwrap();
+    assert_eq!(c.reg[RegNames::V0], 2);
+}
+
+#[test]
+fn raised_lines() {
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+
+    c.mem.store(0x1000, 4, &[0x08, 0x00, 0x04, 0x00]).unwrap(); //j 0x1000
+    c.PC = 0x1000;
+    c.step().unwrap();
+    assert_eq!(c.stats.interrupts, 0);
+
+    //lines raised twice before the Core looks are one interrupt
+    let lines = c.interrupt_lines();
+    lines.raise(CLOCK_LINE);
+    lines.raise(CLOCK_LINE);
+    c.step().unwrap();
+    assert_eq!((c.stats.interrupts, c.PC, lines.pending()), (1, 0, 0));
+}
+
+#[test]
+fn interrupt_controller() {
+    let handler: [Word; 5] = [
+        OP::NOP,        //skipped on entry
+        0x401a6800,     //mfc0 $k0, $13
+        0x925b0002,     //lbu $k1, 2($s2), claim
+        0xa25b0002,     //sb $k1, 2($s2), acknowledge
+        OP::HLT
+    ];
+    let program: [Word; 4] = [
+        0x24128000,     //addiu $s2, $zero, 0x8000
+        0x00129400,     //sll $s2, $s2, 16
+        0x36520010,     //ori $s2, $s2, 0x10
+        0x1000ffff      //beq $zero, $zero, -1
+    ];
+
+    let image = Utils::image_of(&[(0, &handler[..]), (0x4000, &program[..])]);
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_image(&image, 0x4000);
+    c.add_timer(VirtualInterruptor::at("Device", 8).on(3));
+    c.run().unwrap();
+
+    //the handler saw an interrupt on line 3 and acknowledged it
+    assert_eq!(c.reg[RegNames::K0], CP0::EXC_INT << 2 | 1 << 3 << CP0::IP_SHIFT);
+    assert_eq!(c.reg[RegNames::K1], 3);
+    assert_eq!(c.pic.borrow().pending, 0);
+
+    //only privileged code reads coprocessor 0
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.mem.store(0x1000, 4, &0x401a6800u32.to_be_bytes()).unwrap();
+    c.PC = 0x1000;
+    assert!(matches!(c.step(), Err(ExecutionError::PrivilegeError { .. })));
+}
+
+#[test]
+fn nested_interrupts() {
+    let handler: [Word; 22] = [
+        OP::NOP,        //skipped on entry
+        0x401a6800,     //mfc0 $k0, $13
+        0x335a007c,     //andi $k0, $k0, 0x7c
+        0x1340000c,     //beq $k0, $zero, 12, interrupts
+        0x241b000a,     //addiu $k1, $zero, 10
+        0x105b000f,     //beq $v0, $k1, 15, syscall 10 halts
+        0x40137000,     //mfc0 $s3, $14, save EPC and Status
+        0x40146000,     //mfc0 $s4, $12
+        0x36880008,     //ori $t0, $s4, 8
+        0x40886000,     //mtc0 $t0, $12, enable interrupts
+        0x26b50001,     //addiu $s5, $s5, 1
+        0x26b50001,     //addiu $s5, $s5, 1
+        0x26b50001,     //addiu $s5, $s5, 1
+        0x40946000,     //mtc0 $s4, $12, restore them
+        0x40937000,     //mtc0 $s3, $14
+        OP::RFE,
+        0x925b0002,     //lbu $k1, 2($s2), claim
+        0xa25b0002,     //sb $k1, 2($s2), acknowledge
+        0x26f70001,     //addiu $s7, $s7, 1
+        0x400b7000,     //mfc0 $t3, $14
+        OP::RFE,
+        OP::HLT
+    ];
+    let program: [Word; 10] = [
+        0x24128000,     //addiu $s2, $zero, 0x8000
+        0x00129400,     //sll $s2, $s2, 16
+        0x36520010,     //ori $s2, $s2, 0x10
+        0x24090020,     //addiu $t1, $zero, 0x20
+        0xa2490005,     //sb $t1, 5($s2), line 3 at priority 2
+        0x24020001,     //addiu $v0, $zero, 1
+        OP::SYSCALL,
+        0x24160001,     //addiu $s6, $zero, 1
+        0x2402000a,     //addiu $v0, $zero, 10
+        OP::SYSCALL
+    ];
+
+    let image = Utils::image_of(&[(0, &handler[..]), (0x4000, &program[..])]);
+
+    //line 3 is raised on the first addiu $s5 of the syscall handler, after it enables interrupts
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.set_timing(TimingModel::flat());
+    c.load_image(&image, 0x4000);
+    c.add_timer(VirtualInterruptor::at("Device", 17).on(3));
+    c.run().unwrap();
+
+    //the interrupt nested in the handler, which went on where it was and returned to the program
+    assert_eq!(c.reg[RegNames::S7], 1);
+    assert_eq!(c.reg[RegNames::T3], 40);
+    assert_eq!(c.reg[RegNames::S5], 3);
+    assert_eq!(c.reg[RegNames::S6], 1);
+    assert_eq!((c.stats.syscalls, c.stats.interrupts), (2, 1));
+    assert_eq!(c.pic.borrow().pending, 0);
+
+    //the KU/IE stack
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.interrupt();
+    c.interrupt();
+    let stack = Arch::MODE_FLAG | Arch::PREV_MODE_FLAG | Arch::OLD_IENABLE_FLAG;
+    assert_eq!(c.flags & (CP0::STATUS_WRITABLE), stack);
+}
+
+#[test]
+fn evaluate_cop0() {
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.interrupt();
+    c.IntEnableOnNext = true;
+    let (flags, epc) = (c.flags, c.EPC);
+
+    //dropping to user mode and rewriting EPC leave no trace
+    c.evaluate(0x40886000, &[(8, Arch::IENABLE_FLAG)]).unwrap();      //mtc0 $t0, $12
+    c.evaluate(0x40887000, &[(8, 0x1234)]).unwrap();                 //mtc0 $t0, $14
+    assert_eq!((c.flags, c.EPC, c.IntEnableOnNext), (flags, epc, true));
+    assert!(c.mem.store(0x10, 1, &[0]).is_ok());
+
+    //reads see the Status of the Core
+    let (values, _) = c.evaluate(0x401a6000, &[]).unwrap();          //mfc0 $k0, $12
+    assert_eq!(values[0], Some(flags));
+}
+
+#[test]
+fn guest_timer() {
+    let handler: [Word; 8] = [
+        OP::NOP,        //skipped on entry
+        0x925b0002,     //lbu $k1, 2($s2), claim
+        0xa25b0002,     //sb $k1, 2($s2), acknowledge
+        0x26f70001,     //addiu $s7, $s7, 1
+        0x241a0003,     //addiu $k0, $zero, 3
+        0x12fa0001,     //beq $s7, $k0, 1, halt on the third
+        OP::RFE,
+        OP::HLT
+    ];
+    let program: [Word; 8] = [
+        0x24128000,     //addiu $s2, $zero, 0x8000
+        0x00129400,     //sll $s2, $s2, 16
+        0x36520010,     //ori $s2, $s2, 0x10
+        0x24080032,     //addiu $t0, $zero, 50
+        0xa248000f,     //sb $t0, 0xf($s2), compare
+        0x24080007,     //addiu $t0, $zero, 7
+        0xa2480017,     //sb $t0, 0x17($s2), control: enable, periodic, irq
+        0x1000ffff      //beq $zero, $zero, -1
+    ];
+
+    let image = Utils::image_of(&[(0, &handler[..]), (0x4000, &program[..])]);
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.set_timing(TimingModel::flat());
+    c.load_image(&image, 0x4000);
+
+    //periodic matches every 50 cycles from when the timer was enabled, on the timer's line
+    let mut taken = Vec::new();
+    while !c.step().unwrap() {
+        if c.PC == c.irq_handler_addr {
+            taken.push(c.stats.cycl_count);
+            assert_eq!(c.pic.borrow().pending, 1 << TIMER_LINE);
+        }
+    }
+    assert_eq!(taken, vec![6 + 50, 6 + 100, 6 + 150]);
+    assert_eq!(c.reg[RegNames::S7], 3);
+    assert_eq!(c.timer.state().control & Timer::MATCHED, Timer::MATCHED);
+
+    //the timer is saved with the machine
+    let mut snap = Vec::new();
+    c.save_snapshot(&mut snap).unwrap();
+    let mut c2: Core = Core::with_clock(ClockMode::Off);
+    c2.load_snapshot(snap.as_slice()).unwrap();
+    let now = c.stats.cycl_count as u64;
+    assert_eq!(c2.timer.due(), 6 + 200);
+    assert_eq!(c2.timer.state().count(now), c.timer.state().count(now));
+}
+
+#[test]
+fn realtime_timer() {
+    let handler: [Word; 10] = [
+        OP::NOP,        //skipped on entry
+        0x925b0002,     //lbu $k1, 2($s2), claim
+        0xa25b0002,     //sb $k1, 2($s2), acknowledge
+        0x241a0001,     //addiu $k0, $zero, 1
+        0x177a0003,     //bne $k1, $k0, 3, only the timer's line counts
+        0x26f70001,     //addiu $s7, $s7, 1
+        0x241a0003,     //addiu $k0, $zero, 3
+        0x12fa0001,     //beq $s7, $k0, 1, halt on the third
+        OP::RFE,
+        OP::HLT
+    ];
+    let program: [Word; 8] = [
+        0x24128000,     //addiu $s2, $zero, 0x8000
+        0x00129400,     //sll $s2, $s2, 16
+        0x36520010,     //ori $s2, $s2, 0x10
+        0x24080032,     //addiu $t0, $zero, 50
+        0xa248000f,     //sb $t0, 0xf($s2), compare
+        0x24080007,     //addiu $t0, $zero, 7
+        0xa2480017,     //sb $t0, 0x17($s2), control: enable, periodic, irq
+        0x1000ffff      //beq $zero, $zero, -1
+    ];
+
+    //in real time the timer counts microseconds, and its thread raises the line
+    let mut c: Core = Core::with_clock(ClockMode::RealTime(std::time::Duration::from_secs(3600)));
+    c.load_image(&Utils::image_of(&[(0, &handler[..]), (0x4000, &program[..])]), 0x4000);
+    assert!(matches!(c.timer.state().base, TimeBase::RealTime(_)));
+
+    let start = Instant::now();
+    while !c.step().unwrap() && start.elapsed().as_secs() < 5 {}
+    assert!(c.is_finished());
+    assert_eq!(c.reg[RegNames::S7], 3);
+    assert!(start.elapsed().as_micros() >= 150);
+}
+
+#[test]
+fn core_dump_roundtrip() {
+    let mut c: Core = Core::new();
+    c.load_RELF("testbins/parsing_more.s.relf").unwrap();
+
+    let eobj = c.run().unwrap_err();
+    let mut dump = Vec::new();
+    c.dump_core(&mut dump, Some(&eobj)).unwrap();
+
+    let mut c2: Core = Core::new();
+    let fault = c2.load_core(dump.as_slice()).unwrap().unwrap();
+
+    assert_eq!(fault.kind, "MemError");
+    assert_eq!(fault.pc, 0x00400004);
+    assert_eq!(c2.PC, c.PC);
+    assert_eq!(c2.reg, c.reg);
+    assert_eq!(c2.fetch(0x00400004), Some(0x80800000));
+    assert_eq!(c2.backtrace(), c.backtrace());
+    //a restored Core faults the same way
+    assert_eq!(c2.run().unwrap_err().to_string(), eobj.to_string());
+
+    //frames returned from through the wrong $ra keep their marker
+    let mut c: Core = Core::new();
+    c.mem.store(0x1000, 4, &[0x0c, 0x00, 0x08, 0x00]).unwrap(); //jal 0x2000
+    c.mem.store(0x2000, 4, &[0x24, 0x1f, 0x30, 0x00]).unwrap(); //addiu $ra, $zero, 0x3000
+    c.mem.store(0x2004, 4, &[0x03, 0xe0, 0x00, 0x08]).unwrap(); //jr $ra
+    c.PC = 0x1000;
+    for _ in 0..3 { c.step().unwrap(); }
+    assert!(c.backtrace().contains("!! returned through $ra=0x00003000"));
+
+    let mut dump = Vec::new();
+    c.dump_core(&mut dump, None).unwrap();
+    let mut c2: Core = Core::new();
+    c2.load_core(dump.as_slice()).unwrap();
+    assert_eq!(c2.backtrace(), c.backtrace());
+}
+
+#[test]
+fn snapshot_resume() {
+    //a fast clock, so both runs take interrupts after the checkpoint
+    let mut c: Core = Core::with_clock(ClockMode::Virtual(300));
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+
+    for _ in 0..1000 { c.step().unwrap(); }
+
+    let mut snap = Vec::new();
+    c.save_snapshot(&mut snap).unwrap();
+
+    //restoring over a Core that already ran something else replaces its state
+    let mut c2: Core = Core::with_clock(ClockMode::Virtual(300));
+    c2.load_RELF("testbins/testingLS.s.relf").unwrap();
+    c2.protect_mem(0x00400000, 0x00400010);
+    c2.load_snapshot(snap.as_slice()).unwrap();
+
+    assert_eq!(c2.stats.instr_count, 1000);
+    assert_eq!(c2.mem.protected_ranges(), c.mem.protected_ranges());
+    assert_eq!(c2.snapshot(c2.PC, 0), c.snapshot(c.PC, 0));
+
+    //both machines take the same path from the checkpoint
+    for _ in 0..1000 {
+        c.step().unwrap();
+        c2.step().unwrap();
+        assert_eq!(c2.snapshot(c2.PC, 0), c.snapshot(c.PC, 0));
+    }
+}
+
+#[test]
+fn virtual_clock() {
+    let mut c: Core = Core::with_clock(ClockMode::Virtual(10));
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+    c.set_timing(TimingModel::flat());
+
+    //the clock fires exactly when the 10th cycle retires
+    for _ in 0..9 { c.step().unwrap(); }
+    assert_ne!(c.PC, c.irq_handler_addr);
+    c.step().unwrap();
+    assert_eq!(c.PC, c.irq_handler_addr);
+
+    //one-shot sources fire once, at their cycle
+    c.set_flag(false, Arch::IENABLE_FLAG);
+    c.timers.clear();
+    c.add_timer(VirtualInterruptor::at("Once", 20));
+    c.pic.borrow_mut().ack(1 << CLOCK_LINE);
+    for _ in 0..20 { c.step().unwrap(); }
+    assert_eq!(c.pic.borrow().pending, 1 << CLOCK_LINE);
+    assert!(!c.timers[0].poll(u64::MAX - 1));
+}
+
+#[test]
+fn virtual_clock_deterministic() {
+    let trace = || {
+        let mut c: Core = Core::with_clock(ClockMode::Virtual(100));
+        c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+        let mut epcs = Vec::new();
+        for _ in 0..2000 {
+            c.step().unwrap();
+            if c.PC == c.irq_handler_addr { epcs.push((c.instr_count(), c.EPC)); }
+        }
+        epcs
+    };
+
+    let first = trace();
+    assert!(first.len() > 5);
+    assert_eq!(first, trace());
+}
+
+#[test]
+fn journal_replay() {
+    //a run whose clock interrupts came from a thread, at instructions 5 and 40
+    let log = "MIPSJRNL 1\nclock realtime\n5 irq\n40 irq\n";
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+    c.load_journal(log.as_bytes()).unwrap();
+    c.set_journaling(true);
+
+    let mut taken = Vec::new();
+    for _ in 0..100 {
+        c.step().unwrap();
+        if c.PC == c.irq_handler_addr { taken.push(c.instr_count()); }
+    }
+    assert_eq!(taken, vec![5, 40]);
+
+    //saving again gives back the same log
+    let mut out = Vec::new();
+    c.save_journal(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), log);
+
+    //a virtual clock log sets up the clock it was recorded with
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_journal(&b"MIPSJRNL 1\nclock virtual 77\n"[..]).unwrap();
+    assert_eq!(c.clock, ClockMode::Virtual(77));
+    assert_eq!(c.timers, vec![VirtualInterruptor::periodic("Clock", 77)]);
+
+    //device input is journaled at the instruction that read it
+    let program: [Word; 3] = [
+        0x24128000,     //addiu $s2, $zero, 0x8000
+        0x00129400,     //sll $s2, $s2, 16
+        0x92480018      //lbu $t0, 0x18($s2), the real-time timer's count
+    ];
+    let mut c: Core = Core::with_clock(ClockMode::RealTime(std::time::Duration::from_secs(3600)));
+    c.load_image(&Utils::image_of(&[(0x4000, &program[..])]), 0x4000);
+    c.set_journaling(true);
+    c.set_flag(false, Arch::IENABLE_FLAG);
+    for _ in 0..3 { c.step().unwrap(); }
+    let journal = c.journal.borrow();
+    assert_eq!(journal.entries().iter().map(|e| e.at).collect::<Vec<_>>(), vec![2]);
+}
+
+#[test]
+fn trace_records() {
+    use super::Definitions::Trace::TraceFormat;
+
+    let path = std::env::temp_dir().join(format!("mips_trace_{}.txt", std::process::id()));
+
+    let mut c: Core = Core::new();
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    c.start_trace(Tracer::new(Box::new(std::fs::File::create(&path).unwrap()), TraceFormat::Text).unwrap());
+    c.run().unwrap();
+    c.finish_trace().unwrap();
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), c.stats.instr_count);
+    assert!(lines[0].starts_with("00400000\t"));
+    assert!(lines.iter().all(|l| l.split('\t').count() == 4));
+    assert_eq!(lines[3], "0040000c\t24020004\taddiu $v0, $zero, 4\t$v0=00000004");
+    assert_eq!(lines[4], "00400010\t68000000\tsyscall\t!syscall");
+    //the irqH writes the console mode register
+    assert!(lines.iter().any(|l| l.ends_with("\tS1@80000004=03")));
+}
+
+#[test]
+fn trace_writes() {
+    use super::Definitions::Trace::TraceFormat;
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.mem.store(0x1000, 4, &[0x24, 0x08, 0x10, 0x04]).unwrap(); //addiu $t0, $zero, 0x1004
+    c.mem.store(0x1004, 4, &[0xa1, 0x00, 0x00, 0x03]).unwrap(); //sb $zero, 3($t0), over itself
+    c.mem.store(0x1008, 4, &[0x01, 0x20, 0x48, 0x21]).unwrap(); //addu $t1, $t1, $zero
+    c.PC = 0x1000;
+    c.start_trace(Tracer::new(Box::new(std::io::sink()), TraceFormat::Text).unwrap());
+
+    c.step().unwrap();
+    c.step().unwrap();
+    //the word that ran, not the one it left behind
+    assert_eq!(c.trace_record().unwrap().code, 0xa1000003);
+    assert!(c.trace_record().unwrap().regs.is_empty());
+
+    //writes of the value a register already held are recorded too
+    c.step().unwrap();
+    assert_eq!(c.trace_record().unwrap().regs, vec![(9, 0)]);
+}
+
+#[test]
+fn stats_counts() {
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    c.set_opcode_counts(true);
+    c.run().unwrap();
+
+    let stats = c.stats();
+    assert_eq!(stats.opcodes.iter().sum::<u64>(), stats.instr_count as u64);
+    assert_eq!(stats.branches_taken + stats.branches_not_taken, ["beq", "bne", "bgtz", "blez"].iter().map(|m| stats.opcodes[Disasm::index(m)]).sum::<u64>());
+    assert_eq!(stats.stores[0], stats.opcodes[Disasm::index("sb")]);
+    assert!(stats.syscalls > 0);
+    assert!(stats.priv_instr_count > 0 && stats.priv_instr_count < stats.instr_count as u64);
+
+    //opcodes are only counted when asked for
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/testingLS.s.relf").unwrap();
+    c.run().unwrap();
+    assert!(c.stats().histogram().is_empty());
+}
+
+#[test]
+fn timing_model() {
+    let run = |timing: TimingModel| {
+        let mut c: Core = Core::with_clock(ClockMode::Off);
+        c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+        c.set_timing(timing);
+        c.run().unwrap();
+        (c.stats.instr_count, c.stats.cycl_count)
+    };
+
+    let (instrs, cycles) = run(TimingModel::flat());
+    assert_eq!(instrs, cycles);
+
+    //the loop ends in a taken bne every 3 instructions, and each one costs a cycle more
+    let (_, r3000) = run(TimingModel::default());
+    let (_, no_penalty) = run(TimingModel { branch: 0, ..TimingModel::default() });
+    assert!(r3000 > cycles);
+    assert_eq!(r3000 - no_penalty, 9989);
+}
+
+#[test]
+fn cache_cycles() {
+    use super::Cache::{Cache, CacheConfig};
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+    c.set_timing(TimingModel::flat());
+    let icache = Cache::new("L1I", CacheConfig { size: 64, latency: 0, ..Default::default() });
+    c.set_caches(Some(Hierarchy { icache: Some(icache), dcache: None, l2: None, memory_latency: 10 }));
+    c.run().unwrap();
+
+    //the loop fits in the cache, so fetches only miss the first time through
+    let l1i = c.stats.caches["L1I"];
+    assert_eq!(l1i.hits + l1i.misses, c.stats.instr_count as u64);
+    assert!(l1i.misses < 8);
+    assert_eq!(c.stats.cycl_count as u64, c.stats.instr_count as u64 + 10 * l1i.misses);
+    assert!(!c.stats.caches.contains_key("L1D"));
+}
+
+#[test]
+fn predictor_timing() {
+    use super::Predictor::{self, Btb};
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+    c.set_timing(TimingModel { mispredict: 5, ..TimingModel::flat() });
+    let predictors = vec![Predictor::by_name("2bit", 8).unwrap(), Predictor::by_name("not-taken", 8).unwrap()];
+    c.set_predictors(Some(BranchUnit::new(predictors, Btb::new(16), true)));
+    c.run().unwrap();
+
+    //the loop's bne is mispredicted by 2bit only on the way in and out
+    let acc = c.predictors().unwrap().accuracy();
+    let missed = acc[0].1.branches + acc[0].1.jumps - acc[0].1.branch_hits - acc[0].1.jump_hits;
+    assert!(acc[0].1.branches >= 9989);
+    assert!(missed < 5);
+    assert!(acc[1].1.rate() < 0.01);
+    assert_eq!(c.stats.cycl_count as u64, c.stats.instr_count as u64 + 5 * missed);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn jit_equivalence() {
+    use super::Jit::Jit;
+
+    //a short clock period, so blocks have to stop short of it
+    let run = |path: &str, jit: bool| {
+        let mut c: Core = Core::with_clock(ClockMode::Virtual(100));
+        c.load_RELF(path).unwrap();
+        c.set_opcode_counts(true);
+        if jit { c.set_jit(Some(Jit::new(1).unwrap())); }
+        let res = c.run().map_err(|eobj| eobj.to_string());
+        let mut snapshot = Vec::new();
+        c.save_snapshot(&mut snapshot).unwrap();
+        let s = c.stats();
+        (res, snapshot, s.histogram(), (s.branches_taken, s.branches_not_taken, s.loads, s.stores, s.interrupts), c.jit().map(|j| j.to_string()))
+    };
+
+    for entry in std::fs::read_dir("testbins").unwrap() {
+        let path = entry.unwrap().path();
+        let path = path.to_str().unwrap();
+
+        let (ours, theirs) = (run(path, true), run(path, false));
+        assert_eq!((&ours.0, &ours.1, &ours.2, &ours.3), (&theirs.0, &theirs.1, &theirs.2, &theirs.3), "{path}");
+    }
+
+    let jit = run("testbins/perf_test_newcompile.relf", true).4.unwrap();
+    assert!(!jit.ends_with(" 0 instructions run translated"), "{jit}");
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn jit_self_modifying_code() {
+    use super::Jit::Jit;
+
+    let mut c: Core = Core::with_clock(ClockMode::Off);
+    c.set_jit(Some(Jit::new(1).unwrap()));
+
+    //each iteration patches the immediate of the addiu after it with the counter
+    c.mem.store(0x1000, 4, &[0x24, 0x63, 0x00, 0x01]).unwrap(); //addiu $v1, $v1, 1
+    c.mem.store(0x1004, 4, &[0xa0, 0x03, 0x10, 0x0b]).unwrap(); //sb $v1, 0x100b($zero)
+    c.mem.store(0x1008, 4, &[0x24, 0x05, 0x00, 0x00]).unwrap(); //addiu $a1, $zero, 0
+    c.mem.store(0x100c, 4, &[0x14, 0x64, 0xff, 0xfc]).unwrap(); //bne $v1, $a0, -4
+    c.mem.store(0x1010, 4, &[0x20, 0x02, 0x00, 0x0a]).unwrap(); //li $v0, 10
+    c.mem.store(0x1014, 4, &[0x68, 0x00, 0x00, 0x00]).unwrap(); //syscall
+    c.reg[RegNames::A0] = 100;
+    c.PC = 0x1000;
+
+    c.run().unwrap();
+    assert_eq!((c.reg[RegNames::V1], c.reg[RegNames::A1]), (100, 100));
+    assert!(!c.jit().unwrap().to_string().contains(" 0 dropped"));
+}
+
 #[test]
 fn default_irqH() {
-    let mut c: Core = Core::new(true);
+    let mut c: Core = Core::new();
  
     c.mem.store(0xff0f8, 4, &[0x20, 0x02, 0x00, 0x0A]).unwrap(); //li $v0, 10
     c.mem.store(0xff0fc, 4, &[0x68,0x00,0x00,0x00]).unwrap(); //syscall
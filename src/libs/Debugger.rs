@@ -0,0 +1,453 @@
+use super::Core::Core;
+use super::Definitions::Disasm;
+use super::Definitions::Utils;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufRead, Write};
+
+const HELP: &str = "\
+Commands:
+  s, step [n]          Execute n instructions (default 1)
+  c, continue          Run until a breakpoint, a fault or HLT
+  rs, reverse-step [n] Go back n instructions (default 1)
+  rc, reverse-continue Go back to the previous breakpoint hit
+  b, break [loc]       Set a breakpoint at loc, or list breakpoints
+  d, delete <loc>      Remove the breakpoint at loc
+  r, regs              Show the register file
+  bt, backtrace        Show the guest call stack
+  x <loc> [n]          Examine n words of memory (default 4)
+  dis [loc] [n]        Disassemble n instructions (default PC, 5)
+  q, quit              Leave the debugger
+  h, help              Show this message
+
+  loc is a hex address prefixed with 0x, a decimal address or a symbol name.
+  An empty line repeats the last command.
+
+  Going back restores the nearest checkpoint and re-executes from it, replaying
+  recorded interrupts and keyboard input. Console output is printed again";
+
+// default number of instructions between checkpoints
+const CHECKPOINT_INTERVAL: u64 = 1000;
+// checkpoints kept; past it, older ones are thinned out
+const MAX_CHECKPOINTS: usize = 32;
+
+/**
+ * Interactive debugger driving a Core one instruction at a time
+ *
+ * While stepping it keeps snapshots of the Core every checkpoint_interval
+ * instructions, keyed by instruction count, to travel back in time from. Only
+ * MAX_CHECKPOINTS are kept: every time there are more, every other one of the older
+ * half goes, so they are denser the more recent they are. The first one is always kept
+ */
+pub struct Debugger {
+    breakpoints: Vec<u32>,
+    last_cmd: String,
+    checkpoints: BTreeMap<u64, Vec<u8>>,
+    checkpoint_interval: u64
+}
+
+impl Debugger {
+
+    pub fn new() -> Debugger {
+        Debugger { breakpoints: Vec::new(), last_cmd: String::new(), checkpoints: BTreeMap::new(), checkpoint_interval: CHECKPOINT_INTERVAL }
+    }
+
+    /**
+     * Sets the number of instructions between checkpoints, which must not be 0
+     */
+    #[allow(dead_code)]
+    pub fn set_checkpoint_interval(&mut self, interval: u64) -> Result<(), String> {
+        if interval == 0 { return Err(String::from("The checkpoint interval must be at least 1")); }
+        self.checkpoint_interval = interval;
+        Ok(())
+    }
+
+    /**
+     * Adds a breakpoint at addr
+     */
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    /**
+     * Reads commands from input until quit or end of input, writing results to out
+     *
+     * ARGS:
+     *
+     *  core: The Core to debug
+     *
+     *  input: Source of commands, one per line
+     *
+     *  out: Where to write prompts and results
+     */
+    pub fn repl<R: BufRead, W: Write>(&mut self, core: &mut Core, mut input: R, out: &mut W) -> io::Result<()> {
+
+        writeln!(out, "Stopped at {}  {}", core.symbolize(core.get_PC()), Self::disasm_at(core, core.get_PC()))?;
+
+        core.set_journaling(true);
+
+        loop {
+            write!(out, "(mips) ")?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 { break; }
+
+            let mut cmd = line.trim().to_string();
+            if cmd.is_empty() {
+                cmd = self.last_cmd.clone();
+            } else {
+                self.last_cmd = cmd.clone();
+            }
+
+            if !self.command(core, &cmd, out)? { break; }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Executes a single debugger command
+     *
+     * RETURNS:
+     *
+     *  false if the debugger should exit
+     */
+    pub fn command<W: Write>(&mut self, core: &mut Core, cmd: &str, out: &mut W) -> io::Result<bool> {
+
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        if args.is_empty() { return Ok(true); }
+
+        match args[0] {
+            "s" | "step" => {
+                let n = Self::parse_count(args.get(1), 1);
+                for _ in 0..n {
+                    if !self.step(core, out)? { break; }
+                }
+                writeln!(out, "{}  {}", core.symbolize(core.get_PC()), Self::disasm_at(core, core.get_PC()))?;
+            }
+            "c" | "continue" => {
+                loop {
+                    if !self.step(core, out)? { break; }
+                    if self.breakpoints.contains(&core.get_PC()) {
+                        writeln!(out, "Breakpoint at {}  {}", core.symbolize(core.get_PC()), Self::disasm_at(core, core.get_PC()))?;
+                        break;
+                    }
+                }
+            }
+            "rs" | "reverse-step" => {
+                let n = Self::parse_count(args.get(1), 1) as u64;
+                if self.travel(core, core.instr_count().saturating_sub(n), out)? {
+                    self.report_position(core, out)?;
+                }
+            }
+            "rc" | "reverse-continue" => {
+                self.reverse_continue(core, out)?;
+            }
+            "b" | "break" => {
+                match args.get(1) {
+                    Some(loc) => match Self::parse_loc(core, loc) {
+                        Some(addr) => {
+                            self.add_breakpoint(addr);
+                            writeln!(out, "Breakpoint set at {}", core.symbolize(addr))?;
+                        }
+                        None => writeln!(out, "Unknown location '{loc}'")?
+                    },
+                    None => {
+                        for addr in &self.breakpoints {
+                            writeln!(out, "  {}", core.symbolize(*addr))?;
+                        }
+                    }
+                }
+            }
+            "d" | "delete" => {
+                match args.get(1).and_then(|loc| Self::parse_loc(core, loc)) {
+                    Some(addr) => self.breakpoints.retain(|b| *b != addr),
+                    None => writeln!(out, "Usage: delete <loc>")?
+                }
+            }
+            "r" | "regs" => {
+                writeln!(out, "pc    = {}\n{}", core.symbolize(core.get_PC()), core.snapshot(core.get_PC(), 0))?;
+            }
+            "bt" | "backtrace" => {
+                writeln!(out, "{}", core.backtrace())?;
+            }
+            "x" => {
+                match args.get(1).and_then(|loc| Self::parse_loc(core, loc)) {
+                    Some(addr) => {
+                        let n = Self::parse_count(args.get(2), 4);
+                        for i in 0..n {
+                            let a = addr.wrapping_add(4 * i);
+                            match core.peek(a, 4) {
+                                Some(w) => writeln!(out, "  {}: 0x{:08x}", core.symbolize(a), Utils::from_word(w))?,
+                                None => { writeln!(out, "  0x{:08x}: <not allocated>", a)?; break; }
+                            }
+                        }
+                    }
+                    None => writeln!(out, "Usage: x <loc> [n]")?
+                }
+            }
+            "dis" => {
+                let addr = args.get(1).and_then(|loc| Self::parse_loc(core, loc)).unwrap_or(core.get_PC());
+                let n = Self::parse_count(args.get(2), 5);
+                for i in 0..n {
+                    let a = addr.wrapping_add(4 * i);
+                    writeln!(out, "{} {}  {}", if a == core.get_PC() { "=>" } else { "  " }, core.symbolize(a), Self::disasm_at(core, a))?;
+                }
+            }
+            "q" | "quit" => { return Ok(false); }
+            "h" | "help" => { writeln!(out, "{HELP}")?; }
+            _ => { writeln!(out, "Unknown command '{}'; type 'help' for a list of commands", args[0])?; }
+        }
+
+        Ok(true)
+    }
+
+    /**
+     * Executes one instruction, reporting the end of the program or a fault
+     *
+     * RETURNS:
+     *
+     *  false if the Core could not make progress
+     */
+    fn step<W: Write>(&mut self, core: &mut Core, out: &mut W) -> io::Result<bool> {
+
+        if core.is_finished() {
+            writeln!(out, "The program has finished")?;
+            return Ok(false);
+        }
+
+        let n = core.instr_count();
+        if !self.checkpoints.contains_key(&n) && (self.checkpoints.is_empty() || n.is_multiple_of(self.checkpoint_interval)) {
+            let mut snap = Vec::new();
+            match core.save_snapshot(&mut snap) {
+                Ok(()) => { self.checkpoints.insert(n, snap); }
+                Err(eobj) => writeln!(out, "Could not take a checkpoint: {eobj}")?
+            }
+            self.thin_checkpoints();
+        }
+
+        match core.step() {
+            Ok(false) => Ok(true),
+            Ok(true) => {
+                writeln!(out, "The program has finished")?;
+                Ok(false)
+            }
+            Err(eobj) => {
+                writeln!(out, "Fault: {}: {eobj}\n{}", eobj.kind(), core.backtrace())?;
+                Ok(false)
+            }
+        }
+    }
+
+    /**
+     * Drops every other checkpoint of the older half, but the first, once there are
+     * more than MAX_CHECKPOINTS
+     */
+    fn thin_checkpoints(&mut self) {
+
+        if self.checkpoints.len() <= MAX_CHECKPOINTS { return; }
+
+        let older: Vec<u64> = self.checkpoints.keys().skip(1).take(MAX_CHECKPOINTS / 2).step_by(2).copied().collect();
+        for at in older { self.checkpoints.remove(&at); }
+    }
+
+    /**
+     * Brings core back to the point where target instructions had been executed, by
+     * restoring the closest checkpoint at or before it and re-executing
+     *
+     * RETURNS:
+     *
+     *  false if there is no checkpoint to start from or re-execution failed
+     */
+    fn travel<W: Write>(&mut self, core: &mut Core, target: u64, out: &mut W) -> io::Result<bool> {
+
+        let (start, snap) = match self.checkpoints.range(..=target).next_back() {
+            Some(checkpoint) => checkpoint,
+            None => {
+                writeln!(out, "No recorded history to go back to")?;
+                return Ok(false);
+            }
+        };
+
+        if let Err(eobj) = core.load_snapshot(snap.as_slice()) {
+            writeln!(out, "Could not restore checkpoint at instruction {start}: {eobj}")?;
+            return Ok(false);
+        }
+        core.seek_journal(*start);
+
+        while core.instr_count() < target {
+            if let Err(eobj) = core.step() {
+                writeln!(out, "Fault while re-executing: {}: {eobj}", eobj.kind())?;
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /**
+     * Goes back to the last time execution reached a breakpoint before the current
+     * instruction, or to the start of the recorded history if there is none
+     */
+    fn reverse_continue<W: Write>(&mut self, core: &mut Core, out: &mut W) -> io::Result<()> {
+
+        let now = core.instr_count();
+        let starts: Vec<u64> = self.checkpoints.range(..now).map(|(at, _)| *at).rev().collect();
+
+        //search the segments between checkpoints from the most recent one backwards
+        for (i, start) in starts.iter().enumerate() {
+
+            let end = if i == 0 { now } else { starts[i-1] };
+
+            if !self.travel(core, *start, out)? { return Ok(()); }
+
+            let mut last_hit = None;
+            while core.instr_count() < end {
+                if self.breakpoints.contains(&core.get_PC()) { last_hit = Some(core.instr_count()); }
+                if let Err(eobj) = core.step() {
+                    writeln!(out, "Fault while re-executing: {}: {eobj}", eobj.kind())?;
+                    return Ok(());
+                }
+            }
+
+            if let Some(hit) = last_hit {
+                if self.travel(core, hit, out)? {
+                    write!(out, "Breakpoint at ")?;
+                    self.report_position(core, out)?;
+                }
+                return Ok(());
+            }
+        }
+
+        match starts.last() {
+            Some(first) => {
+                if self.travel(core, *first, out)? {
+                    writeln!(out, "No earlier breakpoint hit; stopped at the start of the recorded history")?;
+                    self.report_position(core, out)?;
+                }
+            }
+            None => writeln!(out, "No recorded history to go back to")?
+        }
+
+        Ok(())
+    }
+
+    fn report_position<W: Write>(&self, core: &Core, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}  {}  (instruction {})", core.symbolize(core.get_PC()), Self::disasm_at(core, core.get_PC()), core.instr_count())
+    }
+
+    fn disasm_at(core: &Core, addr: u32) -> String {
+        match core.fetch(addr) {
+            Some(code) => Disasm::disassemble(code),
+            None => String::from("<not allocated>")
+        }
+    }
+
+    fn parse_count(arg: Option<&&str>, default: u32) -> u32 {
+        arg.and_then(|s| s.parse::<u32>().ok()).unwrap_or(default)
+    }
+
+    /**
+     * Parses a location, either a hex address prefixed by 0x, a decimal address or a symbol
+     */
+    pub fn parse_loc(core: &Core, s: &str) -> Option<u32> {
+
+        if s.starts_with("0x") | s.starts_with("0X") {
+            u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"), 16).ok()
+        } else if let Ok(addr) = s.parse::<u32>() {
+            Some(addr)
+        } else {
+            core.symbol_addr(s)
+        }
+    }
+
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn breakpoints() {
+    let mut c = Core::new();
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+
+    let out = session(&mut c, "b 0x4018\nc\n\nr\nq\n");
+
+    assert_eq!(c.get_PC(), 0x4018);
+    assert!(out.contains("Breakpoint at 0x00004018 <_start+0x18>  bne $t2, $v0, -3"));
+    assert!(out.contains("$v0"));
+}
+
+#[test]
+fn step_and_examine() {
+    let mut c = Core::new();
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+
+    let out = session(&mut c, "s 2\nx _start 2\ndis\nbogus\n");
+
+    assert_eq!(c.get_PC(), 0x4008);
+    assert!(out.contains("0x00004000 <_start>: 0x2002270f"));
+    assert!(out.contains("=> 0x00004008 <_start+0x8>  addi $t2, $zero, 10"));
+    assert!(out.contains("Unknown command 'bogus'"));
+}
+
+#[test]
+fn fault_backtrace() {
+    let mut c = Core::new();
+
+    c.load_RELF("testbins/parsing_more.s.relf").unwrap();
+    let out = session(&mut c, "c\nbt\n");
+
+    assert!(out.contains("Fault: MemError"));
+    assert!(out.contains("#0  0x00400004 <_start+0x4>"));
+}
+
+#[test]
+fn reverse_execution() {
+    let mut c = Core::new();
+    c.load_RELF("testbins/perf_test_newcompile.relf").unwrap();
+
+    let mut d = Debugger::new();
+    d.set_checkpoint_interval(64).unwrap();
+    assert!(Debugger::new().set_checkpoint_interval(0).is_err());
+    let mut out = Vec::new();
+
+    //the loop body starts at 0x4010, run through it a few hundred times
+    d.repl(&mut c, "s 300\n".as_bytes(), &mut out).unwrap();
+    let after = c.snapshot(c.get_PC(), 0);
+    d.repl(&mut c, "s\nrs\n".as_bytes(), &mut out).unwrap();
+    assert_eq!(c.instr_count(), 300);
+    assert_eq!(c.snapshot(c.get_PC(), 0), after);
+
+    d.repl(&mut c, "b 0x4010\nrc\n".as_bytes(), &mut out).unwrap();
+    assert_eq!(c.get_PC(), 0x4010);
+    assert!(c.instr_count() < 300 && c.instr_count() > 290);
+
+    d.repl(&mut c, "rs 1000\n".as_bytes(), &mut out).unwrap();
+    assert_eq!(c.instr_count(), 0);
+    assert_eq!(c.get_PC(), 0x4000);
+
+    //a long run keeps a bounded number of checkpoints, denser towards the end
+    let mut d = Debugger::new();
+    d.set_checkpoint_interval(1).unwrap();
+    d.repl(&mut c, "s 500\n".as_bytes(), &mut out).unwrap();
+    let at: Vec<u64> = d.checkpoints.keys().copied().collect();
+    assert!(at.len() <= MAX_CHECKPOINTS);
+    assert_eq!((at[0], at[at.len() - 1]), (0, 499));
+    assert!(at[2] - at[1] > at[at.len() - 1] - at[at.len() - 2]);
+
+    d.repl(&mut c, "rs 250\n".as_bytes(), &mut out).unwrap();
+    assert_eq!(c.instr_count(), 250);
+}
+
+#[cfg(test)]
+fn session(core: &mut Core, cmds: &str) -> String {
+    let mut out = Vec::new();
+    Debugger::new().repl(core, cmds.as_bytes(), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
@@ -1,7 +1,10 @@
 /**
  *  FLAG FORMAT
- *  0 1 2 3 4 ...
- *  Z|S| |M|F|
+ *  0 1 2 3 4 5 6  7  8  9 ...
+ *  Z|S|I|E|M|F|Ep|Mp|Eo|Mo|
+ *
+ *  E and M, interrupts enabled and privileged mode, are the top of the KU/IE stack:
+ *  exceptions push them to Ep and Mp, and those to Eo and Mo. RFE pops them back
  */
 
 pub const Z_FLAG:      u32 = 1;
@@ -10,6 +13,10 @@ pub const INTERR_FLAG: u32 = 1<<2;
 pub const IENABLE_FLAG: u32= 1<<3;
 pub const MODE_FLAG:   u32 = 1<<4;
 pub const FIN_FLAG:    u32 = 1<<5;
+pub const PREV_IENABLE_FLAG: u32 = 1<<6;
+pub const PREV_MODE_FLAG:    u32 = 1<<7;
+pub const OLD_IENABLE_FLAG:  u32 = 1<<8;
+pub const OLD_MODE_FLAG:     u32 = 1<<9;
 
 
 
@@ -65,6 +72,8 @@ pub const DEFAULT_IRQH: [u8; 172] = [
 
 pub const STACKSIZE : u32 = 512; // 512b stack
 
+pub const CLOCK_PERIOD_CYCLES : u64 = 1_000_000; // default virtual clock tick
+
 pub mod RegNames {
 
     pub const ZERO : usize = 0;
@@ -118,6 +127,11 @@ pub mod OP {
 
     pub const SYSCALL: u32 = 0x68000000;
 
+    // mfc0 $rt, $rd and mtc0 $rt, $rd: rt and rd vary, every other bit is fixed
+    pub const MFC0     : u32 = 0x40000000;
+    pub const MTC0     : u32 = 0x40800000;
+    pub const MFC0_MASK: u32 = 0xffe007ff;
+
     pub mod R {
 
         pub const ADD  : u32 = 0b100000;
@@ -169,7 +183,8 @@ pub mod OP {
         pub const SB   : u32 = 0b101000;
         pub const SH   : u32 = 0b101001;
         pub const SW   : u32 = 0b101011;
-        
+        pub const COP0 : u32 = 0b010000;
+
     }
 
     pub mod J {
@@ -181,3 +196,30 @@ pub mod OP {
 
 
 }
+
+/**
+ *  Coprocessor 0 registers read by mfc0 and written by mtc0, and the exception codes in Cause
+ *
+ *      Status: the flags, as in FLAG FORMAT; mtc0 only writes the KU/IE stack
+ *      Cause:  the exception code at bits 6..2, and the interrupt controller's unmasked
+ *              pending lines at bits 15..8 (IP), one per line. Read only
+ *      EPC:    where RFE returns to
+ *
+ *  A handler that enables interrupts must save EPC first, as a nested exception overwrites it
+ */
+pub mod CP0 {
+
+    use super::{IENABLE_FLAG, MODE_FLAG, PREV_IENABLE_FLAG, PREV_MODE_FLAG, OLD_IENABLE_FLAG, OLD_MODE_FLAG};
+
+    pub const STATUS: usize = 12;
+    pub const CAUSE : usize = 13;
+    pub const EPC   : usize = 14;
+
+    pub const EXC_INT: u32 = 0;
+    pub const EXC_SYS: u32 = 8;
+
+    pub const IP_SHIFT: u32 = 8;
+
+    pub const STATUS_WRITABLE: u32 = IENABLE_FLAG | MODE_FLAG | PREV_IENABLE_FLAG | PREV_MODE_FLAG | OLD_IENABLE_FLAG | OLD_MODE_FLAG;
+
+}
@@ -0,0 +1,120 @@
+/**
+ *  Shadow call stack kept by Core from JAL/JALR, `jr $ra`, interrupts and RFE
+ *
+ *  It is not visible to the guest, so it survives anything the guest does
+ *  to its own stack and can be compared against the $ra the guest returns through
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Exception
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_site: u32,
+    pub target: u32,
+    pub ret_addr: u32,
+    // set when the guest returned from this frame through a different $ra
+    pub ra_mismatch: Option<u32>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>
+}
+
+impl CallStack {
+
+    /**
+     * Records a call from call_site to target that is expected to return to ret_addr
+     */
+    pub fn call(&mut self, call_site: u32, target: u32, ret_addr: u32) {
+        self.frames.push( Frame { kind: FrameKind::Call, call_site, target, ret_addr, ra_mismatch: None } );
+    }
+
+    /**
+     * Records entering the irqH from epc
+     */
+    pub fn exception(&mut self, epc: u32, handler: u32) {
+        self.frames.push( Frame { kind: FrameKind::Exception, call_site: epc, target: handler, ret_addr: epc, ra_mismatch: None } );
+    }
+
+    /**
+     * Records a `jr $ra`
+     *
+     * If ra matches a frame, that frame and everything above it is popped.
+     * Otherwise the innermost call frame is flagged and kept, since the guest
+     * has returned somewhere its caller never asked for
+     *
+     * ARGS:
+     *
+     *  ra: The address the guest is returning to
+     *
+     * RETURNS:
+     *
+     *  The flagged frame on a mismatch
+     */
+    pub fn ret(&mut self, ra: u32) -> Option<&Frame> {
+
+        match self.frames.iter().rposition(|f| f.kind == FrameKind::Call && f.ret_addr == ra) {
+            Some(i) => { self.frames.truncate(i); None }
+            None => {
+                let top = self.frames.iter_mut().rev().find(|f| f.kind == FrameKind::Call)?;
+                top.ra_mismatch = Some(ra);
+                Some(top)
+            }
+        }
+    }
+
+    /**
+     * Records an RFE, dropping everything down to the innermost exception frame
+     */
+    pub fn rfe(&mut self) {
+        if let Some(i) = self.frames.iter().rposition(|f| f.kind == FrameKind::Exception) {
+            self.frames.truncate(i);
+        }
+    }
+
+    /**
+     * Pushes a frame as is, used when restoring a saved stack
+     */
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /**
+     * Frames from outermost to innermost
+     */
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn call_and_return() {
+    let mut cs = CallStack::default();
+
+    cs.call(0x100, 0x200, 0x100);
+    cs.call(0x204, 0x300, 0x204);
+    assert!(cs.ret(0x204).is_none());
+    assert_eq!(cs.frames().len(), 1);
+
+    //returning through an unknown $ra flags the innermost frame and keeps it
+    assert_eq!(cs.ret(0xdead).unwrap().ra_mismatch, Some(0xdead));
+    assert_eq!(cs.frames().len(), 1);
+
+    //returning to an outer frame unwinds everything above it
+    cs.call(0x208, 0x300, 0x208);
+    cs.exception(0x304, 0x0);
+    cs.rfe();
+    assert!(cs.ret(0x100).is_none());
+    assert!(cs.frames().is_empty());
+}
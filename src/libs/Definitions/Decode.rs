@@ -0,0 +1,219 @@
+/*!
+ *  Predecoded instructions
+ *
+ *  Memory keeps the instructions it has fetched decoded in a DecodeCache, one slot per
+ *  word of every page code was fetched from, so Core does not re-read and re-mask
+ *  them on every step. A store to a page drops its decoded instructions
+ */
+
+use super::Disasm;
+use super::Snapshot::PAGE_SIZE;
+use super::Utils::Word;
+
+use std::collections::HashMap;
+
+const SLOTS: usize = PAGE_SIZE / 4;
+
+/**
+ * The fields of an instruction word, masked once
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoded {
+    pub code: Word,
+    // bits 31..26
+    pub op: u32,
+    pub rs: usize,
+    pub rt: usize,
+    pub rd: usize,
+    pub sham: u32,
+    pub func: u32,
+    // zero extended
+    pub imm: u32,
+    // J-type target, shifted into a byte address
+    pub target: u32,
+    pub mnemonic: &'static str,
+    // index of mnemonic in Disasm::MNEMONICS
+    pub opcode: usize,
+    // registers read, as Disasm::sources
+    pub sources: [Option<u32>; 2],
+    pub branch: bool,
+    pub jump: bool
+}
+
+pub fn decode(code: Word) -> Decoded {
+    Decoded {
+        code,
+        op: (code & 0xfc000000) >> 26,
+        rs: ((code & 0x03e00000) >> 21) as usize,
+        rt: ((code & 0x001f0000) >> 16) as usize,
+        rd: ((code & 0x0000f800) >> 11) as usize,
+        sham: (code & 0x000007c0) >> 6,
+        func: code & 0x0000003f,
+        imm: code & 0x0000ffff,
+        target: (code & !0xfc000000) << 2,
+        mnemonic: Disasm::mnemonic(code),
+        opcode: Disasm::opcode(code),
+        sources: Disasm::sources(code),
+        branch: Disasm::is_branch(code),
+        jump: Disasm::is_jump(code)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    decoded: Decoded,
+    // inside a protected range, so only fetched this way when privileged
+    protected: bool
+}
+
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    // by page number, indexed by word in the page
+    pages: HashMap<u32, Box<[Option<Slot>]>>,
+    // the page of the last hit, to skip the map lookup in straight-line code
+    last: Option<(u32, Box<[Option<Slot>]>)>,
+    // when tracking, pages dropped by invalidate and times the cache was cleared
+    track: bool,
+    dropped: Vec<u32>,
+    clears: u64
+}
+
+impl DecodeCache {
+
+    /**
+     * The instruction decoded at addr, and whether addr is protected
+     */
+    #[inline(always)]
+    pub fn get(&mut self, addr: u32) -> Option<(Decoded, bool)> {
+
+        if !addr.is_multiple_of(4) { return None; }
+
+        let page = addr / PAGE_SIZE as u32;
+        let word = (addr as usize % PAGE_SIZE) / 4;
+
+        if self.last.as_ref().map(|(p, _)| *p) != Some(page) {
+            let slots = self.pages.remove(&page)?;
+            if let Some((old, old_slots)) = self.last.take() { self.pages.insert(old, old_slots); }
+            self.last = Some((page, slots));
+        }
+
+        let slot = self.last.as_ref()?.1[word]?;
+        Some((slot.decoded, slot.protected))
+    }
+
+    pub fn insert(&mut self, addr: u32, decoded: Decoded, protected: bool) {
+
+        if !addr.is_multiple_of(4) { return; }
+
+        let page = addr / PAGE_SIZE as u32;
+        let word = (addr as usize % PAGE_SIZE) / 4;
+        let slot = Some(Slot { decoded, protected });
+
+        match &mut self.last {
+            Some((p, slots)) if *p == page => slots[word] = slot,
+            _ => self.pages.entry(page).or_insert_with(|| vec![None; SLOTS].into_boxed_slice())[word] = slot
+        }
+    }
+
+    /**
+     * Drops the decoded instructions of the pages that addr..addr+size touches
+     */
+    #[inline(always)]
+    pub fn invalidate(&mut self, addr: usize, size: usize) {
+
+        if self.last.is_none() && self.pages.is_empty() { return; }
+
+        let first = (addr / PAGE_SIZE) as u32;
+        let last = ((addr + size.max(1) - 1) / PAGE_SIZE) as u32;
+
+        for page in first..=last {
+            let mut cached = self.pages.remove(&page).is_some();
+            if matches!(&self.last, Some((p, _)) if *p == page) { self.last = None; cached = true; }
+            if cached && self.track { self.dropped.push(page); }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.last = None;
+        self.clears += 1;
+    }
+
+    /**
+     * Starts or stops keeping the pages dropped by stores, for take_dropped
+     */
+    pub fn track(&mut self, on: bool) {
+        self.track = on;
+        self.dropped.clear();
+    }
+
+    /**
+     * True if a store dropped decoded instructions since the last take_dropped
+     */
+    pub fn has_dropped(&self) -> bool {
+        !self.dropped.is_empty()
+    }
+
+    /**
+     * The pages dropped by stores since the last call, and how many times the whole
+     * cache has been cleared
+     */
+    pub fn take_dropped(&mut self) -> (u64, Vec<u32>) {
+        (self.clears, std::mem::take(&mut self.dropped))
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn decode_fields() {
+    let d = decode(0x00430018);     //mult $v0, $v1
+    assert_eq!((d.op, d.rs, d.rt, d.func, d.mnemonic), (0, 2, 3, 0x18, "mult"));
+
+    let d = decode(0x1443fffe);     //bne $v0, $v1, -2
+    assert_eq!((d.op, d.imm, d.branch), (5, 0xfffe, true));
+
+    let d = decode(0x0c000800);     //jal 0x2000
+    assert_eq!((d.target, d.branch, d.jump), (0x2000, false, true));
+}
+
+#[test]
+fn cache_invalidation() {
+    let mut c = DecodeCache::default();
+    let d = decode(0x24020004);
+
+    c.insert(0x1000, d, false);
+    c.insert(0x2004, d, true);
+    assert_eq!(c.get(0x1000), Some((d, false)));
+    assert_eq!(c.get(0x2004), Some((d, true)));
+    assert_eq!(c.get(0x1004), None);
+    assert_eq!(c.get(0x1002), None);
+
+    //a store to a page drops it, and only it
+    c.invalidate(0x1ffe, 1);
+    assert_eq!(c.get(0x1000), None);
+    assert!(c.get(0x2004).is_some());
+
+    //stores straddling pages drop both
+    c.insert(0x1000, d, false);
+    c.invalidate(0x1ffe, 4);
+    assert!(c.get(0x1000).is_none() && c.get(0x2004).is_none());
+}
+
+#[test]
+fn dropped_pages() {
+    let mut c = DecodeCache::default();
+    let d = decode(0x24020004);
+
+    c.track(true);
+    c.insert(0x1000, d, false);
+    c.invalidate(0x5000, 4);
+    assert!(!c.has_dropped());
+
+    c.invalidate(0x1004, 4);
+    assert_eq!(c.take_dropped(), (0, vec![1]));
+    c.clear();
+    assert_eq!(c.take_dropped(), (1, vec![]));
+}
@@ -0,0 +1,308 @@
+use super::Arch::OP;
+use super::Utils::Word;
+
+use crate::to_signed;
+
+/**
+ *  ABI names of the general purpose registers, indexed by register number
+ */
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0",   "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0",   "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8",   "t9", "k0", "k1", "gp", "sp", "fp", "ra"
+];
+
+/**
+ *  Returns the mnemonic of an instruction word, as decoded by Core
+ *
+ *      assert_eq!("addiu", mnemonic(0x24020004));
+ *
+ *  ARGS:
+ *
+ *  code: the instruction word
+ *
+ *  RETURNS:
+ *
+ *  the mnemonic, or "unknown" if the word does not decode to a supported instruction
+ */
+pub fn mnemonic(code: Word) -> &'static str {
+
+    match code {
+        OP::NOP     => return "nop",
+        OP::RFE     => return "rfe",
+        OP::HLT     => return "hlt",
+        OP::SYSCALL => return "syscall",
+        _ => {}
+    }
+
+    let op = (code & 0xfc000000) >> 26;
+
+    if op == 0 {
+        match code & 0x3f {
+            OP::R::ADD   => "add",
+            OP::R::ADDU  => "addu",
+            OP::R::AND   => "and",
+            OP::R::NOR   => "nor",
+            OP::R::OR    => "or",
+            OP::R::SUB   => "sub",
+            OP::R::SUBU  => "subu",
+            OP::R::XOR   => "xor",
+            OP::R::SLT   => "slt",
+            OP::R::SLTU  => "sltu",
+            OP::R::DIV   => "div",
+            OP::R::DIVU  => "divu",
+            OP::R::MULT  => "mult",
+            OP::R::MULTU => "multu",
+            OP::R::SLL   => "sll",
+            OP::R::SRA   => "sra",
+            OP::R::SRAV  => "srav",
+            OP::R::SRLV  => "srlv",
+            OP::R::JARL  => "jalr",
+            OP::R::JR    => "jr",
+            OP::R::MFHI  => "mfhi",
+            OP::R::MFLO  => "mflo",
+            OP::R::MTHI  => "mthi",
+            OP::R::MTLO  => "mtlo",
+            _ => "unknown"
+        }
+    } else {
+        match op {
+            OP::J::J     => "j",
+            OP::J::JAL   => "jal",
+            OP::I::ADDI  => "addi",
+            OP::I::ADDIU => "addiu",
+            OP::I::ANDI  => "andi",
+            OP::I::ORI   => "ori",
+            OP::I::XORI  => "xori",
+            OP::I::SLTI  => "slti",
+            OP::I::SLTIU => "sltiu",
+            OP::I::LHI   => "lhi",
+            OP::I::LLO   => "llo",
+            OP::I::BEQ   => "beq",
+            OP::I::BNE   => "bne",
+            OP::I::BGTZ  => "bgtz",
+            OP::I::BLEZ  => "blez",
+            OP::I::LB    => "lb",
+            OP::I::LBU   => "lbu",
+            OP::I::LH    => "lh",
+            OP::I::LHU   => "lhu",
+            OP::I::LW    => "lw",
+            OP::I::SB    => "sb",
+            OP::I::SH    => "sh",
+            OP::I::SW    => "sw",
+            OP::I::COP0 if code & OP::MFC0_MASK == OP::MFC0 => "mfc0",
+            OP::I::COP0 if code & OP::MFC0_MASK == OP::MTC0 => "mtc0",
+            _ => "unknown"
+        }
+    }
+}
+
+/**
+ *  Every mnemonic returns, indexed by opcode number, so tables of per-opcode counters or
+ *  latencies can be arrays
+ */
+pub const MNEMONICS: [&str; 54] = [
+    "unknown", "nop", "rfe", "hlt", "syscall",
+    "add", "addu", "and", "nor", "or", "sub", "subu", "xor", "slt", "sltu",
+    "div", "divu", "mult", "multu", "sll", "sra", "srav", "srlv",
+    "jalr", "jr", "mfhi", "mflo", "mthi", "mtlo",
+    "j", "jal", "addi", "addiu", "andi", "ori", "xori", "slti", "sltiu", "lhi", "llo",
+    "beq", "bne", "bgtz", "blez", "lb", "lbu", "lh", "lhu", "lw", "sb", "sh", "sw",
+    "mfc0", "mtc0"
+];
+
+/**
+ *  Returns the opcode number of a mnemonic, its index in MNEMONICS. Usable in constants,
+ *  where a name that is not a mnemonic fails to compile
+ *
+ *      const LW: usize = index("lw");
+ */
+pub const fn index(mnemonic: &str) -> usize {
+
+    let m = mnemonic.as_bytes();
+    let mut i = 0;
+
+    while i < MNEMONICS.len() {
+        let candidate = MNEMONICS[i].as_bytes();
+        if candidate.len() == m.len() {
+            let mut j = 0;
+            while j < m.len() && candidate[j] == m[j] { j += 1; }
+            if j == m.len() { return i; }
+        }
+        i += 1;
+    }
+
+    panic!("not a mnemonic")
+}
+
+/**
+ *  Returns the opcode number of an instruction word, see MNEMONICS
+ */
+pub fn opcode(code: Word) -> usize {
+    index(mnemonic(code))
+}
+
+/**
+ *  Returns true if code is a conditional branch
+ */
+pub fn is_branch(code: Word) -> bool {
+    matches!(mnemonic(code), "beq" | "bne" | "bgtz" | "blez")
+}
+
+/**
+ *  Returns true if code is an unconditional jump
+ */
+pub fn is_jump(code: Word) -> bool {
+    matches!(mnemonic(code), "j" | "jal" | "jr" | "jalr")
+}
+
+// numbers used for HI and LO by sources and destinations
+pub const REG_HI: u32 = 32;
+pub const REG_LO: u32 = 33;
+
+/**
+ *  Returns the registers an instruction reads, HI and LO included
+ */
+pub fn sources(code: Word) -> [Option<u32>; 2] {
+
+    let rs = Some((code >> 21) & 0x1f);
+    let rt = Some((code >> 16) & 0x1f);
+
+    match mnemonic(code) {
+        "j" | "jal" | "lhi" | "llo" | "nop" | "hlt" | "rfe" | "syscall" | "mfc0" | "unknown" => [None, None],
+        "mfhi" => [Some(REG_HI), None],
+        "mflo" => [Some(REG_LO), None],
+        "sll" | "sra" | "mtc0" => [None, rt],
+        "addi" | "addiu" | "andi" | "ori" | "xori" | "slti" | "sltiu" | "bgtz" | "blez"
+            | "lb" | "lbu" | "lh" | "lhu" | "lw" | "jr" | "jalr" | "mthi" | "mtlo" => [rs, None],
+        _ => [rs, rt]
+    }
+}
+
+/**
+ *  Returns the registers an instruction writes, HI and LO included. $zero is never returned
+ */
+pub fn destinations(code: Word) -> [Option<u32>; 2] {
+
+    let rt = (code >> 16) & 0x1f;
+    let rd = (code >> 11) & 0x1f;
+
+    let dst = match mnemonic(code) {
+        "mult" | "multu" | "div" | "divu" => return [Some(REG_HI), Some(REG_LO)],
+        "mthi" => return [Some(REG_HI), None],
+        "mtlo" => return [Some(REG_LO), None],
+        "jal" | "jalr" => 31,
+        "j" | "jr" | "beq" | "bne" | "bgtz" | "blez" | "sb" | "sh" | "sw"
+            | "nop" | "hlt" | "rfe" | "syscall" | "mtc0" | "unknown" => 0,
+        _ if (code >> 26) == 0 => rd,
+        _ => rt
+    };
+
+    [Some(dst).filter(|r| *r != 0), None]
+}
+
+/**
+ *  Disassembles an instruction word into assembly syntax
+ *
+ *      assert_eq!("addiu $v0, $zero, 4", disassemble(0x24020004));
+ *
+ *  Branch offsets are printed in instructions, relative to the next PC,
+ *  and jump targets are printed as absolute addresses
+ *
+ *  ARGS:
+ *
+ *  code: the instruction word
+ *
+ *  RETURNS:
+ *
+ *  the disassembled instruction
+ */
+pub fn disassemble(code: Word) -> String {
+
+    let name = mnemonic(code);
+
+    match code {
+        OP::NOP | OP::RFE | OP::HLT | OP::SYSCALL => return name.to_string(),
+        _ => {}
+    }
+
+    if name == "unknown" {
+        return format!(".word 0x{:08x}", code);
+    }
+
+    let op   = (code & 0xfc000000) >> 26;
+    let rs   = REG_NAMES[((code & 0x03e00000) >> 21) as usize];
+    let rt   = REG_NAMES[((code & 0x001f0000) >> 16) as usize];
+    let rd   = REG_NAMES[((code & 0x0000f800) >> 11) as usize];
+    let sham = (code & 0x000007c0) >> 6;
+    let imm  = code & 0x0000ffff;
+    let simm = if imm & 0x8000 == 0 { imm as i32 } else { -(to_signed!(imm, u16) as i32) };
+
+    if op == 0 {
+        match code & 0x3f {
+            OP::R::SLL | OP::R::SRA                   => format!("{name} ${rd}, ${rt}, {sham}"),
+            OP::R::SRAV | OP::R::SRLV                 => format!("{name} ${rd}, ${rt}, ${rs}"),
+            OP::R::DIV | OP::R::DIVU
+            | OP::R::MULT | OP::R::MULTU              => format!("{name} ${rs}, ${rt}"),
+            OP::R::JR | OP::R::MTHI | OP::R::MTLO     => format!("{name} ${rs}"),
+            OP::R::JARL                               => format!("{name} ${rd}, ${rs}"),
+            OP::R::MFHI | OP::R::MFLO                 => format!("{name} ${rd}"),
+            _                                         => format!("{name} ${rd}, ${rs}, ${rt}")
+        }
+    } else {
+        match op {
+            OP::J::J | OP::J::JAL                     => format!("{name} 0x{:08x}", (code & !0xfc000000) << 2),
+            OP::I::BEQ | OP::I::BNE | OP::I::BLEZ     => format!("{name} ${rs}, ${rt}, {simm}"),
+            OP::I::BGTZ                               => format!("{name} ${rs}, {simm}"),
+            OP::I::LHI | OP::I::LLO                   => format!("{name} ${rt}, 0x{:04x}", imm),
+            OP::I::COP0                               => format!("{name} ${rt}, ${}", (code & 0x0000f800) >> 11),
+            OP::I::LB | OP::I::LBU | OP::I::LH | OP::I::LHU | OP::I::LW
+            | OP::I::SB | OP::I::SH | OP::I::SW       => format!("{name} ${rt}, {simm}(${rs})"),
+            _                                         => format!("{name} ${rt}, ${rs}, {simm}")
+        }
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn disassembly() {
+    assert_eq!("nop", disassemble(0x00000000));
+    assert_eq!("syscall", disassemble(0x68000000));
+    assert_eq!("rfe", disassemble(0x42000001));
+    assert_eq!("addiu $v0, $zero, 4", disassemble(0x24020004));
+    assert_eq!("sll $at, $at, 16", disassemble(0x00010c00));
+    assert_eq!("lw $k1, 0($a0)", disassemble(0x8c9b0000));
+    assert_eq!("bne $t2, $v0, -3", disassemble(0x1542fffd));
+    assert_eq!("j 0x0000008c", disassemble(0x08000023));
+    assert_eq!("mfc0 $k0, $13", disassemble(0x401a6800));
+    assert_eq!("mtc0 $t0, $12", disassemble(0x40886000));
+    assert_eq!(".word 0xfc000000", disassemble(0xfc000000));
+}
+
+#[test]
+fn opcodes() {
+    assert_eq!(MNEMONICS[opcode(0x24020004)], "addiu");
+    assert_eq!(opcode(0xfc000000), index("unknown"));
+    assert!(MNEMONICS.iter().enumerate().all(|(i, m)| index(m) == i));
+}
+
+#[test]
+fn operands() {
+    assert_eq!(sources(0x20230001), [Some(1), None]);               //addi $v1, $at, 1
+    assert_eq!(destinations(0x20230001), [Some(3), None]);
+    assert_eq!(sources(0xac220004), [Some(1), Some(2)]);            //sw $v0, 4($at)
+    assert_eq!(destinations(0xac220004), [None, None]);
+    assert_eq!(destinations(0x00430018), [Some(REG_HI), Some(REG_LO)]); //mult $v0, $v1
+    assert_eq!(sources(0x00001012), [Some(REG_LO), None]);           //mflo $v0
+    assert_eq!(destinations(0x00001012), [Some(2), None]);
+    assert_eq!(destinations(0x0c000800), [Some(31), None]);         //jal
+    assert_eq!(destinations(0x24000004), [None, None]);              //addiu $zero, $zero, 4
+    assert_eq!(sources(0x401a6800), [None, None]);                  //mfc0 $k0, $13
+    assert_eq!(destinations(0x401a6800), [Some(26), None]);
+    assert_eq!(sources(0x40886000), [None, Some(8)]);              //mtc0 $t0, $12
+    assert_eq!(destinations(0x40886000), [None, None]);
+}
@@ -1,3 +1,5 @@
+use super::Disasm::REG_NAMES;
+
 #[allow(dead_code)]
 
 #[derive(Debug)]
@@ -9,6 +11,20 @@ pub enum HeaderError {
 }
 
 
+impl HeaderError {
+  /**
+   * Short name of the error variant, for reports
+   */
+  pub fn kind(&self) -> &'static str {
+    match self {
+      HeaderError::MagicError => "MagicError",
+      HeaderError::ArchError => "ArchError",
+      HeaderError::PermExecError(_) => "PermExecError",
+      HeaderError::IOError(_) => "IOError"
+    }
+  }
+}
+
 impl std::fmt::Display for HeaderError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -22,7 +38,7 @@ impl std::fmt::Display for HeaderError {
 
 impl From<std::io::Error> for HeaderError {
        fn from(e: std::io::Error) -> Self {
-         HeaderError::IOError(format!("Propagated io::Error: {}", e.to_string()))
+         HeaderError::IOError(format!("Propagated io::Error: {e}"))
        }
      }
 
@@ -45,31 +61,165 @@ impl std::fmt::Display for MemError {
 
 impl std::error::Error for MemError {}
 
+#[derive(Debug)]
+pub enum SnapshotError {
+  FormatError(String),
+  UnsupportedVersion(u32),
+  IOError(String)
+}
+
+impl std::fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      SnapshotError::FormatError(emsg) => write!(f, "{emsg}"),
+      SnapshotError::UnsupportedVersion(v) => write!(f, "Unsupported snapshot version {v}"),
+      SnapshotError::IOError(emsg) => write!(f, "{emsg}")
+    }
+  }
+}
+
+impl From<std::io::Error> for SnapshotError {
+  fn from(e: std::io::Error) -> Self {
+    SnapshotError::IOError(format!("Propagated io::Error: {e}"))
+  }
+}
+
+impl From<MemError> for SnapshotError {
+  fn from(e: MemError) -> Self {
+    SnapshotError::FormatError(format!("Could not restore device state: {e}"))
+  }
+}
+
+impl std::error::Error for SnapshotError {}
+
+
+/**
+ * Architectural state of the Core at the instruction that raised an ExecutionError
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecContext {
+  pub pc: u32,
+  pub code: u32,
+  pub regs: [u32; 32],
+  pub hi: u32,
+  pub lo: u32,
+  pub flags: u32,
+  pub epc: u32
+}
+
+/**
+ * Formats the register file, HI/LO, flags and EPC, four registers per line
+ */
+impl std::fmt::Display for ExecContext {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    for (i, name) in REG_NAMES.iter().enumerate() {
+      write!(f, "${:<4} = 0x{:08x}{}", name, self.regs[i], if i % 4 == 3 { "\n" } else { "  " })?;
+    }
+    write!(f, "$hi   = 0x{:08x}  $lo   = 0x{:08x}  flags = 0x{:08x}  epc   = 0x{:08x}", self.hi, self.lo, self.flags, self.epc)
+  }
+}
+
+/**
+ * The kind of memory access that failed
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+  Fetch,
+  Load,
+  Store
+}
 
+impl std::fmt::Display for Access {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Access::Fetch => write!(f, "instruction fetch"),
+      Access::Load => write!(f, "load"),
+      Access::Store => write!(f, "store")
+    }
+  }
+}
+
+/**
+ * Errors raised while executing guest code
+ *
+ * Every variant carries the ExecContext of the faulting instruction. It is
+ * filled in by Core once the instruction has been aborted, so variants created
+ * elsewhere start with an empty context
+ */
 #[derive(Debug)]
 pub enum ExecutionError {
-  PrivilegeError(String),
-  UnrecognizedOPError(String),
-  MemError(String)
+  PrivilegeError { iname: String, ctx: Box<ExecContext> },
+  UnrecognizedOPError { format: char, func: u32, ctx: Box<ExecContext> },
+  MemError { addr: u32, size: usize, access: Access, source: MemError, ctx: Box<ExecContext> }
 }
 
-impl From<MemError> for ExecutionError {
-  fn from(e: MemError) -> Self {
-    ExecutionError::MemError(format!("Propagated MemError: {}", e.to_string()))
+impl ExecutionError {
+
+  pub fn privilege(iname: &str) -> Self {
+    ExecutionError::PrivilegeError { iname: iname.to_string(), ctx: Box::default() }
+  }
+
+  pub fn unrecognized(format: char, func: u32) -> Self {
+    ExecutionError::UnrecognizedOPError { format, func, ctx: Box::default() }
+  }
+
+  pub fn mem(source: MemError, addr: u32, size: usize, access: Access) -> Self {
+    ExecutionError::MemError { addr, size, access, source, ctx: Box::default() }
+  }
+
+  /**
+   * Short name of the error variant, for reports
+   */
+  pub fn kind(&self) -> &'static str {
+    match self {
+      ExecutionError::PrivilegeError { .. } => "PrivilegeError",
+      ExecutionError::UnrecognizedOPError { .. } => "UnrecognizedOPError",
+      ExecutionError::MemError { .. } => "MemError"
+    }
+  }
+
+  /**
+   * State of the Core at the faulting instruction
+   */
+  pub fn context(&self) -> &ExecContext {
+    match self {
+      ExecutionError::PrivilegeError { ctx, .. }
+      | ExecutionError::UnrecognizedOPError { ctx, .. }
+      | ExecutionError::MemError { ctx, .. } => ctx
+    }
+  }
+
+  /**
+   * Replaces the context of this error
+   */
+  pub fn with_context(mut self, new_ctx: ExecContext) -> Self {
+    match &mut self {
+      ExecutionError::PrivilegeError { ctx, .. }
+      | ExecutionError::UnrecognizedOPError { ctx, .. }
+      | ExecutionError::MemError { ctx, .. } => **ctx = new_ctx
+    }
+    self
   }
 }
 
 impl std::fmt::Display for ExecutionError {
   fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     match self {
-      ExecutionError::PrivilegeError(iname) => { write!(f, "Tried to use privileged instruction {iname} but the mode bitflag was not set") },
-      ExecutionError::UnrecognizedOPError(emsg) => { write!(f, "{emsg}") },
-      ExecutionError::MemError(emsg) => { write!(f, "{emsg}") }
+      ExecutionError::PrivilegeError { iname, .. } => { write!(f, "Tried to use privileged instruction {iname} but the mode bitflag was not set") },
+      ExecutionError::UnrecognizedOPError { format, func, .. } => { write!(f, "Unrecognized {format} type func {:02x}", func) },
+      ExecutionError::MemError { addr, size, access, source, .. } => { write!(f, "Failed {access} of {size} bytes at address 0x{:08x}: {source}", addr) }
     }
   }
 }
 
-impl std::error::Error for ExecutionError {}
+impl std::error::Error for ExecutionError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ExecutionError::MemError { source, .. } => Some(source),
+      _ => None
+    }
+  }
+}
 
 #[test]
 fn error_fmt() {
@@ -81,15 +231,39 @@ fn error_fmt() {
   println!("{}", MemError::MappedDeviceError(String::from("")));
   println!("{}", MemError::PermError(1,2,3));
 
-  println!("{}",ExecutionError::MemError(String::from("")));
-  println!("{}",ExecutionError::PrivilegeError(String::from("")));
-  println!("{}",ExecutionError::UnrecognizedOPError(String::from("")));
+  println!("{}", SnapshotError::FormatError(String::from("")));
+  println!("{}", SnapshotError::UnsupportedVersion(2));
+
+  println!("{}",ExecutionError::mem(MemError::PermError(1,2,3), 3, 4, Access::Load));
+  println!("{}",ExecutionError::privilege(""));
+  println!("{}",ExecutionError::unrecognized('R', 0x3f));
+  println!("{}",ExecContext::default());
+}
+
+#[test]
+fn error_kind() {
+  assert_eq!(HeaderError::MagicError.kind(), "MagicError");
+  assert_eq!(ExecutionError::privilege("RFE").kind(), "PrivilegeError");
 }
 
 #[test]
 fn error_from() {
   #[allow(unused_variables)]
   let e: HeaderError = std::io::Error::new(std::io::ErrorKind::Other, "error!").into();
-  #[allow(unused_variables)]
-  let e2: ExecutionError = MemError::PermError(1,2,3).into();
+}
+
+#[test]
+fn error_source_and_context() {
+  use std::error::Error;
+
+  let ctx = ExecContext { pc: 0x00400004, code: 0x8c9b0000, ..Default::default() };
+  let e = ExecutionError::mem(MemError::PermError(1,2,3), 3, 4, Access::Store).with_context(ctx.clone());
+
+  assert_eq!(e.context(), &ctx);
+  assert!(e.source().unwrap().downcast_ref::<MemError>().is_some());
+
+  match e {
+    ExecutionError::MemError { addr, size, access, .. } => assert_eq!((addr, size, access), (3, 4, Access::Store)),
+    _ => panic!("expected a MemError")
+  }
 }
\ No newline at end of file
@@ -0,0 +1,284 @@
+/*!
+ *  Journal of the nondeterministic inputs consumed by a Core: interrupts and keyboard input
+ *
+ *  Every input is stored with the instruction count at which the Core consumed it. While
+ *  the cursor is behind the end of the journal, inputs are replayed from it instead of
+ *  asking the live source, so re-executing from a snapshot takes exactly the same path.
+ *  Once the cursor reaches the end, inputs come from the live source again and are
+ *  appended if recording is enabled
+ *
+ *  A journal can be saved to a log file and loaded on another machine to replay a run
+ *  exactly. The log is text:
+ *
+ *      MIPSJRNL 1
+ *      clock virtual <period>     or     clock realtime
+ *      <instruction count> irq [<lines as hex>]
+ *      <instruction count> input <bytes as hex>
+ *
+ *  The clock line records how the clock interrupt was generated. Virtual clock interrupts
+ *  are deterministic and not journaled, so the replaying Core must use the same period;
+ *  real-time ones are all in the log. An irq records the interrupt lines raised, as bits;
+ *  it is only line 0, the clock's, when they are left out
+ */
+
+use super::Errors::SnapshotError;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::io::{BufRead, Write};
+
+const LOG_HEADER: &str = "MIPSJRNL 1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // the lines raised, as bits
+    Interrupt(u32),
+    Input(Vec<u8>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub at: u64,
+    pub event: Event
+}
+
+#[derive(Debug, Default)]
+pub struct Journal {
+    entries: Vec<Entry>,
+    cursor: usize,
+    pub recording: bool,
+    // instruction count of the device access being executed, set by Core before each one
+    pub now: u64
+}
+
+pub type SharedJournal = Rc<RefCell<Journal>>;
+
+impl Journal {
+
+    /**
+     * Returns true while inputs are being replayed from the journal
+     */
+    pub fn replaying(&self) -> bool {
+        self.cursor < self.entries.len()
+    }
+
+    /**
+     * Decides whether an interrupt is delivered at instruction count at
+     *
+     * ARGS:
+     *
+     *  at: The instruction count at which the Core is checking for interrupts
+     *
+     *  live: Takes the lines raised by the live interrupt sources, as bits
+     *
+     * RETURNS:
+     *
+     *  The lines raised at this instruction, 0 if none
+     */
+    pub fn interrupt<F: FnOnce() -> u32>(&mut self, at: u64, live: F) -> u32 {
+
+        if self.replaying() {
+            if let Entry { at: when, event: Event::Interrupt(lines) } = self.entries[self.cursor] {
+                if when == at {
+                    self.cursor += 1;
+                    return lines;
+                }
+            }
+            return 0;
+        }
+
+        let raised = live();
+        if raised != 0 { self.push(at, Event::Interrupt(raised)); }
+        raised
+    }
+
+    /**
+     * Returns the next chunk of input for the instruction being executed
+     *
+     * ARGS:
+     *
+     *  live: Reads from the live input source
+     */
+    pub fn input<F: FnOnce() -> Vec<u8>>(&mut self, live: F) -> Vec<u8> {
+
+        if self.replaying() {
+            if let Entry { event: Event::Input(bytes), .. } = &self.entries[self.cursor] {
+                let bytes = bytes.clone();
+                self.cursor += 1;
+                return bytes;
+            }
+        }
+
+        let bytes = live();
+        let at = self.now;
+        self.push(at, Event::Input(bytes.clone()));
+        bytes
+    }
+
+    fn push(&mut self, at: u64, event: Event) {
+        if self.recording {
+            self.entries.push( Entry { at, event } );
+            self.cursor = self.entries.len();
+        }
+    }
+
+    /**
+     * Moves the cursor to the first input consumed at or after instruction count at,
+     * to replay from a snapshot taken at that count
+     */
+    pub fn seek(&mut self, at: u64) {
+        self.cursor = self.entries.partition_point(|e| e.at < at);
+    }
+
+    /**
+     * Writes the journal as a log file
+     *
+     * ARGS:
+     *
+     *  clock_period: The virtual clock period the run used, None if its clock
+     *  interrupts came from a thread and are in the journal
+     */
+    pub fn save<W: Write>(&self, mut w: W, clock_period: Option<u64>) -> Result<(), SnapshotError> {
+
+        writeln!(w, "{LOG_HEADER}")?;
+        match clock_period {
+            Some(period) => writeln!(w, "clock virtual {period}")?,
+            None => writeln!(w, "clock realtime")?
+        }
+
+        for Entry { at, event } in &self.entries {
+            match event {
+                Event::Interrupt(1) => writeln!(w, "{at} irq")?,
+                Event::Interrupt(lines) => writeln!(w, "{at} irq {lines:x}")?,
+                Event::Input(bytes) => {
+                    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                    writeln!(w, "{at} input {hex}")?
+                }
+            }
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    /**
+     * Reads a log file written by save
+     *
+     * RETURNS:
+     *
+     *  The journal, positioned to replay from the start, and the recorded clock period
+     */
+    pub fn load<R: BufRead>(r: R) -> Result<(Journal, Option<u64>), SnapshotError> {
+
+        let bad = |n: usize, what: &str| SnapshotError::FormatError(format!("Journal line {}: {what}", n + 1));
+
+        let mut lines = r.lines();
+
+        if lines.next().transpose()?.as_deref() != Some(LOG_HEADER) {
+            return Err(SnapshotError::FormatError(String::from("Journal header not found")))
+        }
+
+        let clock = lines.next().transpose()?.unwrap_or_default();
+        let clock_period = match clock.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["clock", "realtime"] => None,
+            ["clock", "virtual", period] => Some(period.parse::<u64>().map_err(|_| bad(1, "invalid clock period"))?),
+            _ => return Err(bad(1, "expected a clock line"))
+        };
+
+        let mut journal = Journal::default();
+
+        for (n, line) in lines.enumerate() {
+            let line = line?;
+            let n = n + 2;
+            let mut fields = line.split_whitespace();
+
+            let at = match fields.next() {
+                Some(at) => at.parse::<u64>().map_err(|_| bad(n, "invalid instruction count"))?,
+                None => continue
+            };
+
+            let event = match (fields.next(), fields.next()) {
+                (Some("irq"), None) => Event::Interrupt(1),
+                (Some("irq"), Some(lines)) => Event::Interrupt(u32::from_str_radix(lines, 16).map_err(|_| bad(n, "invalid interrupt lines"))?),
+                (Some("input"), hex) => {
+                    let hex = hex.unwrap_or("");
+                    if hex.len() % 2 != 0 { return Err(bad(n, "odd number of hex digits")) }
+                    let bytes = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16)).collect::<Result<Vec<u8>, _>>();
+                    Event::Input(bytes.map_err(|_| bad(n, "invalid hex input"))?)
+                }
+                _ => return Err(bad(n, "unknown event"))
+            };
+
+            journal.entries.push( Entry { at, event } );
+        }
+
+        Ok((journal, clock_period))
+    }
+
+    #[allow(dead_code)]
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn record_then_replay() {
+    let mut j = Journal { recording: true, ..Default::default() };
+
+    assert_eq!(j.interrupt(1, || 0), 0);
+    assert_eq!(j.interrupt(2, || 1), 1);
+    j.now = 5;
+    assert_eq!(j.input(|| b"abc".to_vec()), b"abc".to_vec());
+    assert_eq!(j.entries().len(), 2);
+    assert!(!j.replaying());
+
+    j.seek(0);
+    assert!(j.replaying());
+    //live sources are ignored while replaying
+    assert_eq!(j.interrupt(1, || 1), 0);
+    assert_eq!(j.interrupt(2, || 0), 1);
+    assert_eq!(j.input(|| b"xyz".to_vec()), b"abc".to_vec());
+
+    //back at the live edge
+    assert!(!j.replaying());
+    assert_eq!(j.input(|| b"xyz".to_vec()), b"xyz".to_vec());
+}
+
+#[test]
+fn log_roundtrip() {
+    let mut j = Journal { recording: true, ..Default::default() };
+    assert_eq!(j.interrupt(7, || 1), 1);
+    assert_eq!(j.interrupt(8, || 0b110), 0b110);
+    j.now = 9;
+    j.input(|| b"hi".to_vec());
+    j.input(Vec::new);
+
+    let mut log = Vec::new();
+    j.save(&mut log, Some(500)).unwrap();
+    assert_eq!(String::from_utf8(log.clone()).unwrap(), "MIPSJRNL 1\nclock virtual 500\n7 irq\n8 irq 6\n9 input 6869\n9 input \n");
+
+    let (mut j2, period) = Journal::load(log.as_slice()).unwrap();
+    assert_eq!(period, Some(500));
+    assert_eq!(j2.entries(), j.entries());
+    assert!(j2.replaying());
+    assert_eq!(j2.interrupt(7, || 0), 1);
+    assert_eq!(j2.interrupt(8, || 0), 0b110);
+
+    assert!(Journal::load(&b"MIPSJRNL 1\nclock realtime\n3 bogus\n"[..]).is_err());
+    assert!(Journal::load(&b"nope\n"[..]).is_err());
+    assert_eq!(Journal::load(&b"MIPSJRNL 1\nclock realtime\n"[..]).unwrap().1, None);
+}
+
+#[test]
+fn not_recording() {
+    let mut j = Journal::default();
+
+    assert_eq!(j.interrupt(1, || 1), 1);
+    assert!(j.entries().is_empty());
+    assert!(!j.replaying());
+}
@@ -0,0 +1,199 @@
+/*!
+ *  Levelled logging of the emulator's internals, per subsystem
+ *
+ *  Every message belongs to a subsystem, CORE, MEM or the name of a device or
+ *  interruptor, and has a level. A Filter sets the most detailed level shown for each
+ *  subsystem, from a spec like "info,core=trace,clock=off": entries without a subsystem
+ *  set the level of everything else. Subsystem names are not case sensitive.
+ *
+ *      error   something went wrong in the emulator
+ *      warn    the guest did something odd, like returning to the wrong caller
+ *      info    setup, loading and the end of the run
+ *      debug   flags, privilege changes, syscalls and interrupts
+ *      trace   every instruction, memory access and interruptor wakeup
+ *
+ *  Messages go to stderr, so they do not mix with the guest's console output.
+ *  Everything is off until set_filter is called.
+ *
+ *  The log! and log_enabled! macros check the "logging" cargo feature, so building
+ *  without it leaves no logging branches in the core loop or memory accesses
+ */
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+
+    pub fn parse(name: &str) -> Result<Level, String> {
+        match name.trim().to_lowercase().as_str() {
+            "off"   => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn"  => Ok(Level::Warn),
+            "info"  => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other   => Err(format!("Unknown log level '{other}'; expected off, error, warn, info, debug or trace"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    // for subsystems not listed
+    pub default: Level,
+    pub subsystems: Vec<(String, Level)>
+}
+
+impl Filter {
+
+    /**
+     * Everything at level and below
+     */
+    pub fn all(level: Level) -> Filter {
+        Filter { default: level, subsystems: Vec::new() }
+    }
+
+    /**
+     * Reads a filter from a comma separated list of level or subsystem=level entries
+     */
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+
+        let mut filter = Filter::all(Level::Off);
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once('=') {
+                None => filter.default = Level::parse(entry)?,
+                Some((sub, level)) => {
+                    let sub = sub.trim();
+                    if sub.is_empty() { return Err(format!("Missing subsystem in log filter entry '{entry}'")); }
+                    filter.subsystems.push((sub.to_uppercase(), Level::parse(level)?));
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /**
+     * The most detailed level shown for subsystem; later entries win
+     */
+    pub fn level(&self, subsystem: &str) -> Level {
+        self.subsystems.iter().rev()
+            .find(|(sub, _)| sub.eq_ignore_ascii_case(subsystem))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> Level {
+        self.subsystems.iter().map(|(_, level)| *level).fold(self.default, Level::max)
+    }
+}
+
+static FILTER: RwLock<Option<Filter>> = RwLock::new(None);
+// the most detailed level of FILTER, so most messages are turned down without locking
+static MAX: AtomicU8 = AtomicU8::new(Level::Off as u8);
+
+thread_local! {
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/**
+ * Sets which messages are shown, for every thread
+ */
+pub fn set_filter(filter: Filter) {
+    let mut current = FILTER.write().unwrap_or_else(|e| e.into_inner());
+    MAX.store(filter.max() as u8, Ordering::Relaxed);
+    *current = Some(filter);
+}
+
+/**
+ * True if a message of subsystem at level would be shown
+ */
+#[inline(always)]
+pub fn enabled(level: Level, subsystem: &str) -> bool {
+    level as u8 <= MAX.load(Ordering::Relaxed) && filtered(level, subsystem)
+}
+
+#[inline(never)]
+fn filtered(level: Level, subsystem: &str) -> bool {
+    if QUIET.with(Cell::get) { return false; }
+    let filter = FILTER.read().unwrap_or_else(|e| e.into_inner());
+    filter.as_ref().is_some_and(|f| level <= f.level(subsystem))
+}
+
+/**
+ * Runs f with logging turned off on this thread, for work that is not part of the run
+ */
+pub fn quiet<T>(f: impl FnOnce() -> T) -> T {
+    let was = QUIET.with(|q| q.replace(true));
+    let res = f();
+    QUIET.with(|q| q.set(was));
+    res
+}
+
+/**
+ * Writes a message; use log! instead, which checks the filter first
+ */
+pub fn write(level: Level, subsystem: &str, args: fmt::Arguments) {
+    match level {
+        Level::Error | Level::Warn => eprintln!("[{}] {}: {args}", subsystem.to_uppercase(), if level == Level::Error { "error" } else { "warning" }),
+        _ => eprintln!("[{}]: {args}", subsystem.to_uppercase())
+    }
+}
+
+/**
+ * Logs a message for a subsystem at a level, a Level variant name:
+ *
+ *      log!(Debug, "MEM", "Changed privilege mode to {}", m);
+ */
+#[macro_export]
+macro_rules! log {
+    ($level: ident, $subsystem: expr, $($arg: tt)+) => {
+        if $crate::log_enabled!($level, $subsystem) {
+            $crate::libs::Definitions::Log::write($crate::libs::Definitions::Log::Level::$level, $subsystem, format_args!($($arg)+));
+        }
+    };
+}
+
+/**
+ * True if a log! of subsystem at level would be shown, constant false without the
+ * logging feature
+ */
+#[macro_export]
+macro_rules! log_enabled {
+    ($level: ident, $subsystem: expr) => {
+        cfg!(feature = "logging") && $crate::libs::Definitions::Log::enabled($crate::libs::Definitions::Log::Level::$level, $subsystem)
+    };
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn filter_spec() {
+    let f = Filter::parse("info, core=trace,Clock=off").unwrap();
+    assert_eq!(f.level("CORE"), Level::Trace);
+    assert_eq!(f.level("clock"), Level::Off);
+    assert_eq!(f.level("MEM"), Level::Info);
+    assert_eq!(f.max(), Level::Trace);
+
+    //later entries win, and an empty spec turns everything off
+    assert_eq!(Filter::parse("mem=debug,mem=warn").unwrap().level("mem"), Level::Warn);
+    assert_eq!(Filter::parse("").unwrap(), Filter::all(Level::Off));
+
+    assert!(Filter::parse("core=loud").is_err());
+    assert!(Filter::parse("=info").is_err());
+}
+
@@ -0,0 +1,318 @@
+/*!
+ *  Machine snapshot file format, used for snapshots and core dumps
+ *
+ *  All integers are big endian, like RELF executables. Strings are a u32 length
+ *  followed by UTF-8 bytes.
+ *
+ *      magic:   8 bytes, "MIPSSNAP"
+ *      version: u32
+ *      sections until end of file, each one:
+ *          tag:     4 ASCII bytes
+ *          len:     u32, length of the payload in bytes
+ *          payload: len bytes
+ *
+ *  Sections:
+ *
+ *      "CPU ": PC, the 32 GPRs, HI, LO, flags with the KU/IE stack, EPC and irqH address,
+ *              IntEnableOnNext, the post-RFE interrupt enable flag and the exception code of
+ *              Cause, as u32
+ *      "PROT": protected address ranges, each as lower and upper address u32
+ *      "STAT": instruction count and cycle count, as u64 written high u32 first
+ *      "TIME": for each virtual timer its name as a string and the cycle count it fires
+ *              next at as u64. Timers the reading Core does not have are ignored. Pending
+ *              interrupts are part of the interrupt controller's device state
+ *      "PIPE": timing model state: the cycle HI and LO are ready at as u64, and the
+ *              register loaded by the previous instruction as u32, 0 if none
+ *      "PAGE": page address as u32 followed by the contents of the page. There is one
+ *              section per PAGE_SIZE page of memory holding a non-zero byte; pages
+ *              that are not present read as zero
+ *      "DEV ": lower and upper address of a mapped device as u32, followed by its
+ *              state as produced by MemoryMapped::save_state. One section per device
+ *      "SYM ": address as u32 followed by the UTF-8 name of a symbol. One section per symbol
+ *      "STK ": shadow call stack frames, outermost first, each as kind (0 call, 1 exception),
+ *              call site, target and return address, then 1 and the $ra it was returned
+ *              through if that was not its return address, or 0 and 0, as u32
+ *      "FALT": only present in core dumps; the fault as a string kind, a string message,
+ *              the faulting PC and the instruction word
+ *
+ *  Readers skip sections with unknown tags, so new sections can be added without
+ *  changing the version
+ */
+
+use super::Errors::SnapshotError;
+
+use std::io::{Read, Write};
+
+pub const MAGIC: &[u8; 8] = b"MIPSSNAP";
+// readers reject every other version
+pub const VERSION: u32 = 1;
+pub const PAGE_SIZE: usize = 4096;
+
+pub const TAG_CPU:   [u8; 4] = *b"CPU ";
+pub const TAG_PAGE:  [u8; 4] = *b"PAGE";
+pub const TAG_DEV:   [u8; 4] = *b"DEV ";
+pub const TAG_SYM:   [u8; 4] = *b"SYM ";
+pub const TAG_STK:   [u8; 4] = *b"STK ";
+pub const TAG_FAULT: [u8; 4] = *b"FALT";
+pub const TAG_PROT:  [u8; 4] = *b"PROT";
+pub const TAG_STAT:  [u8; 4] = *b"STAT";
+pub const TAG_TIME:  [u8; 4] = *b"TIME";
+pub const TAG_PIPE:  [u8; 4] = *b"PIPE";
+
+/**
+ *  The fault recorded in a core dump
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRecord {
+    pub kind: String,
+    pub message: String,
+    pub pc: u32,
+    pub code: u32
+}
+
+/**
+ *  Writes the header, then one section per call to section
+ */
+pub struct SnapshotWriter<W: Write> {
+    w: W
+}
+
+impl<W: Write> SnapshotWriter<W> {
+
+    pub fn new(mut w: W) -> Result<SnapshotWriter<W>, SnapshotError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_be_bytes())?;
+        Ok(SnapshotWriter { w })
+    }
+
+    pub fn section(&mut self, tag: [u8; 4], payload: &[u8]) -> Result<(), SnapshotError> {
+        self.w.write_all(&tag)?;
+        self.w.write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.w.write_all(payload)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), SnapshotError> {
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+// a section's tag and payload
+pub type Section = ([u8; 4], Vec<u8>);
+
+/**
+ *  Checks the header, then returns sections one by one
+ */
+pub struct SnapshotReader<R: Read> {
+    r: R
+}
+
+impl<R: Read> SnapshotReader<R> {
+
+    pub fn new(mut r: R) -> Result<SnapshotReader<R>, SnapshotError> {
+
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(SnapshotError::FormatError(String::from("Snapshot magic number not found"))) }
+
+        let mut version = [0u8; 4];
+        r.read_exact(&mut version)?;
+        let version = u32::from_be_bytes(version);
+        if version != VERSION { return Err(SnapshotError::UnsupportedVersion(version)) }
+
+        Ok(SnapshotReader { r })
+    }
+
+    /**
+     * RETURNS:
+     *
+     *  The next section's tag and payload, or None at end of file. A file ending
+     *  inside a section is a FormatError
+     */
+    pub fn next_section(&mut self) -> Result<Option<Section>, SnapshotError> {
+
+        let mut tag = [0u8; 4];
+        match self.fill(&mut tag)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(Self::truncated())
+        }
+
+        let mut len = [0u8; 4];
+        if self.fill(&mut len)? != 4 { return Err(Self::truncated()) }
+        let len = u32::from_be_bytes(len) as usize;
+
+        //grow with what is actually read, the length may be bogus
+        let mut payload = Vec::new();
+        (&mut self.r).take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() != len { return Err(Self::truncated()) }
+
+        Ok(Some((tag, payload)))
+    }
+
+    /**
+     * Reads until buf is full or the file ends, returning the bytes read
+     */
+    fn fill(&mut self, buf: &mut [u8]) -> Result<usize, SnapshotError> {
+
+        let mut read = 0;
+        while read < buf.len() {
+            match self.r.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+        Ok(read)
+    }
+
+    fn truncated() -> SnapshotError {
+        SnapshotError::FormatError(String::from("Snapshot ends in the middle of a section"))
+    }
+}
+
+/**
+ *  Appends big endian fields to a section payload
+ */
+#[derive(Default)]
+pub struct Payload {
+    pub bytes: Vec<u8>
+}
+
+impl Payload {
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn raw(&mut self, v: &[u8]) -> &mut Self {
+        self.bytes.extend_from_slice(v);
+        self
+    }
+
+    pub fn str(&mut self, v: &str) -> &mut Self {
+        self.u32(v.len() as u32).raw(v.as_bytes())
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.u32((v >> 32) as u32).u32(v as u32)
+    }
+}
+
+/**
+ *  Reads big endian fields from a section payload, failing on truncated sections
+ */
+pub struct Fields<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Fields<'a> {
+
+    pub fn new(buf: &'a [u8]) -> Fields<'a> {
+        Fields { buf, pos: 0 }
+    }
+
+    pub fn raw(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+
+        if self.pos + n > self.buf.len() {
+            return Err(SnapshotError::FormatError(String::from("Truncated snapshot section")))
+        }
+
+        let out = &self.buf[self.pos..self.pos+n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(super::Utils::from_word(self.raw(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+
+    pub fn str(&mut self) -> Result<String, SnapshotError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.raw(len)?.to_vec()).map_err(|_| SnapshotError::FormatError(String::from("Invalid UTF-8 string in snapshot")))
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let out = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        out
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn roundtrip() {
+    let mut out = Vec::new();
+
+    let mut w = SnapshotWriter::new(&mut out).unwrap();
+    w.section(TAG_CPU, &Payload::default().u32(0xdeadbeef).str("abc").u64(1 << 40 | 5).bytes).unwrap();
+    w.section(*b"NEW!", &[1, 2, 3]).unwrap();
+    w.finish().unwrap();
+
+    let mut r = SnapshotReader::new(out.as_slice()).unwrap();
+
+    let (tag, payload) = r.next_section().unwrap().unwrap();
+    assert_eq!(tag, TAG_CPU);
+    let mut f = Fields::new(&payload);
+    assert_eq!(f.u32().unwrap(), 0xdeadbeef);
+    assert_eq!(f.str().unwrap(), "abc");
+    assert_eq!(f.u64().unwrap(), 1 << 40 | 5);
+    assert!(f.is_empty());
+    assert!(f.u32().is_err());
+
+    assert_eq!(r.next_section().unwrap().unwrap().1, vec![1, 2, 3]);
+    assert!(r.next_section().unwrap().is_none());
+}
+
+#[test]
+fn bad_header() {
+    assert!(SnapshotReader::new(&b"NOTASNAP\x00\x00\x00\x01"[..]).is_err());
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&99u32.to_be_bytes());
+    match SnapshotReader::new(out.as_slice()) {
+        Err(SnapshotError::UnsupportedVersion(99)) => {}
+        _ => panic!("expected an UnsupportedVersion")
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&(VERSION - 1).to_be_bytes());
+    assert!(SnapshotReader::new(out.as_slice()).is_err());
+}
+
+
+#[test]
+fn truncated() {
+    let mut out = Vec::new();
+    let mut w = SnapshotWriter::new(&mut out).unwrap();
+    w.section(TAG_CPU, &[1, 2, 3, 4]).unwrap();
+    w.finish().unwrap();
+
+    //cut in the tag, the length and the payload
+    for end in [out.len() - 10, out.len() - 6, out.len() - 1] {
+        let mut r = SnapshotReader::new(&out[..end]).unwrap();
+        assert!(matches!(r.next_section(), Err(SnapshotError::FormatError(_))), "cut at {end}");
+    }
+
+    //a huge length is not allocated up front
+    let mut out = out[..12].to_vec();
+    out.extend_from_slice(&TAG_PAGE);
+    out.extend_from_slice(&u32::MAX.to_be_bytes());
+    out.extend_from_slice(&[0; 16]);
+    assert!(matches!(SnapshotReader::new(out.as_slice()).unwrap().next_section(), Err(SnapshotError::FormatError(_))));
+}
\ No newline at end of file
@@ -1,5 +1,27 @@
 use std::time::Instant;
 use std::time::Duration;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::super::Cache::CacheAccess;
+use super::Disasm::MNEMONICS;
+
+// access widths counted by load and store, in bytes
+const WIDTHS: [(usize, &str); 3] = [(1, "byte"), (2, "half"), (4, "word")];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64
+}
+
+impl CacheStats {
+    pub fn miss_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 { 0.0 } else { self.misses as f64 / (self.hits + self.misses) as f64 }
+    }
+}
 
 #[derive(Debug)]
 pub struct Stats {
@@ -8,10 +30,32 @@ pub struct Stats {
     pub st_time: Instant,
     pub exec_total_time: Duration,
 
+    // executed instructions by opcode number, see Disasm::MNEMONICS; only counted if the Core is asked to
+    pub opcodes: [u64; MNEMONICS.len()],
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    // indexed like WIDTHS
+    pub loads: [u64; 3],
+    pub stores: [u64; 3],
+    pub syscalls: u64,
+    pub interrupts: u64,
+    // spent with MODE_FLAG set
+    pub priv_instr_count: u64,
+    pub priv_cycl_count: u64,
+    // by cache name, see Cache
+    pub caches: BTreeMap<&'static str, CacheStats>
 }
 
 pub fn new() -> Stats {
-    Stats {instr_count: 0, cycl_count: 0, st_time: Instant::now(), exec_total_time: Duration::new(0,0)}
+    Stats {
+        instr_count: 0, cycl_count: 0, st_time: Instant::now(), exec_total_time: Duration::new(0,0),
+        opcodes: [0; MNEMONICS.len()], branches_taken: 0, branches_not_taken: 0, loads: [0; 3], stores: [0; 3],
+        syscalls: 0, interrupts: 0, priv_instr_count: 0, priv_cycl_count: 0, caches: BTreeMap::new()
+    }
+}
+
+fn width_index(size: usize) -> usize {
+    WIDTHS.iter().position(|(w, _)| *w == size).unwrap_or(2)
 }
 
 impl Stats {
@@ -20,8 +64,8 @@ impl Stats {
         self.cycl_count as f32 / self.instr_count as f32
     }
 
-    pub fn cycle_incr(&mut self) {
-        self.cycl_count += 1;
+    pub fn cycle_add(&mut self, cycles: u64) {
+        self.cycl_count += cycles as usize;
     }
 
     pub fn instr_incr(&mut self) {
@@ -42,4 +86,135 @@ impl Stats {
         self.exec_total_time().as_secs_f32() / self.instr_count as f32
     }
 
-}
\ No newline at end of file
+    #[inline(always)]
+    pub fn opcode(&mut self, opcode: usize) {
+        self.opcodes[opcode] += 1;
+    }
+
+    pub fn branch(&mut self, taken: bool) {
+        if taken { self.branches_taken += 1 } else { self.branches_not_taken += 1 }
+    }
+
+    pub fn load(&mut self, size: usize) {
+        self.loads[width_index(size)] += 1;
+    }
+
+    pub fn store(&mut self, size: usize) {
+        self.stores[width_index(size)] += 1;
+    }
+
+    pub fn syscall(&mut self) {
+        self.syscalls += 1;
+    }
+
+    pub fn interrupt(&mut self) {
+        self.interrupts += 1;
+    }
+
+    pub fn privileged(&mut self, cycles: u64) {
+        self.priv_instr_count += 1;
+        self.priv_cycl_count += cycles;
+    }
+
+    pub fn cache(&mut self, name: &'static str, access: &CacheAccess) {
+        let c = self.caches.entry(name).or_default();
+        if access.hit { c.hits += 1 } else { c.misses += 1 }
+        c.evictions += access.evicted as u64;
+        c.writebacks += access.writeback.is_some() as u64;
+    }
+
+    /**
+     * Opcodes from most to least executed
+     */
+    pub fn histogram(&self) -> Vec<(&'static str, u64)> {
+        let mut h: Vec<(&'static str, u64)> = MNEMONICS.iter().zip(self.opcodes).filter(|(_, n)| *n > 0).map(|(m, n)| (*m, n)).collect();
+        h.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        h
+    }
+
+    /**
+     * The statistics as a JSON object, for --stats-json
+     */
+    pub fn to_json(&self) -> String {
+
+        let widths = |counts: &[u64; 3]| WIDTHS.iter().zip(counts).map(|((_, name), n)| format!("\"{name}\": {n}")).collect::<Vec<_>>().join(", ");
+
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"instructions\": {},", self.instr_count);
+        let _ = writeln!(out, "  \"cycles\": {},", self.cycl_count);
+        let _ = writeln!(out, "  \"cpi\": {},", if self.instr_count == 0 { 0.0 } else { self.CPI() });
+        let _ = writeln!(out, "  \"time_s\": {},", self.exec_total_time.as_secs_f64());
+        let _ = writeln!(out, "  \"privileged\": {{ \"instructions\": {}, \"cycles\": {} }},", self.priv_instr_count, self.priv_cycl_count);
+        let _ = writeln!(out, "  \"branches\": {{ \"taken\": {}, \"not_taken\": {} }},", self.branches_taken, self.branches_not_taken);
+        let _ = writeln!(out, "  \"loads\": {{ {} }},", widths(&self.loads));
+        let _ = writeln!(out, "  \"stores\": {{ {} }},", widths(&self.stores));
+        let _ = writeln!(out, "  \"syscalls\": {},", self.syscalls);
+        let _ = writeln!(out, "  \"interrupts\": {},", self.interrupts);
+        let caches: Vec<String> = self.caches.iter().map(|(name, c)| format!("\"{name}\": {{ \"hits\": {}, \"misses\": {}, \"evictions\": {}, \"writebacks\": {} }}",
+            c.hits, c.misses, c.evictions, c.writebacks)).collect();
+        let _ = writeln!(out, "  \"caches\": {{ {} }},", caches.join(", "));
+        let opcodes: Vec<String> = self.histogram().iter().map(|(m, n)| format!("\"{m}\": {n}")).collect();
+        let _ = writeln!(out, "  \"opcodes\": {{ {} }}", opcodes.join(", "));
+        out.push('}');
+        out
+    }
+
+}
+
+/**
+ * The report printed by --stats
+ */
+impl std::fmt::Display for Stats {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+
+    let pct = |n: u64, of: usize| if of == 0 { 0.0 } else { n as f64 * 100.0 / of as f64 };
+    let widths = |counts: &[u64; 3]| WIDTHS.iter().zip(counts).map(|((_, name), n)| format!("{name} {n}")).collect::<Vec<_>>().join(", ");
+
+    writeln!(f, "Executed {} instructions in {} cycles (CPI {:.2}) in {:.6} s", self.instr_count, self.cycl_count,
+        if self.instr_count == 0 { 0.0 } else { self.CPI() }, self.exec_total_time.as_secs_f64())?;
+    writeln!(f, "Privileged:  {} instructions, {} cycles ({:.1}% of cycles)", self.priv_instr_count, self.priv_cycl_count, pct(self.priv_cycl_count, self.cycl_count))?;
+    writeln!(f, "Branches:    {} taken, {} not taken", self.branches_taken, self.branches_not_taken)?;
+    writeln!(f, "Loads:       {}", widths(&self.loads))?;
+    writeln!(f, "Stores:      {}", widths(&self.stores))?;
+    writeln!(f, "Syscalls:    {}, interrupts: {}", self.syscalls, self.interrupts)?;
+    for (name, c) in &self.caches {
+        writeln!(f, "Cache {name:<5} {} hits, {} misses ({:.1}% miss rate), {} evictions, {} write-backs", c.hits, c.misses, c.miss_rate() * 100.0, c.evictions, c.writebacks)?;
+    }
+    write!(f, "Opcodes:")?;
+    for (m, n) in self.histogram() {
+        write!(f, "\n  {m:<8} {n:>10}  {:5.1}%", pct(n, self.instr_count))?;
+    }
+    Ok(())
+  }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn report() {
+    let mut s = new();
+    s.instr_count = 4;
+    s.cycl_count = 4;
+    for m in ["addiu", "lw", "addiu", "beq"] { s.opcode(super::Disasm::index(m)); }
+    s.branch(true);
+    s.load(4);
+    s.store(1);
+    s.privileged(1);
+    s.cache("L1D", &CacheAccess { hit: true, ..Default::default() });
+    s.cache("L1D", &CacheAccess { filled: true, evicted: true, writeback: Some(0x40), ..Default::default() });
+
+    assert_eq!(s.histogram(), vec![("addiu", 2), ("beq", 1), ("lw", 1)]);
+
+    let text = s.to_string();
+    assert!(text.contains("Privileged:  1 instructions, 1 cycles (25.0% of cycles)"));
+    assert!(text.contains("Stores:      byte 1, half 0, word 0"));
+    assert!(text.contains("\n  addiu             2   50.0%"));
+    assert!(text.contains("Cache L1D   1 hits, 1 misses (50.0% miss rate), 1 evictions, 1 write-backs"));
+
+    let json = s.to_json();
+    assert!(json.contains("\"branches\": { \"taken\": 1, \"not_taken\": 0 },"));
+    assert!(json.contains("\"opcodes\": { \"addiu\": 2, \"beq\": 1, \"lw\": 1 }"));
+    assert!(json.contains("\"caches\": { \"L1D\": { \"hits\": 1, \"misses\": 1, \"evictions\": 1, \"writebacks\": 1 } },"));
+}
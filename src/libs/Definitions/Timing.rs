@@ -0,0 +1,263 @@
+/*!
+ *  Cycle timing model used by Core to count cycles
+ *
+ *  Every instruction costs its opcode's latency, 1 unless overridden, plus:
+ *
+ *      hilo:   mult, multu, div, divu, mfhi, mflo, mthi and mtlo stall until a previous
+ *              mult or div has finished, mult and div cycles after it issued
+ *      load:   extra cycles when an instruction reads the register loaded by the
+ *              previous one
+ *      branch: extra cycles for a taken branch or a jump
+ *      mispredict: extra cycles for a mispredicted branch or jump, replacing the branch
+ *              penalty when a branch predictor feeds the model
+ *      mem:    extra cycles per load or store to memory
+ *      device: extra cycles per load or store to a mapped device
+ *
+ *  Cycles spent in the cache hierarchy, if there is one, are added on top
+ *  The defaults follow the R3000: 12 cycle multiplies, 35 cycle divides and single
+ *  cycle load and branch penalties. Models can be read from a file of "key = value"
+ *  lines, where key is one of mult, div, load, branch, mispredict, mem or device, or op.<mnemonic>
+ *  for an opcode latency, at least 1. Lines starting with # are comments
+ *
+ *  Latencies are kept by opcode number, and instructions are timed from their predecoded
+ *  fields, so costing one is a few table lookups
+ */
+
+use super::Decode::Decoded;
+use super::Disasm::{self, MNEMONICS};
+
+const MULT: usize  = Disasm::index("mult");
+const MULTU: usize = Disasm::index("multu");
+const DIV: usize   = Disasm::index("div");
+const DIVU: usize  = Disasm::index("divu");
+const MFHI: usize  = Disasm::index("mfhi");
+const MFLO: usize  = Disasm::index("mflo");
+const MTHI: usize  = Disasm::index("mthi");
+const MTLO: usize  = Disasm::index("mtlo");
+const LB: usize    = Disasm::index("lb");
+const LBU: usize   = Disasm::index("lbu");
+const LH: usize    = Disasm::index("lh");
+const LHU: usize   = Disasm::index("lhu");
+const LW: usize    = Disasm::index("lw");
+
+// what cost looks at an instruction for, besides its latency
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class { Plain, Mult, Div, HiLo, Load }
+
+const CLASSES: [Class; MNEMONICS.len()] = {
+    let mut classes = [Class::Plain; MNEMONICS.len()];
+    classes[MULT] = Class::Mult;
+    classes[MULTU] = Class::Mult;
+    classes[DIV] = Class::Div;
+    classes[DIVU] = Class::Div;
+    classes[MFHI] = Class::HiLo;
+    classes[MFLO] = Class::HiLo;
+    classes[MTHI] = Class::HiLo;
+    classes[MTLO] = Class::HiLo;
+    classes[LB] = Class::Load;
+    classes[LBU] = Class::Load;
+    classes[LH] = Class::Load;
+    classes[LHU] = Class::Load;
+    classes[LW] = Class::Load;
+    classes
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingModel {
+    pub mult: u64,
+    pub div: u64,
+    pub load: u64,
+    pub branch: u64,
+    pub mispredict: u64,
+    pub mem: u64,
+    pub device: u64,
+    // opcode latencies, by opcode number, see Disasm::MNEMONICS
+    pub latencies: [u64; MNEMONICS.len()]
+}
+
+impl Default for TimingModel {
+    fn default() -> TimingModel {
+        TimingModel { mult: 12, div: 35, load: 1, branch: 1, mispredict: 2, mem: 0, device: 0, latencies: [1; MNEMONICS.len()] }
+    }
+}
+
+/**
+ * What an instruction did, besides what it decodes to, as needed to time it
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retired {
+    // branch taken or jump
+    pub taken: bool,
+    // whether the branch predictor got it wrong, if one feeds the model
+    pub mispredicted: Option<bool>,
+    pub mem_accesses: u64,
+    pub device_accesses: u64,
+    // spent in the cache hierarchy by the fetch, loads and stores
+    pub cache_cycles: u64
+}
+
+/**
+ * Pipeline state carried between instructions
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimingState {
+    // cycle at which HI and LO are ready
+    pub hilo_ready: u64,
+    // register written by the previous instruction if it was a load
+    pub loaded: Option<u32>
+}
+
+impl TimingModel {
+
+    /**
+     * Every instruction takes one cycle
+     */
+    pub fn flat() -> TimingModel {
+        TimingModel { mult: 0, div: 0, load: 0, branch: 0, mispredict: 0, mem: 0, device: 0, latencies: [1; MNEMONICS.len()] }
+    }
+
+    /**
+     * Reads a model from "key = value" lines, starting from the defaults
+     */
+    pub fn parse(text: &str) -> Result<TimingModel, String> {
+
+        let mut model = TimingModel::default();
+
+        for (n, line) in text.lines().enumerate() {
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("Timing line {}: expected 'key = value'", n + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+            let value = value.parse::<u64>().map_err(|_| format!("Timing line {}: invalid cycle count '{value}'", n + 1))?;
+
+            match key {
+                "mult"   => model.mult = value,
+                "div"    => model.div = value,
+                "load"   => model.load = value,
+                "branch" => model.branch = value,
+                "mispredict" => model.mispredict = value,
+                "mem"    => model.mem = value,
+                "device" => model.device = value,
+                _ => match key.strip_prefix("op.") {
+                    Some(op) => match MNEMONICS.iter().position(|m| *m == op) {
+                        //cost counts the cycle an instruction issues in
+                        Some(_) if value == 0 => return Err(format!("Timing line {}: '{op}' must take at least one cycle", n + 1)),
+                        Some(opcode) => model.latencies[opcode] = value,
+                        None => return Err(format!("Timing line {}: unknown opcode '{op}'", n + 1))
+                    }
+                    None => return Err(format!("Timing line {}: unknown key '{key}'", n + 1))
+                }
+            }
+        }
+
+        Ok(model)
+    }
+
+    /**
+     * True if every instruction takes one cycle, so Core can skip costing them
+     */
+    pub fn is_flat(&self) -> bool {
+        *self == TimingModel::flat()
+    }
+
+    /**
+     * Cycles taken by an instruction, updating the pipeline state
+     *
+     * ARGS:
+     *
+     *  now: The cycle count when the instruction issues
+     *
+     *  d: The instruction
+     */
+    #[inline(always)]
+    pub fn cost(&self, state: &mut TimingState, now: u64, d: &Decoded, instr: &Retired) -> u64 {
+
+        let mut cycles = self.latencies[d.opcode];
+        let class = CLASSES[d.opcode];
+
+        if let Some(r) = state.loaded.take() {
+            if d.sources.contains(&Some(r)) { cycles += self.load; }
+        }
+
+        let hilo = match class {
+            Class::Mult => Some(self.mult),
+            Class::Div => Some(self.div),
+            Class::HiLo => Some(0),
+            _ => None
+        };
+
+        if let Some(latency) = hilo {
+            let stall = state.hilo_ready.saturating_sub(now + cycles - 1);
+            cycles += stall;
+            if latency > 0 { state.hilo_ready = now + cycles + latency; }
+        }
+
+        match instr.mispredicted {
+            Some(wrong) => if wrong { cycles += self.mispredict },
+            None => if instr.taken { cycles += self.branch }
+        }
+        cycles += instr.mem_accesses * self.mem + instr.device_accesses * self.device + instr.cache_cycles;
+
+        if class == Class::Load {
+            state.loaded = Some(d.rt as u32).filter(|r| *r != 0);
+        }
+
+        cycles
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn costs() {
+    use super::Decode::decode;
+
+    let model = TimingModel::default();
+    let mut st = TimingState::default();
+    let none = Retired::default();
+
+    let addiu = decode(0x24020004);     //addiu $v0, $zero, 4
+    assert_eq!(model.cost(&mut st, 0, &addiu, &none), 1);
+
+    //mult issues at 1, HI/LO ready at 14; an mflo at 2 waits 12 cycles
+    let mult = decode(0x00430018);      //mult $v0, $v1
+    assert_eq!(model.cost(&mut st, 1, &mult, &none), 1);
+    let mflo = decode(0x00001012);      //mflo $v0
+    assert_eq!(model.cost(&mut st, 2, &mflo, &none), 13);
+    assert_eq!(model.cost(&mut st, 15, &mflo, &none), 1);
+
+    //load-use stall
+    let lw = decode(0x8c820000);        //lw $v0, 0($a0)
+    assert_eq!(model.cost(&mut st, 16, &lw, &Retired { mem_accesses: 1, ..none }), 1);
+    let use_v0 = decode(0x24420001);    //addiu $v0, $v0, 1
+    assert_eq!(model.cost(&mut st, 17, &use_v0, &none), 2);
+    assert_eq!(model.cost(&mut st, 19, &use_v0, &none), 1);
+
+    let beq = decode(0x1000ffff);       //beq $zero, $zero, -1
+    let taken = Retired { taken: true, ..none };
+    assert_eq!(model.cost(&mut st, 20, &beq, &taken), 2);
+    //with a predictor, only mispredictions cost extra
+    assert_eq!(model.cost(&mut st, 22, &beq, &Retired { mispredicted: Some(false), ..taken }), 1);
+    assert_eq!(model.cost(&mut st, 23, &beq, &Retired { mispredicted: Some(true), ..taken }), 3);
+
+    assert_eq!(TimingModel::flat().cost(&mut TimingState::default(), 0, &mult, &none), 1);
+    assert!(TimingModel::flat().is_flat() && !TimingModel { mem: 1, ..TimingModel::flat() }.is_flat());
+}
+
+#[test]
+fn parse_model() {
+    let model = TimingModel::parse("# slow memory\nmem = 3\n  op.addiu=2\ndevice = 10\n").unwrap();
+    assert_eq!((model.mem, model.device, model.mult), (3, 10, 12));
+    assert_eq!(model.latencies[Disasm::index("addiu")], 2);
+    assert_eq!(model.latencies[Disasm::index("addu")], 1);
+
+    assert!(TimingModel::parse("bogus = 1").is_err());
+    assert!(TimingModel::parse("op.bogus = 1").is_err());
+    assert!(TimingModel::parse("op.mfhi = 0").is_err());
+    assert!(TimingModel::parse("mem = fast").is_err());
+    assert!(TimingModel::parse("mem").is_err());
+}
\ No newline at end of file
@@ -0,0 +1,456 @@
+/*!
+ *  Execution trace written by Core, one record per retired instruction
+ *
+ *  A record holds the PC and word of the instruction, the registers it wrote with
+ *  their new values, the loads and stores it made and the exception it raised, if any.
+ *  Registers 0 to 31 are the GPRs, 32 is HI and 33 is LO.
+ *
+ *  Text format, one line per record with tab separated columns:
+ *
+ *      <pc>\t<word>\t<disassembly>\t<effects>
+ *
+ *  pc, word, addresses and values are hex without a prefix. effects is a space separated
+ *  list of, in this order:
+ *
+ *      $<reg>=<value>              a register write, $hi and $lo included
+ *      L<size>@<addr>=<value>      a load of size bytes
+ *      S<size>@<addr>=<value>      a store of size bytes
+ *      !syscall, !interrupt, !fault    the exception raised by the instruction
+ *
+ *  Binary format, big endian: the magic "MIPSTRCE" and a u32 version, then per record
+ *
+ *      pc: u32, word: u32
+ *      register count: u8, then per register its number as u8 and value as u32
+ *      access count: u8, then per access its kind (0 load, 1 store) and size as u8,
+ *          address and value as u32
+ *      exception: u8, 0 none, 1 syscall, 2 interrupt, 3 fault
+ *
+ *  TraceReader also reads SPIM/MARS-style listings, where each executed instruction is
+ *  a line like "[0x00400000]\t0x24011000  addiu $1, $0, 4096". Those only give the PC
+ *  and word, other lines are ignored
+ */
+
+use super::Disasm;
+use super::Errors::Access;
+use super::Snapshot::Payload;
+
+use std::io::{self, BufRead, Write};
+
+pub const MAGIC: &[u8; 8] = b"MIPSTRCE";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceException {
+    Syscall,
+    Interrupt,
+    Fault
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemAccess {
+    pub access: Access,
+    pub addr: u32,
+    pub size: u8,
+    pub value: u32
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceRecord {
+    pub pc: u32,
+    pub code: u32,
+    pub regs: Vec<(u8, u32)>,
+    pub mem: Vec<MemAccess>,
+    pub exception: Option<TraceException>
+}
+
+/**
+ * Name of register r in trace records, without the '$'; HI and LO are numbered as in Disasm
+ */
+pub fn reg_name(r: u32) -> &'static str {
+    match r {
+        Disasm::REG_HI => "hi",
+        Disasm::REG_LO => "lo",
+        r => Disasm::REG_NAMES[r as usize & 31]
+    }
+}
+
+impl std::fmt::Display for TraceException {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      TraceException::Syscall => write!(f, "syscall"),
+      TraceException::Interrupt => write!(f, "interrupt"),
+      TraceException::Fault => write!(f, "fault")
+    }
+  }
+}
+
+/**
+ * Formats the effects column of a text record
+ */
+impl std::fmt::Display for TraceRecord {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+
+    let mut tokens = Vec::new();
+
+    for (r, v) in &self.regs {
+        tokens.push(format!("${}={v:08x}", reg_name(*r as u32)));
+    }
+    for m in &self.mem {
+        let kind = if m.access == Access::Store { 'S' } else { 'L' };
+        tokens.push(format!("{kind}{}@{:08x}={:0w$x}", m.size, m.addr, m.value, w = m.size as usize * 2));
+    }
+    if let Some(e) = self.exception {
+        tokens.push(format!("!{e}"));
+    }
+
+    write!(f, "{}", tokens.join(" "))
+  }
+}
+
+/**
+ * Collects the effects of the instruction being executed and writes a record
+ * when it retires
+ *
+ * Write errors don't stop the Core; the first one is kept and returned by finish
+ */
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    current: TraceRecord,
+    last: TraceRecord,
+    error: Option<io::Error>
+}
+
+impl Tracer {
+
+    pub fn new(mut out: Box<dyn Write>, format: TraceFormat) -> io::Result<Tracer> {
+
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_be_bytes())?;
+        }
+
+        Ok(Tracer { out, format, current: TraceRecord::default(), last: TraceRecord::default(), error: None })
+    }
+
+    /**
+     * Records a load or store made by the instruction being executed
+     */
+    pub fn access(&mut self, access: Access, addr: u32, contents: &[u8]) {
+        let value = contents.iter().fold(0u32, |v, b| v << 8 | *b as u32);
+        self.current.mem.push( MemAccess { access, addr, size: contents.len() as u8, value } );
+    }
+
+    /**
+     * Records the exception raised by the instruction being executed
+     */
+    pub fn exception(&mut self, e: TraceException) {
+        self.current.exception = Some(e);
+    }
+
+    /**
+     * Writes the record of a retired instruction and starts a new one
+     *
+     * ARGS:
+     *
+     *  regs: The registers the instruction wrote, with their new values
+     */
+    pub fn retire(&mut self, pc: u32, code: u32, regs: Vec<(u8, u32)>) {
+
+        self.last = std::mem::take(&mut self.current);
+        self.last.pc = pc;
+        self.last.code = code;
+        self.last.regs = regs;
+
+        if self.error.is_some() { return; }
+
+        let res = match self.format {
+            TraceFormat::Text => writeln!(self.out, "{:08x}\t{:08x}\t{}\t{}", pc, code, Disasm::disassemble(code), self.last),
+            TraceFormat::Binary => self.out.write_all(&Tracer::encode(&self.last))
+        };
+
+        if let Err(eobj) = res { self.error = Some(eobj); }
+    }
+
+    /**
+     * The record of the last retired instruction
+     */
+    pub fn last(&self) -> &TraceRecord {
+        &self.last
+    }
+
+    fn encode(record: &TraceRecord) -> Vec<u8> {
+
+        let mut p = Payload::default();
+        p.u32(record.pc).u32(record.code);
+
+        p.raw(&[record.regs.len() as u8]);
+        for (r, v) in &record.regs { p.raw(&[*r]).u32(*v); }
+
+        p.raw(&[record.mem.len() as u8]);
+        for m in &record.mem {
+            p.raw(&[(m.access == Access::Store) as u8, m.size]).u32(m.addr).u32(m.value);
+        }
+
+        p.raw(&[match record.exception {
+            None => 0,
+            Some(TraceException::Syscall) => 1,
+            Some(TraceException::Interrupt) => 2,
+            Some(TraceException::Fault) => 3
+        }]);
+
+        p.bytes
+    }
+
+    /**
+     * Flushes the trace
+     *
+     * RETURNS:
+     *
+     *  The first write error, if any
+     */
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(eobj) => Err(eobj),
+            None => self.out.flush()
+        }
+    }
+}
+
+/**
+ * Reads trace records from any of the formats above
+ */
+pub struct TraceReader<R: BufRead> {
+    r: R,
+    format: ReaderFormat,
+    line: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReaderFormat {
+    Text,
+    Binary,
+    Listing
+}
+
+fn invalid(emsg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, emsg)
+}
+
+impl<R: BufRead> TraceReader<R> {
+
+    /**
+     * Detects the format of the trace from its first bytes
+     */
+    pub fn new(mut r: R) -> io::Result<TraceReader<R>> {
+
+        let head = r.fill_buf()?;
+
+        let format = if head.starts_with(MAGIC) {
+            let mut header = [0u8; 12];
+            r.read_exact(&mut header)?;
+            let version = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+            if version != VERSION { return Err(invalid(format!("Unsupported trace version {version}"))) }
+            ReaderFormat::Binary
+        } else if head.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+            ReaderFormat::Listing
+        } else {
+            ReaderFormat::Text
+        };
+
+        Ok(TraceReader { r, format, line: 0 })
+    }
+
+    /**
+     * True if records carry register writes, accesses and exceptions,
+     * false if they only have the PC and word
+     */
+    pub fn has_effects(&self) -> bool {
+        self.format != ReaderFormat::Listing
+    }
+
+    /**
+     * RETURNS:
+     *
+     *  The next record, or None at end of file
+     */
+    pub fn next_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        match self.format {
+            ReaderFormat::Binary => self.next_binary(),
+            _ => self.next_line()
+        }
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<TraceRecord>> {
+
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.r.read_line(&mut line)? == 0 { return Ok(None) }
+            self.line += 1;
+
+            let parsed = match self.format {
+                ReaderFormat::Listing => parse_listing(&line),
+                _ if line.trim().is_empty() => continue,
+                _ => Some(parse_text(&line).ok_or_else(|| invalid(format!("Trace line {}: could not parse '{}'", self.line, line.trim_end())))?)
+            };
+
+            if let Some(record) = parsed { return Ok(Some(record)) }
+        }
+    }
+
+    fn next_binary(&mut self) -> io::Result<Option<TraceRecord>> {
+
+        let mut head = [0u8; 8];
+        match self.r.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+
+        let u32_at = |b: &[u8], i: usize| u32::from_be_bytes([b[i], b[i+1], b[i+2], b[i+3]]);
+
+        let mut record = TraceRecord { pc: u32_at(&head, 0), code: u32_at(&head, 4), ..Default::default() };
+
+        let mut n = [0u8; 1];
+        self.r.read_exact(&mut n)?;
+        for _ in 0..n[0] {
+            let mut reg = [0u8; 5];
+            self.r.read_exact(&mut reg)?;
+            record.regs.push((reg[0], u32_at(&reg, 1)));
+        }
+
+        self.r.read_exact(&mut n)?;
+        for _ in 0..n[0] {
+            let mut m = [0u8; 10];
+            self.r.read_exact(&mut m)?;
+            let access = if m[0] == 1 { Access::Store } else { Access::Load };
+            record.mem.push( MemAccess { access, size: m[1], addr: u32_at(&m, 2), value: u32_at(&m, 6) } );
+        }
+
+        self.r.read_exact(&mut n)?;
+        record.exception = match n[0] {
+            0 => None,
+            1 => Some(TraceException::Syscall),
+            2 => Some(TraceException::Interrupt),
+            3 => Some(TraceException::Fault),
+            e => return Err(invalid(format!("Unknown exception {e} in trace record")))
+        };
+
+        Ok(Some(record))
+    }
+}
+
+/**
+ * Parses a line of the text format
+ */
+fn parse_text(line: &str) -> Option<TraceRecord> {
+
+    let cols: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+    if cols.len() != 4 { return None }
+
+    let mut record = TraceRecord {
+        pc: u32::from_str_radix(cols[0], 16).ok()?,
+        code: u32::from_str_radix(cols[1], 16).ok()?,
+        ..Default::default()
+    };
+
+    for token in cols[3].split_whitespace() {
+        if let Some(reg) = token.strip_prefix('$') {
+            let (name, value) = reg.split_once('=')?;
+            let r = (0..=Disasm::REG_LO).find(|r| reg_name(*r) == name)?;
+            record.regs.push((r as u8, u32::from_str_radix(value, 16).ok()?));
+        } else if let Some(e) = token.strip_prefix('!') {
+            record.exception = Some(match e {
+                "syscall" => TraceException::Syscall,
+                "interrupt" => TraceException::Interrupt,
+                "fault" => TraceException::Fault,
+                _ => return None
+            });
+        } else {
+            let access = match token.chars().next()? { 'L' => Access::Load, 'S' => Access::Store, _ => return None };
+            let (size, rest) = token[1..].split_once('@')?;
+            let (addr, value) = rest.split_once('=')?;
+            record.mem.push( MemAccess {
+                access,
+                size: size.parse().ok()?,
+                addr: u32::from_str_radix(addr, 16).ok()?,
+                value: u32::from_str_radix(value, 16).ok()?
+            });
+        }
+    }
+
+    Some(record)
+}
+
+/**
+ * Parses a "[0x<pc>] 0x<word> ..." line of a SPIM/MARS-style listing
+ */
+fn parse_listing(line: &str) -> Option<TraceRecord> {
+
+    let rest = line.trim_start().strip_prefix("[0x")?;
+    let (pc, rest) = rest.split_once(']')?;
+    let code = rest.split_whitespace().next()?.strip_prefix("0x")?;
+
+    Some( TraceRecord {
+        pc: u32::from_str_radix(pc, 16).ok()?,
+        code: u32::from_str_radix(code, 16).ok()?,
+        ..Default::default()
+    })
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn text_and_binary() {
+    let record = TraceRecord {
+        pc: 0x00400000,
+        code: 0xac220004,
+        regs: vec![(2, 5), (Disasm::REG_HI as u8, 0xffffffff)],
+        mem: vec![MemAccess { access: Access::Store, addr: 0x10010004, size: 2, value: 0xbeef }],
+        exception: Some(TraceException::Syscall)
+    };
+
+    assert_eq!(record.to_string(), "$v0=00000005 $hi=ffffffff S2@10010004=beef !syscall");
+
+    let bin = Tracer::encode(&record);
+    assert_eq!(bin.len(), 8 + 1 + 2*5 + 1 + 10 + 1);
+    assert_eq!(&bin[..4], &[0x00, 0x40, 0x00, 0x00]);
+    assert_eq!(*bin.last().unwrap(), 1);
+
+    //both formats read back to the same record
+    let text = format!("{:08x}\t{:08x}\t{}\t{}\n", record.pc, record.code, Disasm::disassemble(record.code), record);
+    let mut r = TraceReader::new(text.as_bytes()).unwrap();
+    assert!(r.has_effects());
+    assert_eq!(r.next_record().unwrap(), Some(record.clone()));
+    assert_eq!(r.next_record().unwrap(), None);
+
+    let mut bin_file = MAGIC.to_vec();
+    bin_file.extend_from_slice(&VERSION.to_be_bytes());
+    bin_file.extend_from_slice(&bin);
+    let mut r = TraceReader::new(bin_file.as_slice()).unwrap();
+    assert_eq!(r.next_record().unwrap(), Some(record));
+    assert_eq!(r.next_record().unwrap(), None);
+
+    assert!(TraceReader::new(&b"00400000\tzz\t\t\n"[..]).unwrap().next_record().is_err());
+}
+
+#[test]
+fn spim_listing() {
+    let listing = "[0x00400000]\t0x8fa40000  lw $4, 0($29)   ; 183: lw $a0 0($sp)\nsome program output\n[0x00400004]\t0x27a50004  addiu $5, $29, 4\n";
+    let mut r = TraceReader::new(listing.as_bytes()).unwrap();
+
+    assert!(!r.has_effects());
+    assert_eq!(r.next_record().unwrap().map(|t| (t.pc, t.code)), Some((0x00400000, 0x8fa40000)));
+    assert_eq!(r.next_record().unwrap().map(|t| (t.pc, t.code)), Some((0x00400004, 0x27a50004)));
+    assert_eq!(r.next_record().unwrap(), None);
+}
@@ -101,6 +101,35 @@ pub fn from_byte(contents: &[u8]) -> u32 {
     contents[0] as u32
 }
 
+/**
+ *  Lays words out big endian in a raw image, like a .bin, for Core::load_image
+ *
+ *      image = Definitions::image_of(&[(0, &[0x01020304]), (0x10, &[0xaabbccdd])]);
+ *      assert_eq!(&[1,2,3,4], &image[0..4]);
+ *      assert_eq!(0x14, image.len());
+ *
+ *  Bytes not covered by a segment are 0
+ *
+ *  ARGS:
+ *
+ *  segments: (address, words) to place, in any order
+ *
+ *  RETURNS:
+ *
+ *  the image, up to the end of the last segment
+ */
+pub fn image_of(segments: &[(usize, &[Word])]) -> Vec<Byte> {
+
+    let end = segments.iter().map(|(at, code)| at + 4*code.len()).max().unwrap_or(0);
+    let mut image = vec![0; end];
+    for (at, code) in segments {
+        for (n, word) in code.iter().enumerate() {
+            image[at + 4*n..at + 4*n + 4].copy_from_slice(&word.to_be_bytes());
+        }
+    }
+    image
+}
+
 #[macro_use]
 pub mod Macros {
     /**
@@ -185,3 +214,12 @@ fn from_size() {
     arr = &[23, 4, 5, 6, 9]; //len 5
     assert_eq!([23, 4, 5, 6], from_sizeN::<4>(arr));
 }
+
+#[test]
+fn images() {
+
+    let image = image_of(&[(8, &[0x01020304]), (0, &[0xaabbccdd])]);
+    assert_eq!(image, [0xaa, 0xbb, 0xcc, 0xdd, 0, 0, 0, 0, 1, 2, 3, 4]);
+
+    assert!(image_of(&[]).is_empty());
+}
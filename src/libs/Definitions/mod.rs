@@ -2,4 +2,12 @@ pub mod Utils;
 pub mod Arch;
 pub mod RELFHeaders;
 pub mod Stats;
-pub mod Errors;
\ No newline at end of file
+pub mod Errors;
+pub mod Disasm;
+pub mod CallStack;
+pub mod Snapshot;
+pub mod Journal;
+pub mod Trace;
+pub mod Timing;
+pub mod Decode;
+pub mod Log;
\ No newline at end of file
@@ -46,6 +46,17 @@ impl MemoryMapped for Console {
 
         Ok(())
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.mode]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), MemError> {
+        match state {
+            [mode] => { self.mode = *mode; Ok(()) }
+            _ => Err(MemError::MappedDeviceError(format!("Console: Expected 1 byte of state, got {}", state.len())))
+        }
+    }
 }
 
 /**
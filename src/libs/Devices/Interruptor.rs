@@ -1,16 +1,61 @@
 use std::thread;
-use std::sync::mpsc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::log;
+
+// the line of the real-time clock
+pub const CLOCK_LINE: u32 = 0;
+
+/**
+ * Interrupt lines shared by the Core with interruptor threads and devices
+ *
+ * Every line is a bit of one word. Raising a line sets its bit atomically and the Core
+ * takes all of them at once, so checking for interrupts is a single load and a line
+ * raised twice before the Core looks is one interrupt
+ */
+#[derive(Debug, Clone, Default)]
+pub struct InterruptLines(Arc<AtomicU32>);
+
+impl InterruptLines {
+
+    pub fn raise(&self, line: u32) {
+        self.0.fetch_or(1 << line, Ordering::Release);
+    }
+
+    /**
+     * The lines raised and not taken yet, as bits
+     */
+    #[inline(always)]
+    pub fn pending(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Clears the raised lines and returns them
+     */
+    pub fn take(&self) -> u32 {
+        self.0.swap(0, Ordering::Acquire)
+    }
+
+    /**
+     * True if nobody else holds these lines, so raising them is pointless
+     */
+    fn orphaned(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+}
+
 /**
  * Start a new Interruptor thread with the given action
  * 
- * The Interruptor thread will fire an interrupt every time the
- * associated closure returns true. The thread will then sleep for 'duration'
+ * The Interruptor thread will raise its line every time the
+ * associated closure returns true. The thread will then sleep for 'duration',
+ * or until it is unparked
  * 
- * This behaviour loops infinitely until the ch_open_flag is set to false
+ * This behaviour loops infinitely until the open flag is set to false,
+ * or the Core drops its lines
  * 
  * ARGS:
  * 
@@ -18,50 +63,76 @@ use std::time::Duration;
  * 
  * duration: The firing speed of the interruptor, as Duration
  *
- * ch_send: Sender mpsc to Core's internal receiver
+ * lines: The Core's interrupt lines
+ *
+ * line: The line raised, 0 to 31
  * 
- * ch_open_flag: Flag to signal closing of the channel
+ * open: Cleared to stop the thread; unpark it after, to stop it at once
  * 
  * action: The function determining when to fire the interrupt. Fn() -> bool + Send + 'static
 */
-pub fn new<FuncTyp>(name: &'static str,duration: Duration, ch_send: &mpsc::Sender<u32>, ch_open_flag: Arc<AtomicBool> ,verbose: bool, action: FuncTyp) -> std::thread::JoinHandle<()>
+pub fn new<FuncTyp>(name: &'static str,duration: Duration, lines: &InterruptLines, line: u32, open: Arc<AtomicBool>, action: FuncTyp) -> std::thread::JoinHandle<()>
     where FuncTyp: Fn() -> bool + Send + 'static
 {
 
-    let ch_send = ch_send.clone();
+    log!(Info, name, "Spawned interruptor with timeout {:?} on line {}",duration, line);
+
+    new_waiting(name, lines, line, open, action, move || Some(duration))
+}
+
+/**
+ * Start a new Interruptor thread that asks how long to sleep every time
+ *
+ * Like new, but after each call to action the thread sleeps for what wait returns,
+ * or until it is unparked if that is None. Whoever changes what wait would return
+ * must unpark the thread
+ *
+ * ARGS:
+ *
+ * name: The name for the Interruptor
+ *
+ * lines: The Core's interrupt lines
+ *
+ * line: The line raised, 0 to 31
+ *
+ * open: Cleared to stop the thread; unpark it after, to stop it at once
+ *
+ * action: The function determining when to fire the interrupt. Fn() -> bool + Send + 'static
+ *
+ * wait: How long to sleep after action. Fn() -> Option<Duration> + Send + 'static
+*/
+pub fn new_waiting<FuncTyp, WaitTyp>(name: &'static str, lines: &InterruptLines, line: u32, open: Arc<AtomicBool>, action: FuncTyp, wait: WaitTyp) -> std::thread::JoinHandle<()>
+    where FuncTyp: Fn() -> bool + Send + 'static,
+          WaitTyp: Fn() -> Option<Duration> + Send + 'static
+{
 
-    if verbose { println!("[{}]: Spawned interruptor with timeout {:?}",name.to_uppercase(),duration); }
+    let lines = lines.clone();
 
     thread::Builder::new().name(name.to_string()).spawn(move || {
         
         loop {
             // flag is false -> close thread
-            if !ch_open_flag.load(Ordering::Relaxed) {
-                if verbose {
-                    println!("[{}]: Channel close flag set: closing",name.to_uppercase());
-                }
+            if !open.load(Ordering::Relaxed) {
+                log!(Debug, name, "Close flag set: closing");
                 break;
             }
 
-            if action() { 
+            if lines.orphaned() {
+                log!(Debug, name, "CORE lines unavailable: CORE assumed dead, closing");
+                break;
+            }
 
-                if verbose {
-                    println!("[{}]: awakened after {:?}, sending interrupt",name.to_uppercase(),duration);
-                }
+            if action() { 
 
-                match ch_send.send(1) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        if verbose {
-                            println!("[{}]: CORE channel unavailable: CORE assumed dead, closing", name.to_uppercase());
-                        }
-                        break;
-                    }
-                }
+                log!(Trace, name, "awakened, raising line {}", line);
+                lines.raise(line);
 
             }
 
-            thread::sleep(duration);
+            match wait() {
+                Some(duration) => thread::park_timeout(duration),
+                None => thread::park()
+            }
         }
 
     }).unwrap()
@@ -76,53 +147,154 @@ pub fn new<FuncTyp>(name: &'static str,duration: Duration, ch_send: &mpsc::Sende
  *
  * resolution: The firing speed of the interruptor, in Duration
  *
- * ch_send: Sender mpsc to Core's internal receiver
+ * lines: The Core's interrupt lines
+ *
+ * line: The line raised
  * 
- * ch_open_flag: Flag to signal closing of the channel
+ * open: Cleared to stop the thread
 */
-pub fn new_default(name: &'static str,resolution: Duration, ch_send: &mpsc::Sender<u32>, ch_open_flag: Arc<AtomicBool> , verbose: bool) -> std::thread::JoinHandle<()> {
+pub fn new_default(name: &'static str,resolution: Duration, lines: &InterruptLines, line: u32, open: Arc<AtomicBool>) -> std::thread::JoinHandle<()> {
 
-    new(name, resolution, ch_send, ch_open_flag, verbose, move || {
+    new(name, resolution, lines, line, open, move || {
         true
     })
 
 }
 
+/**
+ * How the Core's clock interrupt is generated
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMode {
+    // fires every n executed cycles, deterministically
+    Virtual(u64),
+    // fires every period of wall-clock time, from an Interruptor thread
+    RealTime(Duration),
+    // never fires; used when replaying a journal, which holds the interrupts
+    Off
+}
+
+/**
+ * An interrupt source driven by the Core's cycle count instead of a thread
+ *
+ * It fires when the cycle count reaches `next`, and then every `period` cycles
+ * after that if it is periodic, so it always fires at the same instruction
+ * for a given program. Firing raises `line`, the clock's unless set with on
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualInterruptor {
+    pub name: &'static str,
+    pub next: u64,
+    pub period: Option<u64>,
+    pub line: u32
+}
+
+impl VirtualInterruptor {
+
+    /**
+     * Fires for the first time at cycle period, then every period cycles
+     */
+    pub fn periodic(name: &'static str, period: u64) -> VirtualInterruptor {
+        VirtualInterruptor { name, next: period, period: Some(period.max(1)), line: CLOCK_LINE }
+    }
+
+    /**
+     * Fires once, at cycle at
+     */
+    #[allow(dead_code)]
+    pub fn at(name: &'static str, at: u64) -> VirtualInterruptor {
+        VirtualInterruptor { name, next: at, period: None, line: CLOCK_LINE }
+    }
+
+    /**
+     * The same source, raising line instead
+     */
+    #[allow(dead_code)]
+    pub fn on(self, line: u32) -> VirtualInterruptor {
+        VirtualInterruptor { line, ..self }
+    }
+
+    /**
+     * RETURNS:
+     *
+     *  true if the source fires at cycle count cycles
+     */
+    #[inline(always)]
+    pub fn poll(&mut self, cycles: u64) -> bool {
+
+        if cycles < self.next { return false; }
+
+        match self.period {
+            Some(period) => { while self.next <= cycles { self.next += period; } }
+            None => { self.next = u64::MAX; }
+        }
+
+        true
+    }
+}
+
+#[test]
+fn virtual_poll() {
+    let mut p = VirtualInterruptor::periodic("TEST", 3);
+    let fired: Vec<u64> = (0..10).filter(|c| p.poll(*c)).collect();
+    assert_eq!(fired, vec![3, 6, 9]);
+
+    let mut o = VirtualInterruptor::at("TEST", 4);
+    let fired: Vec<u64> = (0..10).filter(|c| o.poll(*c)).collect();
+    assert_eq!(fired, vec![4]);
+}
+
+/**
+ * Waits until lines has a line raised, for up to a second
+ */
+#[cfg(test)]
+fn wait_raised(lines: &InterruptLines) -> u32 {
+    for _ in 0..1000 {
+        let raised = lines.take();
+        if raised != 0 { return raised; }
+        thread::sleep(Duration::from_millis(1));
+    }
+    0
+}
+
 #[test]
 fn triggers() {
 
-    let (send,recv) = mpsc::channel();
+    let lines = InterruptLines::default();
 
-    new_default("TEST", Duration::new(0, 2), &send, Arc::new(AtomicBool::new(true)) ,true);
+    new_default("TEST", Duration::new(0, 2), &lines, 3, Arc::new(AtomicBool::new(true)));
 
-    thread::sleep(Duration::new(0,1));
-    assert_eq!(recv.recv().unwrap(), 1);
-    thread::sleep(Duration::new(0,1));
-    assert_eq!(recv.recv().unwrap(), 1);
+    assert_eq!(wait_raised(&lines), 1 << 3);
+    assert_eq!(wait_raised(&lines), 1 << 3);
 
 }
 
 #[test]
 fn fn_passing() {
     
-    let (send,recv) = mpsc::channel();
+    let lines = InterruptLines::default();
 
-    new("CUSTOMFN", Duration::new(0,1), &send, Arc::new(AtomicBool::new(true)), true, move || 4 % 2 == 0 );
+    new("CUSTOMFN", Duration::new(0,1), &lines, 0, Arc::new(AtomicBool::new(true)), move || 4 % 2 == 0 );
 
-    assert_eq!(recv.recv().unwrap(), 1);
-    assert_ne!(recv.recv().unwrap(), 0);
+    assert_eq!(wait_raised(&lines), 1);
+    assert_ne!(wait_raised(&lines), 0);
 }
 
 #[test]
 fn closing_signal() {
 
-    let (send,recv) = mpsc::channel();
+    let lines = InterruptLines::default();
+    let open = Arc::new(AtomicBool::new(true));
 
-    new_default("TEST", Duration::new(0, 1), &send, Arc::new(AtomicBool::new(false)) ,true);
-
-    //is_err: The AtomicBool was passed as false so the thread will finish as soon as it is started.
-    //The channel is dropped and therefore closed, recv from a closed channel returns Err
-    assert!(recv.recv_timeout(Duration::new(0,2)).is_err());
-}
+    //a long period, so closing only works if the thread is woken up
+    let handle = new_default("TEST", Duration::new(3600, 0), &lines, 0, open.clone());
+    assert_eq!(wait_raised(&lines), 1);
 
+    open.store(false, Ordering::Relaxed);
+    handle.thread().unpark();
+    handle.join().unwrap();
 
+    //the thread also ends once the Core is gone
+    let handle = new_default("TEST", Duration::new(0, 1), &InterruptLines::default(), 0, Arc::new(AtomicBool::new(true)));
+    handle.join().unwrap();
+}
@@ -1,14 +1,15 @@
 use super::MemoryMapped;
 use std::io;
-use std::io::Read;
 use super::super::Definitions::Utils::from_sizeN;
 use super::super::Definitions::Errors::MemError;
+use super::super::Definitions::Journal::SharedJournal;
 
 pub struct Keyboard {
     pub range_lower: u32,
     pub range_upper: u32,
     buffer: Vec<u8>,
-    mode: u8
+    mode: u8,
+    journal: Option<SharedJournal>
 }
 
 impl MemoryMapped for Keyboard {
@@ -20,9 +21,21 @@ impl MemoryMapped for Keyboard {
 
         if dir < self.range_lower+3 {
 
-            io::stdin().read(&mut self.buffer).unwrap();
-            //remove intro character
-            self.buffer.pop();
+            let live = || {
+                let mut line = String::new();
+                // a closed stdin reads as an empty line
+                io::stdin().read_line(&mut line).unwrap_or(0);
+                //remove intro character
+                line.trim_end_matches(&['\n', '\r'][..]).as_bytes().to_vec()
+            };
+
+            self.buffer = match &self.journal {
+                Some(journal) => journal.borrow_mut().input(live),
+                None => live()
+            };
+
+            //short lines read as zero
+            if self.buffer.len() < size { self.buffer.resize(size, 0); }
 
         } else {
 
@@ -42,7 +55,17 @@ impl MemoryMapped for Keyboard {
         
         Err(MemError::MappedDeviceError(String::from(format!("Tried to write to non-writeable address 0x{:08x} in device 'Keyboard'",dir)))) 
     }
-    
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.mode]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), MemError> {
+        match state {
+            [mode] => { self.mode = *mode; Ok(()) }
+            _ => Err(MemError::MappedDeviceError(format!("Keyboard: Expected 1 byte of state, got {}", state.len())))
+        }
+    }
 }
 
 /**
@@ -57,7 +80,16 @@ impl MemoryMapped for Keyboard {
  */
 pub fn new() -> Keyboard {
 
-    Keyboard { range_lower: 0x80000008, range_upper: 0x8000000f, buffer: Vec::<u8>::new(), mode: 0 }
+    Keyboard { range_lower: 0x80000008, range_upper: 0x8000000f, buffer: Vec::<u8>::new(), mode: 0, journal: None }
+
+}
+
+/**
+ * Creates a Keyboard like new, whose input goes through journal so it can be recorded and replayed
+ */
+pub fn with_journal(journal: SharedJournal) -> Keyboard {
+
+    Keyboard { journal: Some(journal), ..new() }
 
 }
 
@@ -75,6 +107,24 @@ fn write_mode_K() {
     k.write( (k.range_lower+4) as usize, 1, &[0;4]).unwrap();
 }
 
+#[test]
+fn journaled_read_K() {
+    use super::super::Definitions::Journal::{Journal, Entry, Event};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let journal = Rc::new(RefCell::new(Journal::default()));
+    journal.borrow_mut().recording = true;
+    let mut k: Keyboard = with_journal(journal.clone());
+
+    //pretend we already read "hi" once, and replay it instead of reading stdin
+    journal.borrow_mut().input(|| b"hi".to_vec());
+    journal.borrow_mut().seek(0);
+
+    assert_eq!(k.read(0x80000008, 4).unwrap(), b"hi\0\0");
+    assert_eq!(journal.borrow().entries(), &[Entry { at: 0, event: Event::Input(b"hi".to_vec()) }]);
+}
+
 #[test]
 #[should_panic]
 fn read_mode_K() {
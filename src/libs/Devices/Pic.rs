@@ -0,0 +1,331 @@
+use super::MemoryMapped;
+use super::super::Definitions::Errors::MemError;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// lines of the controller, one per Cause.IP bit
+pub const LINES: u32 = 8;
+// read from CLAIM when no unmasked line is pending
+pub const NO_LINE: u8 = 0xff;
+
+const PENDING: u32  = 0;
+const MASK: u32     = 1;
+const CLAIM: u32    = 2;
+const PRIORITY: u32 = 4;
+
+/**
+ * The registers of the interrupt controller, shared by the Core and the Pic device
+ *
+ * A line is latched in pending when raised and stays there until the guest acknowledges
+ * it. The Core is only interrupted once per raise, when the line is raised or unmasked,
+ * so handlers that never acknowledge work as before there was a controller
+ *
+ * Every exception the Core takes runs at a level, until its RFE: an interrupt at one
+ * above the highest priority it was delivered for, a syscall at the level it was made
+ * from. Only lines of higher priority than the running level interrupt, so with every
+ * priority at 0 handlers are never nested
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PicState {
+    pub pending: u32,
+    // set bits are enabled
+    pub mask: u32,
+    // a nibble per line, line n at bits 4n; higher is served first
+    pub priority: u32,
+    // raised and not delivered to the Core yet
+    fresh: u32,
+    // running levels of the exceptions being handled, innermost last; user code runs at 0
+    levels: Vec<u32>
+}
+
+impl Default for PicState {
+    fn default() -> PicState {
+        PicState { pending: 0, mask: (1 << LINES) - 1, priority: 0, fresh: 0, levels: Vec::new() }
+    }
+}
+
+pub type SharedPic = Rc<RefCell<PicState>>;
+
+impl PicState {
+
+    /**
+     * Latches lines, as bits; lines past the controller's are dropped
+     */
+    pub fn raise(&mut self, lines: u32) {
+        let lines = lines & ((1 << LINES) - 1);
+        self.pending |= lines;
+        self.fresh |= lines;
+    }
+
+    /**
+     * True if an unmasked line above the running level was raised since it was last delivered
+     */
+    #[inline(always)]
+    pub fn signalled(&self) -> bool {
+        self.fresh & self.mask != 0 && self.eligible() != 0
+    }
+
+    fn eligible(&self) -> u32 {
+        let level = self.level();
+        (0..LINES).filter(|l| self.fresh & self.mask & (1 << l) != 0 && self.priority_of(*l) + 1 > level)
+            .fold(0, |bits, l| bits | 1 << l)
+    }
+
+    /**
+     * Marks the signalled lines as delivered when the Core takes the interrupt, and enters their level
+     *
+     * RETURNS:
+     *
+     *  The lines delivered, as bits
+     */
+    pub fn deliver(&mut self) -> u32 {
+        let lines = self.eligible();
+        self.fresh &= !lines;
+        self.enter(lines);
+        lines
+    }
+
+    /**
+     * The level of the innermost exception being handled
+     */
+    pub fn level(&self) -> u32 {
+        self.levels.last().copied().unwrap_or(0)
+    }
+
+    /**
+     * Starts running an exception taken for lines, as bits, 0 for a syscall
+     */
+    pub fn enter(&mut self, lines: u32) {
+        let level = (0..LINES).filter(|l| lines & (1 << l) != 0).map(|l| self.priority_of(l) + 1).fold(self.level(), u32::max);
+        self.levels.push(level);
+    }
+
+    /**
+     * Returns to the level the innermost exception was taken at, on RFE
+     */
+    pub fn leave(&mut self) {
+        self.levels.pop();
+    }
+
+    /**
+     * Clears lines, as bits, from pending
+     */
+    pub fn ack(&mut self, lines: u32) {
+        self.pending &= !lines;
+        self.fresh &= !lines;
+    }
+
+    /**
+     * The unmasked pending lines, as bits, for Cause.IP
+     */
+    pub fn ip(&self) -> u32 {
+        self.pending & self.mask
+    }
+
+    pub fn priority_of(&self, line: u32) -> u32 {
+        (self.priority >> (4 * line)) & 0xf
+    }
+
+    /**
+     * The unmasked pending line of highest priority; the lowest line wins ties
+     */
+    pub fn claim(&self) -> Option<u32> {
+        (0..LINES).filter(|l| self.ip() & (1 << l) != 0)
+            .fold(None, |best: Option<u32>, l| match best {
+                Some(b) if self.priority_of(b) >= self.priority_of(l) => Some(b),
+                _ => Some(l)
+            })
+    }
+}
+
+pub struct Pic {
+    pub range_lower: u32,
+    pub range_upper: u32,
+    state: SharedPic,
+    buffer: Vec<u8>
+}
+
+impl Pic {
+
+    fn register(&self, reg: u32) -> u8 {
+        let state = self.state.borrow();
+        match reg {
+            PENDING => state.pending as u8,
+            MASK => state.mask as u8,
+            CLAIM => state.claim().map_or(NO_LINE, |l| l as u8),
+            PRIORITY..=7 => (state.priority >> (8 * (reg - PRIORITY))) as u8,
+            _ => 0
+        }
+    }
+}
+
+impl MemoryMapped for Pic {
+
+    fn read(&mut self, dir: u32, size: usize) -> Result<&[u8], MemError> {
+
+        let reg = dir - self.range_lower;
+        if reg as usize + size > 8 {
+            return Err(MemError::MappedDeviceError(format!("Tried to read past the end of device 'Pic' at address 0x{:08x}", dir)));
+        }
+
+        self.buffer = (reg..reg + size as u32).map(|r| self.register(r)).collect();
+        Ok(&self.buffer)
+    }
+
+    fn write(&mut self, dir: usize, size: usize, contents: &[u8]) -> Result<(), MemError> {
+
+        let reg = dir as u32 - self.range_lower;
+        if reg as usize + size > 8 {
+            return Err(MemError::MappedDeviceError(format!("Tried to write past the end of device 'Pic' at address 0x{:08x}", dir)));
+        }
+
+        let mut state = self.state.borrow_mut();
+        for (r, byte) in (reg..).zip(contents.iter().take(size).map(|b| *b as u32)) {
+            match r {
+                PENDING => state.ack(byte),
+                MASK => state.mask = byte,
+                CLAIM => if byte < LINES { state.ack(1 << byte) },
+                PRIORITY..=7 => {
+                    let shift = 8 * (r - PRIORITY);
+                    state.priority = (state.priority & !(0xff << shift)) | byte << shift;
+                }
+                _ => return Err(MemError::MappedDeviceError(format!("Tried to write to reserved address 0x{:08x} in device 'Pic'", self.range_lower + r)))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let state = self.state.borrow();
+        [state.pending, state.mask, state.priority, state.fresh].iter().chain(&state.levels).flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), MemError> {
+        if state.len() < 16 || !state.len().is_multiple_of(4) {
+            return Err(MemError::MappedDeviceError(format!("Pic: Expected 16 bytes of state and a word per level, got {}", state.len())));
+        }
+        let words: Vec<u32> = state.chunks(4).map(|w| u32::from_be_bytes([w[0], w[1], w[2], w[3]])).collect();
+        *self.state.borrow_mut() = PicState { pending: words[0], mask: words[1], priority: words[2], fresh: words[3], levels: words[4..].to_vec() };
+        Ok(())
+    }
+}
+
+/**
+ * Creates an interrupt controller implementing MemoryMapped over state, with the
+ * following byte registers
+ *
+ *
+ * 0x80000010: Pending lines as bits; writing acknowledges the lines set
+ *
+ * 0x80000011: Mask; set bits are enabled, all of them at reset
+ *
+ * 0x80000012: Claim; reads the pending line to serve, 0xff if none. Writing a line number acknowledges it
+ *
+ * 0x80000013: Reserved
+ *
+ * 0x80000014..0x80000017: Priorities, a nibble per line: line 2k in the low nibble of byte k, 2k+1 in the high one
+ */
+pub fn new(state: SharedPic) -> Pic {
+
+    Pic { range_lower: 0x80000010, range_upper: 0x80000017, state, buffer: Vec::new() }
+
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn registers_P() {
+    let state: SharedPic = Rc::default();
+    let mut p = new(state.clone());
+
+    state.borrow_mut().raise(0b1010);
+    assert_eq!(p.read(0x80000010, 3).unwrap(), &[0b1010, 0xff, 1]);
+
+    //line 3 over line 1
+    p.write(0x80000015, 1, &[0x50]).unwrap();
+    assert_eq!(state.borrow().priority_of(3), 5);
+    assert_eq!(p.read(0x80000012, 1).unwrap(), &[3]);
+
+    //masked lines stay pending but are not claimed
+    p.write(0x80000011, 1, &[0b0010]).unwrap();
+    assert_eq!(p.read(0x80000012, 1).unwrap(), &[1]);
+    assert_eq!(state.borrow().ip(), 0b0010);
+
+    p.write(0x80000012, 1, &[1]).unwrap();
+    p.write(0x80000011, 1, &[0xff]).unwrap();
+    assert_eq!(p.read(0x80000010, 1).unwrap(), &[0b1000]);
+    p.write(0x80000010, 1, &[0b1000]).unwrap();
+    assert_eq!(p.read(0x80000012, 1).unwrap(), &[NO_LINE]);
+
+    assert!(p.write(0x80000013, 1, &[0]).is_err());
+    assert!(p.read(0x80000016, 4).is_err());
+}
+
+#[test]
+fn delivery_P() {
+    let mut s = PicState { mask: 0b01, ..Default::default() };
+
+    //one interrupt per raise, even if never acknowledged
+    s.raise(0b01);
+    assert!(s.signalled());
+    s.deliver();
+    s.leave();
+    assert!(!s.signalled());
+    s.raise(0b01);
+    assert!(s.signalled());
+    s.deliver();
+    s.leave();
+
+    //a masked line is delivered once unmasked
+    s.raise(0b10);
+    assert!(!s.signalled());
+    s.mask = 0b11;
+    assert!(s.signalled());
+
+    //acknowledged lines come back with a saved state
+    let state = Rc::new(RefCell::new(s));
+    let mut p = new(state.clone());
+    let saved = p.save_state();
+    p.write(0x80000010, 1, &[0b11]).unwrap();
+    assert!(!state.borrow().signalled());
+    p.load_state(&saved).unwrap();
+    assert_eq!(p.read(0x80000010, 1).unwrap(), &[0b11]);
+    assert!(state.borrow().signalled());
+}
+
+#[test]
+fn levels_P() {
+    //line 1 at 2, line 0 at 0
+    let mut s = PicState { priority: 0x20, ..Default::default() };
+
+    //a syscall runs at the level it was made from, so anything interrupts it
+    s.enter(0);
+    s.raise(0b01);
+    assert_eq!(s.deliver(), 0b01);
+    assert_eq!(s.level(), 1);
+
+    //only higher priorities nest; the rest wait for RFE
+    s.raise(0b01);
+    assert!(!s.signalled());
+    s.raise(0b10);
+    assert_eq!(s.deliver(), 0b10);
+    assert_eq!(s.level(), 3);
+
+    s.leave();
+    s.leave();
+    assert!(s.signalled());
+    assert_eq!(s.deliver(), 0b01);
+    s.leave();
+    s.leave();
+    assert_eq!(s.level(), 0);
+
+    //levels are part of the saved state
+    s.enter(0b10);
+    let mut p = new(Rc::default());
+    p.load_state(&new(Rc::new(RefCell::new(s.clone()))).save_state()).unwrap();
+    assert_eq!(*p.state.borrow(), s);
+}
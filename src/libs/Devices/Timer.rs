@@ -0,0 +1,450 @@
+use super::MemoryMapped;
+use super::Interruptor::{self, InterruptLines};
+use super::super::Definitions::Errors::MemError;
+use super::super::Definitions::Journal::SharedJournal;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+// the interrupt controller line raised on a match
+pub const TIMER_LINE: u32 = 1;
+
+// CONTROL bits
+pub const ENABLE: u32   = 1;
+pub const PERIODIC: u32 = 1 << 1;
+pub const IRQ: u32      = 1 << 2;
+pub const MATCHED: u32  = 1 << 3;
+const CONTROL_BITS: u32 = ENABLE | PERIODIC | IRQ | MATCHED;
+
+const COUNT: u32   = 0;
+const COMPARE: u32 = 4;
+const RELOAD: u32  = 8;
+
+/**
+ * What the timer counts
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBase {
+    // executed cycles, so matches happen at the same instruction on every run
+    Cycles,
+    // microseconds since the instant
+    RealTime(Instant),
+    // a real-time run being replayed: counts and interrupts come from the journal
+    Replayed
+}
+
+/**
+ * The registers of the timer, and when they were last settled
+ *
+ * The count is not stored every tick: it was count_at at tick since, and has gone up
+ * by one every tick after while enabled. When it reaches compare the timer matches: it
+ * sets MATCHED, sets raised if IRQ is set, and then counts again from reload if
+ * PERIODIC is set, or stops at compare
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimerState {
+    pub count_at: u32,
+    pub since: u64,
+    pub compare: u32,
+    pub reload: u32,
+    pub control: u32,
+    pub base: TimeBase,
+    // matched with IRQ set since the line was last raised
+    pub raised: bool
+}
+
+impl TimerState {
+
+    pub fn count(&self, now: u64) -> u32 {
+        if self.control & ENABLE == 0 { return self.count_at; }
+        self.count_at.wrapping_add(now.saturating_sub(self.since) as u32)
+    }
+
+    /**
+     * The tick the next match is at, u64::MAX if the timer is stopped
+     */
+    pub fn deadline(&self) -> u64 {
+        if self.control & ENABLE == 0 { return u64::MAX; }
+        //reaching compare takes a full turn if the count is already there
+        match self.compare.wrapping_sub(self.count_at) {
+            0 => self.since + (1 << 32),
+            ticks => self.since + ticks as u64
+        }
+    }
+
+    /**
+     * Moves since to now, keeping the count
+     */
+    fn settle(&mut self, now: u64) {
+        self.count_at = self.count(now);
+        self.since = now;
+    }
+
+    /**
+     * Runs the timer up to now; the matches it caught up on raise the line once
+     */
+    pub fn poll(&mut self, now: u64) {
+
+        let at = self.deadline();
+        if now < at { return; }
+
+        self.control |= MATCHED;
+        self.raised |= self.control & IRQ != 0;
+        self.since = at;
+
+        if self.control & PERIODIC != 0 {
+            self.count_at = self.reload;
+            //skip the turns missed after the first
+            let period = self.deadline() - at;
+            self.since += (now - at) / period * period;
+        } else {
+            self.count_at = self.compare;
+            self.control &= !ENABLE;
+        }
+    }
+}
+
+/**
+ * A timer shared by the Core, its device and, in real time, the interruptor thread polling it
+ */
+#[derive(Debug)]
+pub struct TimerUnit {
+    state: Mutex<TimerState>,
+    // the Core's cycle count, set before it polls or touches the device
+    cycles: AtomicU64,
+    // the cycle the next match is at, u64::MAX unless counting cycles, read every instruction without locking
+    due: AtomicU64,
+    // in real time, the thread sleeping until the next match and the deadline it was last woken for
+    poller: Mutex<Option<Thread>>,
+    woken_for: AtomicU64
+}
+
+pub type SharedTimer = Arc<TimerUnit>;
+
+impl TimerUnit {
+
+    pub fn new(base: TimeBase) -> TimerUnit {
+        let state = TimerState { count_at: 0, since: 0, compare: 0, reload: 0, control: 0, base, raised: false };
+        TimerUnit { state: Mutex::new(state), cycles: AtomicU64::new(0), due: AtomicU64::new(u64::MAX), poller: Mutex::new(None), woken_for: AtomicU64::new(u64::MAX) }
+    }
+
+    /**
+     * Stops the timer and clears its registers, keeping its base
+     */
+    pub fn reset(&self) {
+        let mut state = self.state();
+        *state = TimerUnit::new(state.base).state().clone();
+        self.refresh(&state);
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, TimerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /**
+     * The cycle the next match is at; the Core only polls once it is reached
+     */
+    #[inline(always)]
+    pub fn due(&self) -> u64 {
+        self.due.load(Ordering::Relaxed)
+    }
+
+    pub fn set_cycles(&self, cycles: u64) {
+        self.cycles.store(cycles, Ordering::Relaxed);
+    }
+
+    /**
+     * The current tick of base
+     */
+    pub fn now(&self, base: TimeBase) -> u64 {
+        match base {
+            TimeBase::RealTime(start) => start.elapsed().as_micros() as u64,
+            TimeBase::Cycles | TimeBase::Replayed => self.cycles.load(Ordering::Relaxed)
+        }
+    }
+
+    /**
+     * Runs the timer up to now, see TimerState::poll
+     *
+     * RETURNS:
+     *
+     *  true if its line should be raised
+     */
+    pub fn poll(&self) -> bool {
+        let mut state = self.state();
+        if state.base == TimeBase::Replayed { return false; }
+        let now = self.now(state.base);
+        state.poll(now);
+        self.refresh(&state);
+        std::mem::take(&mut state.raised)
+    }
+
+    /**
+     * Counts in base from now on, keeping the count
+     */
+    pub fn rebase(&self, base: TimeBase) {
+        let mut state = self.state();
+        let now = self.now(state.base);
+        state.settle(now);
+        state.since = self.now(base);
+        state.base = base;
+        self.refresh(&state);
+    }
+
+    /**
+     * How long the real-time poller should sleep: until the next match, or until it is
+     * woken if the timer is stopped or not in real time
+     */
+    pub fn wait(&self) -> Option<Duration> {
+        let state = self.state();
+        match (state.base, state.deadline()) {
+            (TimeBase::RealTime(_), at) if at != u64::MAX => Some(Duration::from_micros(at.saturating_sub(self.now(state.base)))),
+            _ => None
+        }
+    }
+
+    fn refresh(&self, state: &TimerState) {
+        let due = if state.base == TimeBase::Cycles { state.deadline() } else { u64::MAX };
+        self.due.store(due, Ordering::Relaxed);
+
+        //a poller sleeping for the old deadline would miss an earlier one
+        let deadline = if let TimeBase::RealTime(_) = state.base { state.deadline() } else { u64::MAX };
+        if self.woken_for.swap(deadline, Ordering::Relaxed) != deadline {
+            if let Some(poller) = &*self.poller.lock().unwrap_or_else(|e| e.into_inner()) {
+                poller.unpark();
+            }
+        }
+    }
+}
+
+/**
+ * Starts the thread that polls a real-time timer, raising TIMER_LINE on lines
+ *
+ * It sleeps until the next match, and for as long as the timer is stopped
+ *
+ * ARGS:
+ *
+ *  open: Cleared to stop the thread; unpark it after, to stop it at once
+ */
+pub fn poller(timer: &SharedTimer, lines: &InterruptLines, open: Arc<AtomicBool>) -> JoinHandle<()> {
+
+    let (polled, waited) = (timer.clone(), timer.clone());
+    let handle = Interruptor::new_waiting("Timer", lines, TIMER_LINE, open, move || polled.poll(), move || waited.wait());
+    *timer.poller.lock().unwrap_or_else(|e| e.into_inner()) = Some(handle.thread().clone());
+    handle.thread().unpark();
+    handle
+}
+
+pub struct TimerDevice {
+    pub range_lower: u32,
+    pub range_upper: u32,
+    timer: SharedTimer,
+    journal: Option<SharedJournal>,
+    buffer: Vec<u8>
+}
+
+impl TimerDevice {
+
+    /**
+     * The registers, as big-endian words; in real time they go through the journal
+     */
+    fn registers(&self) -> Vec<u8> {
+
+        let mut state = self.timer.state();
+        let now = self.timer.now(state.base);
+        if state.base != TimeBase::Replayed {
+            state.poll(now);
+            self.timer.refresh(&state);
+        }
+
+        let live = || [state.count(now), state.compare, state.reload, state.control].iter().flat_map(|v| v.to_be_bytes()).collect();
+
+        match (&self.journal, state.base) {
+            (Some(journal), TimeBase::RealTime(_) | TimeBase::Replayed) => {
+                let mut regs = journal.borrow_mut().input(live);
+                regs.resize(16, 0);
+                regs
+            }
+            _ => live()
+        }
+    }
+}
+
+impl MemoryMapped for TimerDevice {
+
+    fn read(&mut self, dir: u32, size: usize) -> Result<&[u8], MemError> {
+
+        let reg = (dir - self.range_lower) as usize;
+        if reg + size > 16 {
+            return Err(MemError::MappedDeviceError(format!("Tried to read past the end of device 'Timer' at address 0x{:08x}", dir)));
+        }
+
+        self.buffer = self.registers()[reg..reg + size].to_vec();
+        Ok(&self.buffer)
+    }
+
+    fn write(&mut self, dir: usize, size: usize, contents: &[u8]) -> Result<(), MemError> {
+
+        let reg = dir as u32 - self.range_lower;
+        if reg as usize + size > 16 {
+            return Err(MemError::MappedDeviceError(format!("Tried to write past the end of device 'Timer' at address 0x{:08x}", dir)));
+        }
+
+        let mut state = self.timer.state();
+        let now = self.timer.now(state.base);
+        if state.base != TimeBase::Replayed { state.poll(now); }
+        state.settle(now);
+
+        //registers are written a byte at a time, so half-written words work like whole ones
+        for (r, byte) in (reg..).zip(contents.iter().take(size)) {
+            let word = match r & !3 {
+                COUNT   => &mut state.count_at,
+                COMPARE => &mut state.compare,
+                RELOAD  => &mut state.reload,
+                _       => &mut state.control
+            };
+            let shift = 8 * (3 - (r & 3));
+            *word = (*word & !(0xff << shift)) | (*byte as u32) << shift;
+        }
+        state.control &= CONTROL_BITS;
+
+        self.timer.refresh(&state);
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.timer.state().clone();
+        state.settle(self.timer.now(state.base));
+        let mut bytes: Vec<u8> = [state.count_at, state.compare, state.reload, state.control].iter().flat_map(|v| v.to_be_bytes()).collect();
+        bytes.extend_from_slice(&state.since.to_be_bytes());
+        bytes
+    }
+
+    fn load_state(&mut self, saved: &[u8]) -> Result<(), MemError> {
+
+        if saved.len() != 24 {
+            return Err(MemError::MappedDeviceError(format!("Timer: Expected 24 bytes of state, got {}", saved.len())));
+        }
+        let word = |n: usize| u32::from_be_bytes([saved[4*n], saved[4*n + 1], saved[4*n + 2], saved[4*n + 3]]);
+
+        let mut state = self.timer.state();
+        (state.count_at, state.compare, state.reload, state.control) = (word(0), word(1), word(2), word(3));
+        //cycles are restored with the Core, wall-clock time goes on from now
+        state.since = match state.base {
+            TimeBase::Cycles => u64::from_be_bytes([saved[16], saved[17], saved[18], saved[19], saved[20], saved[21], saved[22], saved[23]]),
+            _ => self.timer.now(state.base)
+        };
+        self.timer.refresh(&state);
+        Ok(())
+    }
+}
+
+/**
+ * Creates a timer device implementing MemoryMapped over timer, with the following
+ * big-endian word registers
+ *
+ *
+ * 0x80000018..0x8000001b: Count, going up by one every tick while enabled
+ *
+ * 0x8000001c..0x8000001f: Compare; the timer matches when the count reaches it
+ *
+ * 0x80000020..0x80000023: Reload; periodic timers count again from it after a match
+ *
+ * 0x80000024..0x80000027: Control: ENABLE, PERIODIC, IRQ and MATCHED, bits 0 to 3.
+ *                         One-shot timers clear ENABLE when they match. Write MATCHED to clear it
+ *
+ * A tick is a cycle, or a microsecond with a real-time clock. Registers read in real
+ * time go through journal so they are replayed
+ */
+pub fn new(timer: SharedTimer, journal: Option<SharedJournal>) -> TimerDevice {
+
+    TimerDevice { range_lower: 0x80000018, range_upper: 0x80000027, timer, journal, buffer: Vec::new() }
+
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn matches_T() {
+    let mut s = TimerState { count_at: 0, since: 0, compare: 10, reload: 4, control: ENABLE | IRQ, base: TimeBase::Cycles, raised: false };
+    let fired = |s: &mut TimerState, now: u64| { s.poll(now); std::mem::take(&mut s.raised) };
+
+    //one-shot: stops at compare
+    assert!(!fired(&mut s, 9));
+    assert!(fired(&mut s, 10));
+    assert_eq!((s.count(100), s.control & (ENABLE | MATCHED)), (10, MATCHED));
+    assert_eq!(s.deadline(), u64::MAX);
+
+    //periodic: from reload, every compare - reload ticks, catching up at once
+    s = TimerState { control: ENABLE | IRQ | PERIODIC, count_at: 0, since: 0, ..s };
+    assert!(fired(&mut s, 10));
+    assert_eq!(s.deadline(), 16);
+    assert!(fired(&mut s, 29));
+    assert!(!fired(&mut s, 29));
+    assert_eq!((s.count(29), s.deadline()), (5, 34));
+
+    //matches without IRQ do not fire
+    s.control &= !IRQ;
+    assert!(!fired(&mut s, 40));
+    assert!(s.control & MATCHED != 0);
+}
+
+#[test]
+fn registers_T() {
+    let timer: SharedTimer = Arc::new(TimerUnit::new(TimeBase::Cycles));
+    let mut t = new(timer.clone(), None);
+
+    //a byte at a time, as guests store
+    t.write(0x8000001f, 1, &[100]).unwrap();
+    t.write(0x80000027, 1, &[(ENABLE | IRQ) as u8]).unwrap();
+    assert_eq!(timer.due(), 100);
+
+    timer.set_cycles(60);
+    assert_eq!(t.read(0x80000018, 4).unwrap(), &60u32.to_be_bytes());
+    timer.set_cycles(100);
+    assert!(timer.poll());
+    assert_eq!(t.read(0x80000024, 4).unwrap(), &(IRQ | MATCHED).to_be_bytes());
+    assert_eq!(timer.due(), u64::MAX);
+
+    //the state comes back where it was saved
+    t.write(0x80000027, 1, &[(ENABLE | PERIODIC) as u8]).unwrap();
+    let saved = t.save_state();
+    t.write(0x80000027, 1, &[0]).unwrap();
+    t.load_state(&saved).unwrap();
+    assert_eq!(timer.due(), 100 + (1 << 32));
+
+    assert!(t.read(0x80000026, 4).is_err());
+}
+
+#[test]
+fn realtime_T() {
+    let lines = InterruptLines::default();
+    let open = Arc::new(AtomicBool::new(true));
+    let timer: SharedTimer = Arc::new(TimerUnit::new(TimeBase::RealTime(Instant::now())));
+    let mut t = new(timer.clone(), None);
+
+    //stopped, the poller sleeps until it is woken
+    let handle = poller(&timer, &lines, open.clone());
+    assert_eq!(timer.wait(), None);
+
+    //arming the timer wakes it up for the match, 2 ms from now
+    let armed = Instant::now();
+    t.write(0x8000001e, 2, &2000u16.to_be_bytes()).unwrap();
+    t.write(0x80000027, 1, &[(ENABLE | IRQ) as u8]).unwrap();
+    assert!(timer.wait().unwrap() <= Duration::from_millis(2));
+
+    while lines.pending() == 0 && armed.elapsed() < Duration::from_secs(5) {
+        std::thread::yield_now();
+    }
+    assert_eq!(lines.take(), 1 << TIMER_LINE);
+    assert!(armed.elapsed() >= Duration::from_millis(2));
+    assert_eq!(timer.wait(), None);
+
+    open.store(false, Ordering::Relaxed);
+    handle.thread().unpark();
+    handle.join().unwrap();
+}
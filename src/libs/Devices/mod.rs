@@ -3,6 +3,8 @@ use super::Definitions::Errors::MemError;
 pub mod Console;
 pub mod Keyboard;
 pub mod Interruptor;
+pub mod Pic;
+pub mod Timer;
 
 pub trait MemoryMapped {
 
@@ -10,4 +12,14 @@ pub trait MemoryMapped {
 
     fn write(&mut self, dir: usize, size: usize, contents: &[u8]) -> Result<(), MemError>;
 
+    /**
+     * Serializes the internal state of the device, for snapshots and core dumps
+     */
+    fn save_state(&self) -> Vec<u8> { Vec::new() }
+
+    /**
+     * Restores state produced by save_state
+     */
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), MemError> { Ok(()) }
+
 }
\ No newline at end of file
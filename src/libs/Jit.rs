@@ -0,0 +1,625 @@
+/**
+ *  Basic-block JIT, built with the "jit" feature
+ *
+ *  Core looks up a block at every PC it reaches while unprivileged. Once a block has been
+ *  looked up threshold times, its instructions are translated with Cranelift up to the
+ *  first branch or j, the end of the page, or an instruction that is left to the
+ *  interpreter:
+ *
+ *      jal, jalr and jr, which keep the shadow call stack
+ *      syscall, rfe, hlt, lhi, llo and unrecognized instructions
+ *
+ *  A translated block works on a GuestState that Core copies its registers into and back
+ *  out of, and goes through Memory for loads and stores. It stops before an instruction
+ *  the interpreter has to run instead: accesses to devices, accesses that fault and div or
+ *  divu by zero. A store to a page code was fetched from stops it after the store, and the
+ *  blocks of that page are dropped before any other runs
+ *
+ *  Core counts the retired instructions once a block returns, as step would have, and
+ *  only runs a block if no timer can fire inside it
+ */
+
+use super::Memory::Memory;
+use super::Definitions::Arch::{self, OP};
+use super::Definitions::Decode::Decoded;
+use super::Definitions::Snapshot::PAGE_SIZE;
+use super::Definitions::Timing::TimingModel;
+use super::Definitions::Utils::{self, Word};
+use crate::to_signed;
+
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value, condcodes::IntCC};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::offset_of;
+
+// longest block translated
+const MAX_BLOCK: usize = 64;
+// dropped blocks whose code is kept before the module is rebuilt
+const MAX_DEAD: usize = 4096;
+
+// results of the memory helpers
+const OK: u32 = 0;
+const BAIL: u32 = 1;
+const WROTE_CODE: u32 = 2;
+
+// variables of a translated block: the registers, then HI, LO and flags
+const VAR_HI: usize = 32;
+const VAR_LO: usize = 33;
+const VAR_FLAGS: usize = 34;
+const VARS: usize = 35;
+
+/**
+ * Architectural state a block runs on
+ */
+#[repr(C)]
+pub struct GuestState {
+    pub reg: [Word; 32],
+    pub hi: Word,
+    pub lo: Word,
+    pub flags: Word,
+    pub pc: u32,
+    pub mem: *mut Memory
+}
+
+/**
+ * An instruction of a block, with what Core needs to count it once retired
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Instr {
+    pub decoded: Decoded,
+    // branch or j
+    pub control: bool,
+    // bytes loaded or stored, 0 if none
+    pub load: usize,
+    pub store: usize
+}
+
+pub struct Block {
+    pub start: u32,
+    pub instrs: Vec<Instr>,
+    // most cycles the instructions can take, besides waiting for HI and LO when it starts
+    pub bound: u64,
+    func: extern "C" fn(*mut GuestState) -> u32
+}
+
+impl Block {
+
+    /**
+     * Runs the block, leaving the PC to continue at in state
+     *
+     * RETURNS:
+     *
+     *  how many instructions retired, from the start of the block
+     */
+    pub fn run(&self, state: &mut GuestState) -> usize {
+        (self.func)(state) as usize
+    }
+}
+
+pub struct Jit {
+    module: JITModule,
+    ctx: Context,
+    fctx: FunctionBuilderContext,
+    threshold: u32,
+    // lookups of every block start not translated yet
+    heat: HashMap<u32, u32>,
+    // None if the block starts with an instruction left to the interpreter
+    blocks: HashMap<u32, Option<Block>>,
+    // Memory::code_writes count blocks were translated against
+    clears: u64,
+    dead: usize,
+    translated: u64,
+    dropped: u64,
+    retired: u64
+}
+
+impl Jit {
+
+    /**
+     * Creates a JIT for the host, translating blocks looked up threshold times
+     */
+    pub fn new(threshold: u32) -> Result<Jit, String> {
+        let module = Jit::module()?;
+        Ok(Jit {
+            ctx: module.make_context(),
+            module,
+            fctx: FunctionBuilderContext::new(),
+            threshold: threshold.max(1),
+            heat: HashMap::new(),
+            blocks: HashMap::new(),
+            clears: 0,
+            dead: 0,
+            translated: 0,
+            dropped: 0,
+            retired: 0
+        })
+    }
+
+    fn module() -> Result<JITModule, String> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|eobj| eobj.to_string())?;
+        let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags)).map_err(|eobj| eobj.to_string())?;
+        Ok(JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())))
+    }
+
+    /**
+     * Returns the block starting at pc, translating it if it has become hot
+     *
+     * ARGS:
+     *
+     *  mem: Memory to fetch from, whose code writes drop blocks first
+     *
+     *  timing: Model to bound the cycles of new blocks with
+     */
+    pub fn lookup(&mut self, pc: u32, mem: &mut Memory, timing: &TimingModel) -> Option<&Block> {
+
+        self.sync(mem);
+
+        if !self.blocks.contains_key(&pc) {
+            let heat = self.heat.entry(pc).or_insert(0);
+            *heat += 1;
+            if *heat < self.threshold { return None; }
+
+            self.heat.remove(&pc);
+            let block = self.translate(pc, mem, timing);
+            self.blocks.insert(pc, block);
+        }
+
+        self.blocks.get(&pc)?.as_ref()
+    }
+
+    /**
+     * Counts instructions retired by blocks
+     */
+    pub fn count(&mut self, retired: usize) {
+        self.retired += retired as u64;
+    }
+
+    /**
+     * Drops every block, for when what they were translated against changes
+     */
+    pub fn flush(&mut self) {
+        self.dead += self.blocks.values().filter(|b| b.is_some()).count();
+        self.dropped += self.blocks.values().filter(|b| b.is_some()).count() as u64;
+        self.blocks.clear();
+        self.heat.clear();
+        self.collect();
+    }
+
+    /**
+     * Drops the blocks of the pages stores wrote to
+     */
+    fn sync(&mut self, mem: &mut Memory) {
+
+        let (clears, pages) = mem.code_writes();
+
+        if clears != self.clears {
+            self.clears = clears;
+            self.flush();
+            return;
+        }
+        if pages.is_empty() { return; }
+
+        let (mut dead, mut dropped) = (0, 0);
+        self.blocks.retain(|start, block| {
+            let keep = !pages.contains(&(*start / PAGE_SIZE as u32));
+            if !keep && block.is_some() { dead += 1; dropped += 1; }
+            keep
+        });
+        self.dead += dead;
+        self.dropped += dropped;
+        self.collect();
+    }
+
+    /**
+     * Rebuilds the module once enough dropped blocks are taking up its memory
+     */
+    fn collect(&mut self) {
+
+        if self.dead < MAX_DEAD { return; }
+
+        let module = match Jit::module() { Ok(module) => module, Err(_) => return };
+        self.blocks.clear();
+        self.heat.clear();
+        self.dead = 0;
+        let old = std::mem::replace(&mut self.module, module);
+        //no block is left pointing into the old module
+        unsafe { old.free_memory(); }
+    }
+
+    fn translate(&mut self, start: u32, mem: &mut Memory, timing: &TimingModel) -> Option<Block> {
+
+        if start % 4 != 0 { return None; }
+
+        let mut instrs = Vec::new();
+        let mut pc = start;
+
+        while instrs.len() < MAX_BLOCK {
+            if mem.is_device(pc) { break; }
+            let instr = match mem.fetch(pc).ok().and_then(instr) { Some(instr) => instr, None => break };
+            instrs.push(instr);
+            pc = pc.wrapping_add(4);
+            if instr.control || pc as usize % PAGE_SIZE == 0 { break; }
+        }
+
+        if instrs.is_empty() { return None; }
+
+        //if Cranelift fails, the interpreter runs it
+        let func = self.compile(start, &instrs).ok()?;
+        self.translated += 1;
+
+        Some(Block { start, bound: bound(&instrs, timing), instrs, func })
+    }
+
+    fn compile(&mut self, start: u32, instrs: &[Instr]) -> Result<extern "C" fn(*mut GuestState) -> u32, String> {
+
+        let ptr = self.module.target_config().pointer_type();
+
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.returns.push(AbiParam::new(types::I32));
+
+        let mut load_sig = self.module.make_signature();
+        load_sig.params.extend([AbiParam::new(ptr), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+        load_sig.returns.push(AbiParam::new(types::I64));
+
+        let mut store_sig = self.module.make_signature();
+        store_sig.params.extend([AbiParam::new(ptr), AbiParam::new(types::I32), AbiParam::new(types::I32), AbiParam::new(types::I32)]);
+        store_sig.returns.push(AbiParam::new(types::I32));
+
+        let id = self.module.declare_anonymous_function(&sig).map_err(|eobj| eobj.to_string())?;
+        self.ctx.func.signature = sig;
+
+        let mut b = FunctionBuilder::new(&mut self.ctx.func, &mut self.fctx);
+        let load_ref = b.import_signature(load_sig);
+        let store_ref = b.import_signature(store_sig);
+
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let state = b.block_params(entry)[0];
+
+        //every variable an instruction names is loaded on entry and stored on exit
+        let mut used = [false; VARS];
+        for i in instrs {
+            let d = &i.decoded;
+            for r in [d.rs, d.rt, d.rd].into_iter().filter(|r| *r != 0) { used[r] = true; }
+            if d.op == 0 { used[VAR_HI] = true; used[VAR_LO] = true; used[VAR_FLAGS] = true; }
+        }
+        for v in (0..VARS).filter(|v| used[*v]) {
+            let var = Variable::from_u32(v as u32);
+            b.declare_var(var, types::I32);
+            let x = b.ins().load(types::I32, MemFlags::trusted(), state, offset(v));
+            b.def_var(var, x);
+        }
+        let mem = b.ins().load(ptr, MemFlags::trusted(), state, offset_of!(GuestState, mem) as i32);
+        let exit = Exit { state, used };
+
+        let load_fn = b.ins().iconst(ptr, load as *const () as i64);
+        let store_fn = b.ins().iconst(ptr, store as *const () as i64);
+
+        for (i, instr) in instrs.iter().enumerate() {
+
+            let d = &instr.decoded;
+            let pc = start.wrapping_add(4 * i as u32);
+            let n = i as u32;
+            let rs = get(&mut b, d.rs);
+            let rt = get(&mut b, d.rt);
+
+            if d.op == 0 && d.code != OP::NOP {
+
+                // None when the interpreter leaves its result at 1, so flags clear
+                let mut res: Option<Value> = None;
+
+                match d.func {
+                    OP::R::ADD => {
+                        let (sum, diff) = (b.ins().iadd(rs, rt), b.ins().isub(rs, rt));
+                        let neg = b.ins().icmp_imm(IntCC::SignedLessThan, rt, 0);
+                        res = Some(b.ins().select(neg, diff, sum));
+                    }
+                    OP::R::ADDU => res = Some(b.ins().iadd(rs, rt)),
+                    OP::R::AND => res = Some(b.ins().band(rs, rt)),
+                    OP::R::NOR => { let or = b.ins().bor(rs, rt); res = Some(b.ins().bnot(or)); }
+                    OP::R::OR => res = Some(b.ins().bor(rs, rt)),
+                    OP::R::SUB | OP::R::SUBU => res = Some(b.ins().isub(rs, rt)),
+                    OP::R::XOR => res = Some(b.ins().bxor(rs, rt)),
+                    OP::R::SLT => {
+                        let (a, c) = (magnitude(&mut b, rs), magnitude(&mut b, rt));
+                        let lt = b.ins().icmp(IntCC::UnsignedLessThan, a, c);
+                        let x = b.ins().uextend(types::I32, lt);
+                        set(&mut b, d.rd, x);
+                    }
+                    OP::R::SLTU => {
+                        let lt = b.ins().icmp(IntCC::UnsignedLessThan, rs, rt);
+                        res = Some(b.ins().uextend(types::I32, lt));
+                    }
+                    OP::R::DIV | OP::R::DIVU => {
+                        let zero = b.ins().icmp_imm(IntCC::Equal, rt, 0);
+                        exit.bail_if(&mut b, zero, pc, n);
+                        let (q, r) = (b.ins().udiv(rs, rt), b.ins().urem(rs, rt));
+                        b.def_var(Variable::from_u32(VAR_LO as u32), q);
+                        b.def_var(Variable::from_u32(VAR_HI as u32), r);
+                    }
+                    OP::R::MULT | OP::R::MULTU => {
+                        let (x, y) = if d.func == OP::R::MULT { (magnitude(&mut b, rs), magnitude(&mut b, rt)) } else { (rs, rt) };
+                        let (x, y) = (b.ins().uextend(types::I64, x), b.ins().uextend(types::I64, y));
+                        let p = b.ins().imul(x, y);
+                        let high = b.ins().ushr_imm(p, 32);
+                        //widening_mul gives (low, high), assigned to (HI, LO)
+                        let (low, high) = (b.ins().ireduce(types::I32, p), b.ins().ireduce(types::I32, high));
+                        b.def_var(Variable::from_u32(VAR_HI as u32), low);
+                        b.def_var(Variable::from_u32(VAR_LO as u32), high);
+                    }
+                    OP::R::SLL => res = Some(b.ins().ishl_imm(rt, d.sham as i64)),
+                    OP::R::SRA => res = Some(b.ins().sshr_imm(rt, d.sham as i64)),
+                    OP::R::SRAV => res = Some(b.ins().sshr(rt, rs)),
+                    OP::R::SRLV => res = Some(b.ins().ushr(rt, rs)),
+                    OP::R::MFHI => { let x = b.use_var(Variable::from_u32(VAR_HI as u32)); set(&mut b, d.rd, x); }
+                    OP::R::MFLO => { let x = b.use_var(Variable::from_u32(VAR_LO as u32)); set(&mut b, d.rd, x); }
+                    OP::R::MTHI => b.def_var(Variable::from_u32(VAR_HI as u32), rs),
+                    OP::R::MTLO => b.def_var(Variable::from_u32(VAR_LO as u32), rs),
+                    _ => unreachable!()
+                }
+
+                if let Some(res) = res { set(&mut b, d.rd, res); }
+
+                //Z and S follow the result, as set_flag does
+                let flags = b.use_var(Variable::from_u32(VAR_FLAGS as u32));
+                let mut flags = b.ins().band_imm(flags, !(Arch::Z_FLAG | Arch::S_FLAG) as i64);
+                if let Some(res) = res {
+                    let (z, s, none) = (konst(&mut b, Arch::Z_FLAG), konst(&mut b, Arch::S_FLAG), konst(&mut b, 0));
+                    let is_zero = b.ins().icmp_imm(IntCC::Equal, res, 0);
+                    let is_neg = b.ins().icmp_imm(IntCC::SignedLessThan, res, 0);
+                    let (z, s) = (b.ins().select(is_zero, z, none), b.ins().select(is_neg, s, none));
+                    flags = b.ins().bor(flags, z);
+                    flags = b.ins().bor(flags, s);
+                }
+                b.def_var(Variable::from_u32(VAR_FLAGS as u32), flags);
+
+            } else if d.op != 0 {
+
+                let imm = d.imm as i64;
+
+                match d.op {
+                    OP::I::ADDI => {
+                        let x = if d.imm & 0x8000 == 0 { b.ins().iadd_imm(rs, imm) } else { let k = konst(&mut b, d.imm); b.ins().isub(rs, k) };
+                        set(&mut b, d.rt, x);
+                    }
+                    OP::I::ADDIU => { let x = b.ins().iadd_imm(rs, imm); set(&mut b, d.rt, x); }
+                    OP::I::ANDI => { let x = b.ins().band_imm(rs, imm); set(&mut b, d.rt, x); }
+                    OP::I::ORI => { let x = b.ins().bor_imm(rs, imm); set(&mut b, d.rt, x); }
+                    OP::I::XORI => { let x = b.ins().bxor_imm(rs, imm); set(&mut b, d.rt, x); }
+                    OP::I::SLTI | OP::I::SLTIU => {
+                        let lt = b.ins().icmp_imm(IntCC::UnsignedLessThan, rs, imm);
+                        let x = b.ins().uextend(types::I32, lt);
+                        set(&mut b, d.rt, x);
+                    }
+                    OP::I::BEQ | OP::I::BNE | OP::I::BGTZ | OP::I::BLEZ => {
+                        let taken = match d.op {
+                            OP::I::BEQ => b.ins().icmp(IntCC::Equal, rs, rt),
+                            OP::I::BNE => b.ins().icmp(IntCC::NotEqual, rs, rt),
+                            OP::I::BGTZ => b.ins().icmp_imm(IntCC::UnsignedGreaterThan, rs, 0),
+                            _ => b.ins().icmp(IntCC::UnsignedLessThanOrEqual, rs, rt)
+                        };
+                        let target = if d.imm & 0x8000 == 0 { pc.overflowing_add(d.imm << 2).0 } else { pc.overflowing_sub(to_signed!(d.imm << 2, u16)).0 };
+                        let (target, next) = (konst(&mut b, target.wrapping_add(4)), konst(&mut b, pc.wrapping_add(4)));
+                        let to = b.ins().select(taken, target, next);
+                        exit.ret(&mut b, to, n + 1);
+                        break;
+                    }
+                    OP::J::J => {
+                        let to = konst(&mut b, if d.target != 0 { d.target } else { 4 });
+                        exit.ret(&mut b, to, n + 1);
+                        break;
+                    }
+                    _ if instr.load > 0 => {
+                        let addr = b.ins().iadd_imm(rs, imm);
+                        let size = konst(&mut b, instr.load as u32);
+                        let call = b.ins().call_indirect(load_ref, load_fn, &[mem, addr, size]);
+                        let r = b.inst_results(call)[0];
+                        let failed = b.ins().ushr_imm(r, 32);
+                        exit.bail_if(&mut b, failed, pc, n);
+                        let x = b.ins().ireduce(types::I32, r);
+                        set(&mut b, d.rt, x);
+                    }
+                    _ => {
+                        let addr = b.ins().iadd_imm(rs, imm);
+                        let size = konst(&mut b, instr.store as u32);
+                        //sw stores its low byte four times, see handoff_I
+                        let bytes = if instr.store == 4 {
+                            let low = b.ins().band_imm(rt, 0xff);
+                            b.ins().imul_imm(low, 0x01010101)
+                        } else { rt };
+                        let call = b.ins().call_indirect(store_ref, store_fn, &[mem, addr, size, bytes]);
+                        let status = b.inst_results(call)[0];
+                        let failed = b.ins().icmp_imm(IntCC::Equal, status, BAIL as i64);
+                        exit.bail_if(&mut b, failed, pc, n);
+                        let wrote = b.ins().icmp_imm(IntCC::Equal, status, WROTE_CODE as i64);
+                        let (stop, cont) = (b.create_block(), b.create_block());
+                        b.ins().brif(wrote, stop, &[], cont, &[]);
+                        b.seal_block(stop);
+                        b.seal_block(cont);
+                        b.switch_to_block(stop);
+                        let to = konst(&mut b, pc.wrapping_add(4));
+                        exit.ret(&mut b, to, n + 1);
+                        b.switch_to_block(cont);
+                    }
+                }
+            }
+
+            if i + 1 == instrs.len() {
+                let to = konst(&mut b, pc.wrapping_add(4));
+                exit.ret(&mut b, to, n + 1);
+            }
+        }
+
+        b.finalize();
+
+        let res = self.module.define_function(id, &mut self.ctx).map_err(|eobj| eobj.to_string());
+        self.module.clear_context(&mut self.ctx);
+        res?;
+        self.module.finalize_definitions().map_err(|eobj| eobj.to_string())?;
+
+        let code = self.module.get_finalized_function(id);
+        Ok(unsafe { std::mem::transmute::<*const u8, extern "C" fn(*mut GuestState) -> u32>(code) })
+    }
+}
+
+impl fmt::Display for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JIT:         {} blocks translated, {} dropped, {} instructions run translated", self.translated, self.dropped, self.retired)
+    }
+}
+
+/**
+ * Returns the block instruction for d, None if it is left to the interpreter
+ */
+fn instr(d: Decoded) -> Option<Instr> {
+
+    let (load, store) = match d.op {
+        OP::I::LB | OP::I::LBU => (1, 0),
+        OP::I::LH | OP::I::LHU => (2, 0),
+        OP::I::LW => (4, 0),
+        OP::I::SB => (0, 1),
+        OP::I::SH => (0, 2),
+        OP::I::SW => (0, 4),
+        _ => (0, 0)
+    };
+
+    let translated = match d.op {
+        0 => d.code == OP::NOP || matches!(d.func,
+            OP::R::ADD | OP::R::ADDU | OP::R::AND | OP::R::NOR | OP::R::OR | OP::R::SUB | OP::R::SUBU | OP::R::XOR
+            | OP::R::SLT | OP::R::SLTU | OP::R::DIV | OP::R::DIVU | OP::R::MULT | OP::R::MULTU
+            | OP::R::SLL | OP::R::SRA | OP::R::SRAV | OP::R::SRLV | OP::R::MFHI | OP::R::MFLO | OP::R::MTHI | OP::R::MTLO),
+        OP::J::J => true,
+        op => load + store > 0 || matches!(op,
+            OP::I::ADDI | OP::I::ADDIU | OP::I::ANDI | OP::I::ORI | OP::I::XORI | OP::I::SLTI | OP::I::SLTIU
+            | OP::I::BEQ | OP::I::BNE | OP::I::BGTZ | OP::I::BLEZ)
+    };
+
+    translated.then_some(Instr { decoded: d, control: d.branch || d.op == OP::J::J, load, store })
+}
+
+/**
+ * Most cycles the timing model can charge for instrs, given that any wait for HI and LO
+ * when the block starts is added on top
+ */
+fn bound(instrs: &[Instr], timing: &TimingModel) -> u64 {
+
+    //a HI/LO instruction waits at most for the multiply or divide before it in the block
+    let hilo = timing.mult.max(timing.div);
+
+    instrs.iter().map(|i| {
+        let mnemonic = i.decoded.mnemonic;
+        let mut cycles = timing.latencies[i.decoded.opcode] + timing.load;
+        if matches!(mnemonic, "mult" | "multu" | "div" | "divu" | "mfhi" | "mflo" | "mthi" | "mtlo") { cycles += hilo; }
+        if i.control { cycles += timing.branch; }
+        if i.load + i.store > 0 { cycles += timing.mem; }
+        cycles
+    }).sum()
+}
+
+fn offset(v: usize) -> i32 {
+    (match v {
+        VAR_HI => offset_of!(GuestState, hi),
+        VAR_LO => offset_of!(GuestState, lo),
+        VAR_FLAGS => offset_of!(GuestState, flags),
+        r => offset_of!(GuestState, reg) + 4 * r
+    }) as i32
+}
+
+fn konst(b: &mut FunctionBuilder, x: u32) -> Value {
+    b.ins().iconst(types::I32, x as i64)
+}
+
+fn get(b: &mut FunctionBuilder, r: usize) -> Value {
+    if r == 0 { konst(b, 0) } else { b.use_var(Variable::from_u32(r as u32)) }
+}
+
+/**
+ * Writes register r, $zero staying 0 as step leaves it
+ */
+fn set(b: &mut FunctionBuilder, r: usize, x: Value) {
+    if r != 0 { b.def_var(Variable::from_u32(r as u32), x); }
+}
+
+/**
+ * x if it is positive, -x otherwise, like to_signed_cond!
+ */
+fn magnitude(b: &mut FunctionBuilder, x: Value) -> Value {
+    let neg = b.ins().icmp_imm(IntCC::SignedLessThan, x, 0);
+    let minus = b.ins().ineg(x);
+    b.ins().select(neg, minus, x)
+}
+
+/**
+ * Ways out of a block, storing its variables back
+ */
+struct Exit {
+    state: Value,
+    used: [bool; VARS]
+}
+
+impl Exit {
+
+    fn ret(&self, b: &mut FunctionBuilder, pc: Value, retired: u32) {
+        for v in (0..VARS).filter(|v| self.used[*v]) {
+            let x = b.use_var(Variable::from_u32(v as u32));
+            b.ins().store(MemFlags::trusted(), x, self.state, offset(v));
+        }
+        b.ins().store(MemFlags::trusted(), pc, self.state, offset_of!(GuestState, pc) as i32);
+        let n = b.ins().iconst(types::I32, retired as i64);
+        b.ins().return_(&[n]);
+    }
+
+    /**
+     * Leaves the block before the instruction at pc if cond is not zero
+     */
+    fn bail_if(&self, b: &mut FunctionBuilder, cond: Value, pc: u32, retired: u32) {
+        let (bail, cont) = (b.create_block(), b.create_block());
+        b.ins().brif(cond, bail, &[], cont, &[]);
+        b.seal_block(bail);
+        b.seal_block(cont);
+        b.switch_to_block(bail);
+        let to = konst(b, pc);
+        self.ret(b, to, retired);
+        b.switch_to_block(cont);
+    }
+}
+
+/**
+ * Loads for blocks, returning the value or BAIL in the upper half
+ */
+extern "C" fn load(mem: *mut Memory, addr: u32, size: u32) -> u64 {
+
+    let mem = unsafe { &mut *mem };
+    if mem.is_device(addr) { return (BAIL as u64) << 32; }
+
+    match mem.load(addr, size as usize) {
+        Ok(contents) => (match size { 1 => Utils::from_byte(contents), 2 => Utils::from_half(contents), _ => Utils::from_word(contents) }) as u64,
+        Err(_) => (BAIL as u64) << 32
+    }
+}
+
+/**
+ * Stores for blocks, bytes holding what to store in its last size bytes, big-endian
+ */
+extern "C" fn store(mem: *mut Memory, addr: u32, size: u32, bytes: u32) -> u32 {
+
+    let mem = unsafe { &mut *mem };
+    if mem.is_device(addr) { return BAIL; }
+
+    let bytes = bytes.to_be_bytes();
+    match mem.store(addr as usize, size as usize, &bytes[4 - size as usize..]) {
+        Ok(()) if mem.wrote_code() => WROTE_CODE,
+        Ok(()) => OK,
+        Err(_) => BAIL
+    }
+}
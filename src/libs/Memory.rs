@@ -1,8 +1,13 @@
 use super::Definitions::RELFHeaders::{RelfHeader32,SectionHeader32};
 use super::Definitions::Errors::{HeaderError, MemError};
 use super::Definitions::Utils::{Byte, Half, Word};
+use super::Definitions::Snapshot::PAGE_SIZE;
+use super::Definitions::Decode::{self, Decoded, DecodeCache};
+use super::Definitions::Utils;
 use super::Devices::MemoryMapped;
 
+use crate::{log, log_enabled};
+
 use std::fs::File;
 use std::io::Read;
 use std::io;
@@ -14,10 +19,11 @@ pub struct Memory {
     mem_array: Vec<Byte>,
     mem_size: usize,
     mode_privilege: bool,
-    verbose: bool,
     protected_ranges: Vec<(u32, u32)>,
     devices: Vec<(u32, u32, Box<dyn MemoryMapped>)>,
-    
+    // lowest and highest address of any device, so other accesses skip the device list
+    device_window: (u32, u32),
+    decoded: DecodeCache,
 }
 
 
@@ -25,20 +31,17 @@ pub struct Memory {
 
     /**
      * Initializes the memory object
-     * 
-     * ARGS:
-     * 
-     *  v: Verbose flag
      * **/
-    pub fn new( v: bool) -> Memory{
+    pub fn new() -> Memory{
     
         Memory {
              mem_array: vec![0;0],
              mem_size: 0,
              protected_ranges: Vec::<(u32, u32)>::new(),
              mode_privilege: false,
-             verbose: v, 
-             devices: Vec::<(u32, u32, Box<dyn MemoryMapped>)>::new()
+             devices: Vec::<(u32, u32, Box<dyn MemoryMapped>)>::new(),
+             device_window: (u32::MAX, 0),
+             decoded: DecodeCache::default()
             }
     }
 
@@ -54,9 +57,10 @@ pub struct Memory {
      */
     pub fn protect(&mut self, proct_low: u32, proct_high: u32) {
 
-        if self.verbose { println!("[MEM]: Protecting range [0x{:08x}..0x{:08x}]", proct_low, proct_high); }
+        log!(Info, "MEM", "Protecting range [0x{:08x}..0x{:08x}]", proct_low, proct_high);
 
         self.protected_ranges.push( (proct_low, proct_high) );
+        self.decoded.clear();
     }
 
     /**
@@ -67,16 +71,18 @@ pub struct Memory {
      *  m: The new level of permission
      */
     pub fn set_privileged(&mut self,m: bool) {
-        if self.verbose { println!("[MEM]: Changed privilege mode to {}", m); }
+        log!(Debug, "MEM", "Changed privilege mode to {}", m);
         self.mode_privilege = m;
     }
     
 
     pub fn map_device(&mut self, range_lower: u32, range_upper: u32, device: Box<dyn MemoryMapped>) {
 
-        if self.verbose { println!("[MEM]: Mapping device to range [0x{:08x}..0x{:08x}]", range_lower, range_upper); }
+        log!(Info, "MEM", "Mapping device to range [0x{:08x}..0x{:08x}]", range_lower, range_upper);
 
         self.devices.push( (range_lower, range_upper, device) );
+        self.device_window = (self.device_window.0.min(range_lower), self.device_window.1.max(range_upper));
+        self.decoded.clear();
 
     }
 
@@ -94,7 +100,7 @@ pub struct Memory {
         self.mem_array.extend(&vec![0; if alloc > 4 {alloc} else {4} ]);
 
         self.mem_size += alloc;
-        if self.verbose { println!("[MEM]: extend_mem adding {} bytes. New size is: {}. Highest address is: 0x{:x}",alloc,self.mem_size,self.mem_size) }
+        log!(Trace, "MEM", "extend_mem adding {} bytes. New size is: {}. Highest address is: 0x{:x}",alloc,self.mem_size,self.mem_size);
     }
 
 
@@ -110,8 +116,9 @@ pub struct Memory {
         //fast track, avoid Vec::resize at all costs
         self.mem_array = vec![0; alloc];
         self.mem_size = alloc;
+        self.decoded.clear();
 
-        if self.verbose { println!("[MEM]: extend_mem_FAST adding {} bytes. New size is: {}. Highest address is: 0x{:x}",alloc,self.mem_size,self.mem_size) }
+        log!(Debug, "MEM", "extend_mem_FAST adding {} bytes. New size is: {}. Highest address is: 0x{:x}",alloc,self.mem_size,self.mem_size);
     }
 
     /**
@@ -144,34 +151,117 @@ pub struct Memory {
 
         let d = dir as usize;
 
-        //TODO: extend mem *after* the device check so we don't extend if not necessary
-        //fake having a 4GB memory by dynamically extending on "OOB" accesses
-        if d+size > self.mem_size { self.extend_mem(d+size-self.mem_size); }
-        
+        //check if in range of a device
+        let device = match self.is_device(dir) {
+            true => self.devices.iter().position(|(lower, upper, _)| dir >= *lower && dir <= *upper),
+            false => None
+        };
+        if let Some(dev) = device {
 
-        for (dev_lower, dev_upper, device) in & mut self.devices {
-            
+            log!(Trace, "MEM", "Read access to Memory Mapped Device at address 0x{:08x}; Handing off...", dir);
 
-            //check if in range of a device
-            if dir >= *dev_lower && dir <= *dev_upper { 
-
-                if self.verbose { println!("[MEM]: Read access to Memory Mapped Device at address 0x{:08x}; Handing off...", dir); }
+            contents = self.devices[dev].2.read(dir, size)?;
+            return Ok(contents);
+        }
 
-                contents =  device.read(dir, size)? ;
-                return Ok(contents);
-            }
+        //fake having a 4GB memory by dynamically extending on "OOB" accesses; reading a device never extends
+        if d+size > self.mem_size { self.extend_mem(d+size-self.mem_size); }
 
-        }
-        
         //get pointer to slice
         contents = &self.mem_array[d..d+size]; 
 
-        if self.verbose { println!("[MEM]: loading: align={} dir={:08x?} contents={:x?}",size,dir,contents); }
+        log!(Trace, "MEM", "loading: align={} dir={:08x?} contents={:x?}",size,dir,contents);
 
         
         Ok(contents)
     }
 
+    /**
+     * Fetches and decodes the instruction at dir
+     *
+     * Instructions are decoded once and kept until a store to their page. Words
+     * belonging to devices or in unaligned addresses always go through load, and so
+     * does everything when memory accesses are traced, to keep their logs
+     *
+     * ARGS:
+     *
+     *  dir: memory address of the instruction
+     *
+     * RETURNS:
+     *
+     *  the decoded instruction, or the error load would have given
+    */
+    #[inline(always)]
+    pub fn fetch(&mut self, dir: u32) -> Result<Decoded, MemError> {
+
+        let traced = log_enabled!(Trace, "MEM");
+
+        if !traced {
+            if let Some((decoded, protected)) = self.decoded.get(dir) {
+                if !protected || self.mode_privilege { return Ok(decoded); }
+            }
+        }
+
+        let decoded = Decode::decode(Utils::from_word(self.load(dir, 4)?));
+
+        if !traced && !self.is_device(dir) {
+            let protected = self.protected_ranges.iter().any(|(lo, hi)| dir < *hi && dir >= *lo);
+            self.decoded.insert(dir, decoded, protected);
+        }
+
+        Ok(decoded)
+    }
+
+    /**
+     * Starts or stops keeping the code pages written to, see code_writes
+    */
+    #[allow(dead_code)]
+    pub fn track_code_writes(&mut self, on: bool) {
+        self.decoded.track(on);
+    }
+
+    /**
+     * True if a store has written to fetched code since the last call to code_writes
+    */
+    #[allow(dead_code)]
+    pub fn wrote_code(&self) -> bool {
+        self.decoded.has_dropped()
+    }
+
+    /**
+     * Returns the pages of fetched code written to since the last call, and a count
+     * that changes whenever everything fetched so far may have changed
+    */
+    #[allow(dead_code)]
+    pub fn code_writes(&mut self) -> (u64, Vec<u32>) {
+        self.decoded.take_dropped()
+    }
+
+    /**
+     * Returns a slice of backing memory without side effects
+     *
+     * Unlike load, this ignores protected ranges and mapped devices and never
+     * extends memory, so it is safe to use for inspecting a halted machine
+     *
+     * ARGS:
+     *
+     *  dir: memory address to return
+     *
+     *  size: amount of bytes to return after dir
+     *
+     * RETURNS:
+     *
+     *  pointer to slice, or None if the range was never allocated
+    */
+    pub fn peek(&self, dir: u32, size: usize) -> Option<&[Byte]> {
+
+        let d = dir as usize;
+
+        if d+size > self.mem_array.len() { return None }
+
+        Some(&self.mem_array[d..d+size])
+    }
+
     /**
      * Stores size bytes of contents in memory address dir
      * 
@@ -200,7 +290,8 @@ pub struct Memory {
         }
         
 
-        for elem in & mut self.devices {
+        let devices = if self.is_device(d) { &mut self.devices[..] } else { &mut [] };
+        for elem in devices {
             
             let dev_lower = elem.0;
             let dev_upper = elem.1;
@@ -208,7 +299,7 @@ pub struct Memory {
             //check if in range of a device
             if d >= dev_lower && d <= dev_upper { 
 
-                if self.verbose { println!("[MEM]: Write access to Memory Mapped Device at address 0x{:08x} with contents {:?}; Handing off...", dir,contents); }
+                log!(Trace, "MEM", "Write access to Memory Mapped Device at address 0x{:08x} with contents {:?}; Handing off...", dir,contents);
 
                 elem.2.write(dir, size, contents)?;
 
@@ -218,8 +309,9 @@ pub struct Memory {
 
         //extend dynamically
         if dir+size >= self.mem_size { self.extend_mem(dir+size - self.mem_size);}
+        self.decoded.invalidate(dir, size);
 
-        if self.verbose { println!("[MEM]: storing: align={} dir={:08x?} contents={:02x?}", size, dir, contents); }
+        log!(Trace, "MEM", "storing: align={} dir={:08x?} contents={:02x?}", size, dir, contents);
         // copy into mem array, consume elements
         let mut to_insert: Byte;
         let mut byte: usize = 0;
@@ -240,6 +332,91 @@ pub struct Memory {
 
     }
 
+    /**
+     * Returns true if addr is between the lowest and highest address of the mapped
+     * devices, which are mapped next to each other
+     */
+    #[inline(always)]
+    pub fn is_device(&self, addr: u32) -> bool {
+        addr >= self.device_window.0 && addr <= self.device_window.1
+    }
+
+    /**
+     * Returns the protected address ranges
+    */
+    pub fn protected_ranges(&self) -> &[(u32, u32)] {
+        &self.protected_ranges
+    }
+
+    /**
+     * Replaces memory contents and protected ranges with empty ones, keeping mapped devices
+    */
+    pub fn reset(&mut self) {
+        self.mem_array = vec![0;0];
+        self.mem_size = 0;
+        self.protected_ranges.clear();
+        self.decoded.clear();
+    }
+
+    /**
+     * Returns every PAGE_SIZE page of memory that holds a non-zero byte
+     *
+     * RETURNS:
+     *
+     *  (address, contents) of each page, in increasing address order. The last
+     *  page may be shorter than PAGE_SIZE
+    */
+    pub fn pages(&self) -> Vec<(u32, &[Byte])> {
+
+        self.mem_array.chunks(PAGE_SIZE)
+            .enumerate()
+            .filter(|(_, page)| page.iter().any(|b| *b != 0))
+            .map(|(i, page)| ((i * PAGE_SIZE) as u32, page))
+            .collect()
+    }
+
+    /**
+     * Writes contents at dir, extending memory as needed
+     *
+     * Note that this ignores reserved ranges and mapped devices, like load_bin
+     *
+     * ARGS:
+     *
+     *  dir: memory address to write to
+     *
+     *  contents: bytes to write
+    */
+    pub fn restore(&mut self, dir: u32, contents: &[Byte]) {
+
+        let d = dir as usize;
+
+        if d+contents.len() > self.mem_array.len() { self.extend_mem(d+contents.len()-self.mem_array.len()); }
+
+        self.mem_array[d..d+contents.len()].copy_from_slice(contents);
+        self.decoded.invalidate(d, contents.len());
+    }
+
+    /**
+     * Returns the address range and saved state of every mapped device
+    */
+    pub fn device_states(&self) -> Vec<(u32, u32, Vec<u8>)> {
+        self.devices.iter().map(|(lower, upper, dev)| (*lower, *upper, dev.save_state())).collect()
+    }
+
+    /**
+     * Restores the state of the device mapped at exactly lower..upper
+    */
+    pub fn restore_device_state(&mut self, lower: u32, upper: u32, state: &[u8]) -> Result<(), MemError> {
+
+        for (dev_lower, dev_upper, device) in &mut self.devices {
+            if *dev_lower == lower && *dev_upper == upper {
+                return device.load_state(state);
+            }
+        }
+
+        Err(MemError::MappedDeviceError(format!("No device is mapped to range [0x{:08x}..0x{:08x}]", lower, upper)))
+    }
+
     /** 
      * Loads a binary file byte by byte into memory starting from 0x00000000
      * 
@@ -250,16 +427,19 @@ pub struct Memory {
         File::open(bin)?.read_to_end(& mut fBuffer)?;
         
         //read contents of file into buffer
+        self.load_image(&fBuffer);
 
+        Ok(())
+    }
 
-        //raw copy into mem
-        self.extend_mem_FAST(fBuffer.len());
-        
-        for (offset, b) in fBuffer.iter().enumerate() {
-            self.mem_array[offset] = *b
-        }
+    /**
+     * Replaces memory with image, starting from 0x00000000, like load_bin
+     */
+    pub fn load_image(&mut self, image: &[Byte]) {
 
-        Ok(())
+        //raw copy into mem
+        self.extend_mem_FAST(image.len());
+        self.mem_array.copy_from_slice(image);
     }
 
 
@@ -274,9 +454,7 @@ pub struct Memory {
         // unpack
         let relf_header: RelfHeader32 = structure!(">I5B7s2H5I6H").unpack(&fBuffer[0..52])?.into();
 
-        if self.verbose {
-            println!("found RelfHeader32!:\n\t{:x?}",relf_header);
-        }
+        log!(Debug, "MEM", "found RelfHeader32!:\n\t{:x?}", relf_header);
 
         //sanity checks
         if relf_header.e_ident_MAG != 0x7f454c46 { return Err(HeaderError::MagicError) }
@@ -292,9 +470,7 @@ pub struct Memory {
         //unpack
         let prog_header: SectionHeader32 = structure!(">8I").unpack(&fBuffer[52..(52+relf_header.e_phentsize) as usize])?.into();
 
-        if self.verbose {
-            println!("found PROGRAM SectionHeader32!:\n\t{:x?}",prog_header);
-        }
+        log!(Debug, "MEM", "found PROGRAM SectionHeader32!:\n\t{:x?}", prog_header);
 
         if prog_header.p_flags != 0x05000000 { return Err(HeaderError::PermExecError(String::from("The text segment is not Readable and Executable"))) }
         //assert_eq!(prog_header.p_flags,0x05000000,"This text segment is not Readable and Executable"); // Readable, Executable
@@ -306,9 +482,7 @@ pub struct Memory {
         let data_header: SectionHeader32 = structure!(">8I").unpack(&fBuffer[(52+relf_header.e_phentsize as usize)..(52+2*relf_header.e_phentsize as usize)])?.into();
 
 
-        if self.verbose {
-            println!("found DATA SectionHeader32!:\n\t{:x?}",data_header);
-        }
+        log!(Debug, "MEM", "found DATA SectionHeader32!:\n\t{:x?}", data_header);
 
         //sanity checks
         if data_header.p_flags != 0x06000000 { return Err(HeaderError::PermExecError(String::from("The data segment is not Readable and Writeable"))) }
@@ -336,6 +510,7 @@ pub struct Memory {
 
         //We CANNOT use extend_mem_FAST because it'll overwrite the default irqH. only allowed in load_bin because we don't care there
         self.extend_mem(to_alloc);
+        self.decoded.clear();
         //if the data segment exists, load it
 
         //copy to memory
@@ -368,7 +543,7 @@ pub struct Memory {
 #[test]
 fn loading() {
 
-    let mut m: Memory = Memory::new(true);
+    let mut m: Memory = Memory::new();
     match m.load_RELF("testbins/parsing_more.s.relf") {
         Ok(ept) => assert_eq!(ept,0x00400000),
         Err(eobj) => panic!("{eobj}")
@@ -376,7 +551,7 @@ fn loading() {
     
     drop(m);
 
-    let mut m: Memory = Memory::new(false);
+    let mut m: Memory = Memory::new();
     match m.load_RELF("testbins/testingLS.s.relf") {
         Ok(ept) => assert_eq!(ept,0x00400000),
         Err(eobj) => panic!("{eobj}")
@@ -386,7 +561,7 @@ fn loading() {
 
 #[test]
 fn load_store() {
-    let mut m = Memory::new(true);
+    let mut m = Memory::new();
 
     //store as word...
     //also implicitly extends mem dynamically
@@ -401,14 +576,14 @@ fn load_store() {
 #[test]
 fn extend_mem_all() {
 
-    let mut m: Memory = Memory::new(true);
+    let mut m: Memory = Memory::new();
 
     m.extend_mem_FAST(0x700000);
 
     assert_eq!(m.mem_size, m.mem_array.len());
     assert_eq!(m.mem_array.len(), 0x700000);
 
-    m = Memory::new(true);
+    m = Memory::new();
 
     m.extend_mem(0x80);
 
@@ -421,7 +596,7 @@ fn extend_mem_all() {
 #[should_panic]
 fn unprivileged_protected_access() {
 
-    let mut m: Memory = Memory::new(true);
+    let mut m: Memory = Memory::new();
 
     m.extend_mem_FAST(0x0000ff00);
     m.protect(0,0x0000fC00);
@@ -431,7 +606,7 @@ fn unprivileged_protected_access() {
 
 
     //access to protected -> panic
-    let mut m2: Memory = Memory::new(true);
+    let mut m2: Memory = Memory::new();
     m2.extend_mem_FAST(0x0000ff00);
     m2.protect(0,0x0000fC00);
     m2.store(0x0000AA00, 4, got).unwrap();
@@ -440,7 +615,7 @@ fn unprivileged_protected_access() {
 #[test]
 fn privileged_protected_access() {
 
-       let mut m: Memory = Memory::new(true);
+       let mut m: Memory = Memory::new();
 
        m.extend_mem_FAST(0x0000ff00);
        m.protect(0,0x0000fC00);
@@ -452,11 +627,47 @@ fn privileged_protected_access() {
     
 }
 
+#[test]
+fn predecoded_fetch() {
+
+    let mut m: Memory = Memory::new();
+
+    m.store(0x1000, 4, &[0x24, 0x02, 0x00, 0x01]).unwrap(); //addiu $v0, $zero, 1
+    m.protect(0x1000, 0x1004);
+    m.set_privileged(true);
+    assert_eq!(m.fetch(0x1000).unwrap().imm, 1);
+
+    //decoded while privileged, still refused after dropping privilege
+    m.set_privileged(false);
+    assert!(m.fetch(0x1000).is_err());
+
+    //stores drop the decoded instruction
+    m.set_privileged(true);
+    m.store(0x1002, 2, &[0x00, 0x02]).unwrap();
+    assert_eq!(m.fetch(0x1000).unwrap().imm, 2);
+    m.restore(0x1002, &[0x00, 0x03]);
+    assert_eq!(m.fetch(0x1000).unwrap().imm, 3);
+}
+
+#[test]
+fn pages_and_restore() {
+
+    let mut m: Memory = Memory::new();
+
+    m.restore(0x2001, &[7, 8]);
+    m.restore(0x5000, &[9]);
+
+    let pages = m.pages();
+    assert_eq!(pages.len(), 2);
+    assert_eq!((pages[0].0, &pages[0].1[1..3]), (0x2000, &[7u8, 8][..]));
+    assert_eq!(pages[1].0, 0x5000);
+}
+
 #[test]
 fn device_access() {
     use super::Devices;
 
-    let mut m: Memory = Memory::new(true);
+    let mut m: Memory = Memory::new();
     let c = Box::new(Devices::Console::new() );
     let k = Box::new(Devices::Keyboard::new() );
 
@@ -469,4 +680,7 @@ fn device_access() {
     //store (mode) from Keyboard
     m.store(0x8000000c, 1, &[1]).unwrap();
 
+    //the devices span one window
+    assert!(m.is_device(0x80000000) && m.is_device(0x8000000f));
+    assert!(!m.is_device(0x7fffffff) && !m.is_device(0x80000010));
 }
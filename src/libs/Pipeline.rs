@@ -0,0 +1,365 @@
+use super::Core::Core;
+use super::Definitions::Decode::{self, Decoded};
+use super::Definitions::Disasm;
+use super::Definitions::Errors::ExecutionError;
+
+use std::io;
+use std::io::Write;
+
+pub const STAGES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+const IF: usize = 0;
+const ID: usize = 1;
+const EX: usize = 2;
+const MEM: usize = 3;
+const WB: usize = 4;
+
+const LOADS: [usize; 5] = [Disasm::index("lb"), Disasm::index("lbu"), Disasm::index("lh"), Disasm::index("lhu"), Disasm::index("lw")];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotKind {
+    Instr,
+    // inserted into EX while an instruction waits in ID
+    Bubble,
+    // fetched down the wrong path after a branch, jump or exception, flushed when it resolves
+    Squashed
+}
+
+/**
+ * What a pipeline stage holds during a cycle
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+    // fetch order, shared by instructions and squashed slots
+    pub seq: u64,
+    pub pc: u32,
+    pub decoded: Decoded,
+    pub kind: SlotKind,
+    // the instruction changed the flow of control, so the slots fetched after it are squashed
+    pub redirect: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineConfig {
+    // forward EX/MEM and MEM/WB results to EX, so only load-use hazards stall
+    pub forwarding: bool,
+    // keep a CycleRecord per cycle
+    pub record: bool
+}
+
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        PipelineConfig { forwarding: true, record: false }
+    }
+}
+
+/**
+ * Pipeline occupancy during one cycle
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleRecord {
+    pub cycle: u64,
+    pub stages: [Option<Slot>; 5],
+    // the instruction in ID waited this cycle
+    pub stalled: bool,
+    // wrong path slots flushed this cycle
+    pub flushed: u64
+}
+
+/**
+ * Five-stage IF/ID/EX/MEM/WB pipeline model driving a Core
+ *
+ * The Core executes each instruction as it is fetched, so architectural results are
+ * exactly those of Core::run. The pipeline only decides when instructions move: an
+ * instruction in ID waits while an older one in EX or MEM has yet to produce a register
+ * it reads (with forwarding, only when the one in EX is a load), and a branch, jump or
+ * exception is resolved in EX, flushing the two slots fetched after it. Register writes
+ * happen in the first half of WB, so an instruction in ID never waits for WB
+ */
+pub struct Pipeline {
+    config: PipelineConfig,
+    stages: [Option<Slot>; 5],
+    cycle: u64,
+    retired: u64,
+    stalls: u64,
+    flushed: u64,
+    // cycles each stage held an instruction
+    busy: [u64; 5],
+    history: Vec<CycleRecord>,
+    next_seq: u64,
+    // PC of the next wrong path fetch while a redirect is unresolved
+    wrong_path: Option<u32>,
+    // the Core finished or faulted, nothing more is fetched
+    done: bool,
+    fault: Option<ExecutionError>
+}
+
+impl Pipeline {
+
+    pub fn new(config: PipelineConfig) -> Pipeline {
+        Pipeline {
+            config, stages: [None; 5], cycle: 0, retired: 0, stalls: 0, flushed: 0, busy: [0; 5],
+            history: Vec::new(), next_seq: 0, wrong_path: None, done: false, fault: None
+        }
+    }
+
+    /**
+     * Runs core until it finishes and the pipeline drains
+     *
+     * RETURNS:
+     *
+     *  The fault of the Core, once the instructions before it have drained
+     */
+    pub fn run(&mut self, core: &mut Core) -> Result<(), ExecutionError> {
+        while !self.cycle_once(core) {}
+        match self.fault.take() {
+            Some(eobj) => Err(eobj),
+            None => Ok(())
+        }
+    }
+
+    /**
+     * Advances the pipeline by one cycle
+     *
+     * RETURNS:
+     *
+     *  true once the Core is done and the pipeline is empty
+     */
+    pub fn cycle_once(&mut self, core: &mut Core) -> bool {
+
+        self.cycle += 1;
+
+        let stalled = self.hazard();
+        let mut next: [Option<Slot>; 5] = [None; 5];
+        next[WB] = self.stages[MEM];
+        next[MEM] = self.stages[EX];
+
+        if stalled {
+            self.stalls += 1;
+            next[EX] = Some( Slot { seq: u64::MAX, pc: 0, decoded: Decode::decode(0), kind: SlotKind::Bubble, redirect: false } );
+            next[ID] = self.stages[ID];
+            next[IF] = self.stages[IF];
+        } else {
+            next[EX] = self.stages[ID];
+            next[ID] = self.stages[IF];
+            next[IF] = self.fetch(core);
+        }
+
+        //squashed slots are shown in the cycle they are flushed in
+        let occupancy = next;
+
+        //redirects resolve in EX
+        let mut flushed = 0;
+        if let Some(Slot { kind: SlotKind::Instr, redirect: true, .. }) = next[EX] {
+            for stage in [IF, ID] {
+                if next[stage].take().is_some() { flushed += 1; }
+            }
+            self.wrong_path = None;
+        }
+        self.flushed += flushed;
+
+        self.stages = next;
+
+        for (busy, slot) in self.busy.iter_mut().zip(self.stages.iter()) {
+            if let Some(Slot { kind: SlotKind::Instr, .. }) = slot { *busy += 1; }
+        }
+
+        if self.config.record {
+            self.history.push( CycleRecord { cycle: self.cycle, stages: occupancy, stalled, flushed } );
+        }
+
+        //the instruction in WB retires at the end of the cycle
+        if let Some(Slot { kind: SlotKind::Instr, .. }) = self.stages[WB].take() { self.retired += 1; }
+
+        self.done && self.stages.iter().all(|s| s.is_none())
+    }
+
+    /**
+     * True if the instruction in ID must wait this cycle
+     */
+    fn hazard(&self) -> bool {
+
+        let reads = match self.stages[ID] {
+            Some(Slot { kind: SlotKind::Instr, decoded, .. }) => decoded.sources,
+            _ => return false
+        };
+
+        let writes = |stage: usize| match self.stages[stage] {
+            Some(Slot { kind: SlotKind::Instr, decoded, .. }) => Disasm::destinations(decoded.code),
+            _ => [None, None]
+        };
+        let conflicts = |stage: usize| writes(stage).iter().flatten().any(|w| reads.contains(&Some(*w)));
+
+        if self.config.forwarding {
+            let load_in_ex = match self.stages[EX] {
+                Some(Slot { kind: SlotKind::Instr, decoded, .. }) => LOADS.contains(&decoded.opcode),
+                _ => false
+            };
+            load_in_ex && conflicts(EX)
+        } else {
+            conflicts(EX) || conflicts(MEM)
+        }
+    }
+
+    /**
+     * Fetches the next slot, executing it on core unless it is down the wrong path
+     */
+    fn fetch(&mut self, core: &mut Core) -> Option<Slot> {
+
+        let seq = self.next_seq;
+
+        if let Some(pc) = self.wrong_path {
+            self.wrong_path = Some(pc.wrapping_add(4));
+            self.next_seq += 1;
+            return Some( Slot { seq, pc, decoded: Decode::decode(core.fetch(pc).unwrap_or(0)), kind: SlotKind::Squashed, redirect: false } );
+        }
+
+        if self.done { return None; }
+
+        let pc = core.get_PC();
+        let decoded = Decode::decode(core.fetch(pc).unwrap_or(0));
+
+        match core.step() {
+            Ok(finished) => self.done = finished,
+            Err(eobj) => { self.done = true; self.fault = Some(eobj); }
+        }
+
+        let redirect = !self.done && core.get_PC() != pc.wrapping_add(4);
+        if redirect { self.wrong_path = Some(pc.wrapping_add(4)); }
+
+        self.next_seq += 1;
+        Some( Slot { seq, pc, decoded, kind: SlotKind::Instr, redirect } )
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    #[allow(dead_code)]
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    #[allow(dead_code)]
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    #[allow(dead_code)]
+    pub fn flushed(&self) -> u64 {
+        self.flushed
+    }
+
+    /**
+     * Cycle records, if config.record was set
+     */
+    pub fn history(&self) -> &[CycleRecord] {
+        &self.history
+    }
+
+    /**
+     * Writes one line per recorded cycle with what each stage held: the PC of an
+     * instruction, (PC) for a squashed slot, "bubble" or "-" when empty
+     */
+    pub fn write_occupancy<W: Write>(&self, mut w: W) -> io::Result<()> {
+
+        let mut header = format!("{:>8}", "cycle");
+        for name in STAGES { header += &format!("  {name:<10}"); }
+        writeln!(w, "{}", header.trim_end())?;
+
+        for record in &self.history {
+            let mut line = format!("{:>8}", record.cycle);
+            for slot in &record.stages {
+                let cell = match slot {
+                    None => String::from("-"),
+                    Some(Slot { kind: SlotKind::Bubble, .. }) => String::from("bubble"),
+                    Some(Slot { kind: SlotKind::Squashed, pc, .. }) => format!("({pc:08x})"),
+                    Some(Slot { pc, .. }) => format!("{pc:08x}")
+                };
+                line += &format!("  {cell:<10}");
+            }
+            if record.stalled { line += "  stall"; }
+            if record.flushed > 0 { line += &format!("  flush {}", record.flushed); }
+            writeln!(w, "{}", line.trim_end())?;
+        }
+
+        w.flush()
+    }
+}
+
+/**
+ * Summary printed after a pipeline run
+ */
+impl std::fmt::Display for Pipeline {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+
+    let cpi = if self.retired == 0 { 0.0 } else { self.cycle as f64 / self.retired as f64 };
+
+    writeln!(f, "Pipeline: {} instructions in {} cycles (CPI {:.2}), forwarding {}", self.retired, self.cycle, cpi,
+        if self.config.forwarding { "on" } else { "off" })?;
+    writeln!(f, "Stalls:    {} cycles", self.stalls)?;
+    writeln!(f, "Flushed:   {} slots", self.flushed)?;
+    write!(f, "Occupancy:")?;
+    for (name, busy) in STAGES.iter().zip(self.busy) {
+        write!(f, "  {name} {:.1}%", if self.cycle == 0 { 0.0 } else { busy as f64 * 100.0 / self.cycle as f64 })?;
+    }
+    Ok(())
+  }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn hazards_and_forwarding() {
+    //addi $at; addi $v0; addi $v1, $at, 1; nops; hlt, which faults in user mode
+    let (p, c, res) = pipelined("testbins/test_pipelined_simple.relf", PipelineConfig::default());
+    assert_eq!(res.unwrap_err().kind(), "PrivilegeError");
+    assert_eq!(p.stalls(), 0);
+    assert_eq!(p.retired(), 8);
+    //4 cycles to fill the pipeline
+    assert_eq!(p.cycles(), 8 + 4);
+
+    //without forwarding, $at is still in MEM when addi $v1 reaches ID
+    let (p2, c2, _) = pipelined("testbins/test_pipelined_simple.relf", PipelineConfig { forwarding: false, record: true });
+    assert_eq!(p2.stalls(), 1);
+    assert_eq!(p2.cycles(), p.cycles() + 1);
+    assert!(p2.history()[4].stalled);
+    assert_eq!(p2.history()[4].stages[ID].unwrap().pc, 0x4008);
+    assert_eq!(p2.history()[4].stages[EX].unwrap().kind, SlotKind::Bubble);
+
+    //the architectural state is the functional core's
+    assert_eq!(c.snapshot(c.get_PC(), 0), c2.snapshot(c2.get_PC(), 0));
+}
+
+#[test]
+fn flushes() {
+    let (p, c, res) = pipelined("testbins/test_irqh_pipelined_simple.relf", PipelineConfig { forwarding: true, record: true });
+    res.unwrap();
+
+    //the syscall and the taken beq each flush two wrong path slots
+    assert_eq!(p.flushed(), 4);
+    assert_eq!(p.retired(), c.instr_count());
+    assert_eq!(p.cycles(), p.retired() + 4 + p.stalls() + p.flushed());
+
+    let squashed = p.history().iter().flat_map(|r| r.stages.iter().flatten()).filter(|s| s.kind == SlotKind::Squashed).count();
+    assert!(squashed >= 4);
+
+    let mut log = Vec::new();
+    p.write_occupancy(&mut log).unwrap();
+    let log = String::from_utf8(log).unwrap();
+    assert_eq!(log.lines().count() as u64, p.cycles() + 1);
+    assert!(log.contains("flush 2"));
+}
+
+#[cfg(test)]
+fn pipelined(path: &str, config: PipelineConfig) -> (Pipeline, Core, Result<(), ExecutionError>) {
+    use super::Devices::Interruptor::ClockMode;
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF(path).unwrap();
+    let mut p = Pipeline::new(config);
+    let res = p.run(&mut c);
+    (p, c, res)
+}
@@ -0,0 +1,294 @@
+use super::Definitions::Disasm;
+use super::Pipeline::{CycleRecord, Slot, SlotKind, STAGES};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+const LEGEND: &str = "ID* held by a stall, xIF/xID fetched down the wrong path and flushed, bubble rows are stalls inserted into EX";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChartFormat {
+    Text,
+    Html,
+    Vcd
+}
+
+/**
+ * One row of the chart: an instruction, a flushed slot or a bubble
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub label: String,
+    // cell text by cycle
+    pub cells: BTreeMap<u64, String>
+}
+
+/**
+ * Classic pipeline chart, instructions against cycles, built from the cycle records
+ * of a Pipeline run with config.record set
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chart {
+    pub first: u64,
+    pub last: u64,
+    pub rows: Vec<Row>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum RowKey {
+    Slot(u64),
+    // by the cycle the bubble entered EX
+    Bubble(u64)
+}
+
+impl Chart {
+
+    /**
+     * Builds the chart of the cycles from first to last, both included
+     */
+    pub fn new(history: &[CycleRecord], first: u64, last: u64) -> Chart {
+
+        // (first cycle, -stage) of each row, so rows start in fetch order
+        let mut order: BTreeMap<RowKey, (u64, i64)> = BTreeMap::new();
+        let mut rows: BTreeMap<RowKey, Row> = BTreeMap::new();
+
+        for record in history.iter().filter(|r| r.cycle >= first && r.cycle <= last) {
+            for (stage, slot) in record.stages.iter().enumerate() {
+
+                let slot = match slot { Some(slot) => slot, None => continue };
+
+                let key = match slot.kind {
+                    SlotKind::Bubble => RowKey::Bubble(record.cycle - (stage as u64 - 2)),
+                    _ => RowKey::Slot(slot.seq)
+                };
+
+                let mut cell = String::from(STAGES[stage]);
+                if slot.kind == SlotKind::Squashed { cell.insert(0, 'x'); }
+                if record.stalled && stage < 2 && slot.kind == SlotKind::Instr { cell.push('*'); }
+
+                order.entry(key).or_insert((record.cycle, -(stage as i64)));
+                rows.entry(key).or_insert_with(|| Row { label: label(slot), cells: BTreeMap::new() }).cells.insert(record.cycle, cell);
+            }
+        }
+
+        let mut keys: Vec<RowKey> = rows.keys().copied().collect();
+        keys.sort_by_key(|k| (order[k], *k));
+
+        Chart { first, last, rows: keys.iter().map(|k| rows.remove(k).unwrap()).collect() }
+    }
+
+    /**
+     * Fixed width text, one column per cycle
+     */
+    pub fn write_text<W: Write>(&self, mut w: W) -> io::Result<()> {
+
+        let width = self.rows.iter().map(|r| r.label.len()).max().unwrap_or(0).max(5);
+
+        let mut header = format!("{:<width$}", "cycle");
+        for c in self.first..=self.last { header += &format!(" {c:>5}"); }
+        writeln!(w, "{}", header.trim_end())?;
+
+        for row in &self.rows {
+            let mut line = format!("{:<width$}", row.label);
+            for c in self.first..=self.last {
+                line += &format!(" {:>5}", row.cells.get(&c).map(|s| s.as_str()).unwrap_or(""));
+            }
+            writeln!(w, "{}", line.trim_end())?;
+        }
+
+        writeln!(w, "\n{LEGEND}")?;
+        w.flush()
+    }
+
+    /**
+     * Standalone HTML page with a table, cells coloured by stage
+     */
+    pub fn write_html<W: Write>(&self, mut w: W) -> io::Result<()> {
+
+        writeln!(w, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Pipeline chart</title>\n<style>")?;
+        writeln!(w, "table {{ border-collapse: collapse; font-family: monospace; }}")?;
+        writeln!(w, "td, th {{ border: 1px solid #ccc; padding: 2px 6px; text-align: center; }}")?;
+        writeln!(w, "td.label {{ text-align: left; white-space: pre; }}")?;
+        writeln!(w, ".IF {{ background: #cfe2ff; }} .ID {{ background: #d1e7dd; }} .EX {{ background: #fff3cd; }} .MEM {{ background: #f8d7da; }} .WB {{ background: #e2d9f3; }}")?;
+        writeln!(w, ".stall {{ background: #ffc107; }} .flushed {{ background: #adb5bd; text-decoration: line-through; }} .bubble {{ color: #6c757d; font-style: italic; }}")?;
+        writeln!(w, "</style>\n</head>\n<body>\n<table>")?;
+
+        write!(w, "<tr><th>instruction</th>")?;
+        for c in self.first..=self.last { write!(w, "<th>{c}</th>")?; }
+        writeln!(w, "</tr>")?;
+
+        for row in &self.rows {
+            let bubble = if row.label == "bubble" { " bubble" } else { "" };
+            write!(w, "<tr><td class=\"label{bubble}\">{}</td>", escape(&row.label))?;
+            for c in self.first..=self.last {
+                match row.cells.get(&c) {
+                    None => write!(w, "<td></td>")?,
+                    Some(cell) => {
+                        let class = if cell.starts_with('x') { "flushed" } else if cell.ends_with('*') { "stall" } else { cell.as_str() };
+                        write!(w, "<td class=\"{class}\">{cell}</td>")?
+                    }
+                }
+            }
+            writeln!(w, "</tr>")?;
+        }
+
+        writeln!(w, "</table>\n<p>{}</p>\n</body>\n</html>", escape(LEGEND))?;
+        w.flush()
+    }
+}
+
+fn label(slot: &Slot) -> String {
+    match slot.kind {
+        SlotKind::Bubble => String::from("bubble"),
+        _ => format!("{:08x}  {}", slot.pc, Disasm::disassemble(slot.decoded.code))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/**
+ * Writes a VCD waveform of the pipeline registers, one clock period per cycle
+ *
+ * For every stage there is the PC and instruction word it holds and whether it holds
+ * an instruction, a bubble or a squashed slot, plus global stall and flush signals
+ */
+pub fn write_vcd<W: Write>(history: &[CycleRecord], first: u64, last: u64, mut w: W) -> io::Result<()> {
+
+    // per stage: pc, word, valid, bubble, squashed; then stall and flush
+    const PER_STAGE: [(&str, usize); 5] = [("pc", 32), ("instr", 32), ("valid", 1), ("bubble", 1), ("squashed", 1)];
+
+    let mut vars: Vec<(String, usize)> = Vec::new();
+    for stage in STAGES {
+        for (name, width) in PER_STAGE { vars.push((format!("{stage}_{name}"), width)); }
+    }
+    vars.push((String::from("stall"), 1));
+    vars.push((String::from("flush"), 1));
+
+    // identifiers are printable ASCII from '#', '!' being the clock
+    let id = |i: usize| -> String {
+        let (mut i, mut s) = (i, String::new());
+        loop {
+            s.push((b'#' + (i % 90) as u8) as char);
+            i /= 90;
+            if i == 0 { return s; }
+        }
+    };
+
+    writeln!(w, "$version mips_runtime pipeline model $end")?;
+    writeln!(w, "$timescale 1ns $end")?;
+    writeln!(w, "$scope module pipeline $end")?;
+    writeln!(w, "$var wire 1 ! clk $end")?;
+    for (i, (name, width)) in vars.iter().enumerate() {
+        writeln!(w, "$var wire {width} {} {name} $end", id(i))?;
+    }
+    writeln!(w, "$upscope $end\n$enddefinitions $end")?;
+
+    let value = |v: u64, width: usize, ident: &str| -> String {
+        if width == 1 { format!("{v}{ident}") } else { format!("b{v:b} {ident}") }
+    };
+
+    let mut prev: Vec<Option<u64>> = vec![None; vars.len()];
+
+    for record in history.iter().filter(|r| r.cycle >= first && r.cycle <= last) {
+
+        let mut now: Vec<u64> = Vec::with_capacity(vars.len());
+        for slot in &record.stages {
+            let (pc, code, kind) = match slot {
+                Some(s) => (s.pc as u64, s.decoded.code as u64, Some(s.kind)),
+                None => (0, 0, None)
+            };
+            now.extend([pc, code, (kind == Some(SlotKind::Instr)) as u64, (kind == Some(SlotKind::Bubble)) as u64, (kind == Some(SlotKind::Squashed)) as u64]);
+        }
+        now.push(record.stalled as u64);
+        now.push((record.flushed > 0) as u64);
+
+        writeln!(w, "#{}", record.cycle * 10)?;
+        writeln!(w, "1!")?;
+        for (i, v) in now.iter().enumerate() {
+            if prev[i] != Some(*v) {
+                writeln!(w, "{}", value(*v, vars[i].1, &id(i)))?;
+                prev[i] = Some(*v);
+            }
+        }
+        writeln!(w, "#{}\n0!", record.cycle * 10 + 5)?;
+    }
+
+    w.flush()
+}
+
+/**
+ * Writes the chart of the cycles from first to last in the given format
+ */
+pub fn export<W: Write>(history: &[CycleRecord], first: u64, last: u64, format: ChartFormat, w: W) -> io::Result<()> {
+    match format {
+        ChartFormat::Text => Chart::new(history, first, last).write_text(w),
+        ChartFormat::Html => Chart::new(history, first, last).write_html(w),
+        ChartFormat::Vcd => write_vcd(history, first, last, w)
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn chart_rows() {
+    let history = history_of("testbins/test_pipelined_simple.relf", false);
+    let chart = Chart::new(&history, 1, history.len() as u64);
+
+    //addi $v1, $at, 1 waits in ID for $at, and a bubble goes down the pipeline in its place
+    let addi = chart.rows.iter().find(|r| r.label.starts_with("00004008")).unwrap();
+    assert_eq!(addi.cells.values().map(|s| s.as_str()).collect::<Vec<_>>(), vec!["IF", "ID", "ID*", "EX", "MEM", "WB"]);
+    assert_eq!(chart.rows[4].label, "bubble");
+    assert_eq!(chart.rows[4].cells.keys().copied().collect::<Vec<_>>(), vec![5, 6, 7]);
+
+    let mut text = Vec::new();
+    chart.write_text(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.lines().nth(3).unwrap().starts_with("00004008  addi $v1, $at, 1"));
+
+    let mut html = Vec::new();
+    chart.write_html(&mut html).unwrap();
+    assert!(String::from_utf8(html).unwrap().contains("<td class=\"stall\">ID*</td>"));
+}
+
+#[test]
+fn chart_flushes() {
+    let history = history_of("testbins/test_irqh_pipelined_simple.relf", true);
+    let chart = Chart::new(&history, 1, 6);
+
+    //syscall at 0x4004 resolves in EX in cycle 4, flushing 0x4008 and 0x400c
+    let flushed = chart.rows.iter().find(|r| r.label.starts_with("00004008")).unwrap();
+    assert_eq!(flushed.cells.values().map(|s| s.as_str()).collect::<Vec<_>>(), vec!["xIF", "xID"]);
+    assert_eq!(chart.rows.len(), 6);
+}
+
+#[test]
+fn vcd() {
+    let history = history_of("testbins/test_pipelined_simple.relf", true);
+    let mut out = Vec::new();
+    write_vcd(&history, 1, history.len() as u64, &mut out).unwrap();
+    let vcd = String::from_utf8(out).unwrap();
+
+    assert!(vcd.contains("$var wire 32 # IF_pc $end"));
+    assert!(vcd.contains("$enddefinitions $end"));
+    //IF holds 0x4000 in the first cycle
+    assert!(vcd.contains("#10\n1!\nb100000000000000 #\n"));
+    assert_eq!(vcd.matches("\n0!").count(), history.len());
+}
+
+#[cfg(test)]
+fn history_of(path: &str, forwarding: bool) -> Vec<CycleRecord> {
+    use super::Core::Core;
+    use super::Devices::Interruptor::ClockMode;
+    use super::Pipeline::{Pipeline, PipelineConfig};
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF(path).unwrap();
+    let mut p = Pipeline::new(PipelineConfig { forwarding, record: true });
+    let _ = p.run(&mut c);
+    p.history().to_vec()
+}
@@ -0,0 +1,363 @@
+/*!
+ *  Branch predictor simulation
+ *
+ *  A BranchUnit runs any number of direction predictors side by side over the
+ *  conditional branches (beq, bne, bgtz, blez) and jumps the Core resolves, with a
+ *  branch target buffer shared by all of them. A prediction is right when the PC it
+ *  would fetch next is the one the instruction went to: a branch predicted taken
+ *  only redirects fetch if the BTB knows its target, and jumps always rely on the BTB
+ *
+ *  Predictors by name:
+ *
+ *      not-taken: always predicts not taken
+ *      backward:  predicts backward branches taken, forward ones not taken
+ *      1bit:      last outcome, per entry of a table indexed by PC
+ *      2bit:      2-bit saturating counters indexed by PC
+ *      gshare:    2-bit saturating counters indexed by PC xor the global history
+ */
+
+use super::Definitions::Disasm;
+use super::Definitions::Utils::Word;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+
+// sites listed by write_report
+const SITES_SHOWN: usize = 20;
+
+pub const NAMES: [&str; 5] = ["not-taken", "backward", "1bit", "2bit", "gshare"];
+// largest table size, as a power of two, by_name builds
+pub const MAX_BITS: u32 = 24;
+
+/**
+ * A direction predictor for conditional branches
+ */
+pub trait Predictor {
+
+    fn name(&self) -> &'static str;
+
+    /**
+     * Whether the branch at pc will be taken
+     *
+     * ARGS:
+     *
+     *  backward: The branch target is before pc
+     */
+    fn predict(&mut self, pc: u32, backward: bool) -> bool;
+
+    /**
+     * Learns the outcome of the branch at pc
+     */
+    fn update(&mut self, pc: u32, taken: bool);
+}
+
+struct NotTaken;
+
+impl Predictor for NotTaken {
+    fn name(&self) -> &'static str { "not-taken" }
+    fn predict(&mut self, _pc: u32, _backward: bool) -> bool { false }
+    fn update(&mut self, _pc: u32, _taken: bool) {}
+}
+
+struct BackwardTaken;
+
+impl Predictor for BackwardTaken {
+    fn name(&self) -> &'static str { "backward" }
+    fn predict(&mut self, _pc: u32, backward: bool) -> bool { backward }
+    fn update(&mut self, _pc: u32, _taken: bool) {}
+}
+
+struct OneBit {
+    table: Vec<bool>
+}
+
+impl Predictor for OneBit {
+    fn name(&self) -> &'static str { "1bit" }
+
+    fn predict(&mut self, pc: u32, _backward: bool) -> bool {
+        self.table[index(pc, 0, self.table.len())]
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        let i = index(pc, 0, self.table.len());
+        self.table[i] = taken;
+    }
+}
+
+/**
+ * 2-bit saturating counters, starting weakly not taken, shared by 2bit and gshare
+ */
+struct Counters {
+    name: &'static str,
+    table: Vec<u8>,
+    // global history bits, only kept by gshare
+    history: Option<u32>
+}
+
+impl Predictor for Counters {
+    fn name(&self) -> &'static str { self.name }
+
+    fn predict(&mut self, pc: u32, _backward: bool) -> bool {
+        self.table[index(pc, self.history.unwrap_or(0), self.table.len())] >= 2
+    }
+
+    fn update(&mut self, pc: u32, taken: bool) {
+        let i = index(pc, self.history.unwrap_or(0), self.table.len());
+        self.table[i] = if taken { (self.table[i] + 1).min(3) } else { self.table[i].saturating_sub(1) };
+
+        let mask = self.table.len() as u32 - 1;
+        if let Some(h) = &mut self.history { *h = ((*h << 1) | taken as u32) & mask; }
+    }
+}
+
+fn index(pc: u32, history: u32, len: usize) -> usize {
+    (((pc >> 2) ^ history) as usize) & (len - 1)
+}
+
+/**
+ * Builds a predictor from its name
+ *
+ * ARGS:
+ *
+ *  bits: Tables have 2^bits entries, and gshare keeps that many bits of history; at most MAX_BITS
+ */
+pub fn by_name(name: &str, bits: u32) -> Result<Box<dyn Predictor>, String> {
+    if bits > MAX_BITS {
+        return Err(format!("Predictor tables of 2^{bits} entries are too large, the most is 2^{MAX_BITS}"));
+    }
+    let len = 1usize << bits;
+    match name {
+        "not-taken" => Ok(Box::new(NotTaken)),
+        "backward"  => Ok(Box::new(BackwardTaken)),
+        "1bit"      => Ok(Box::new(OneBit { table: vec![false; len] })),
+        "2bit"      => Ok(Box::new(Counters { name: "2bit", table: vec![1; len], history: None })),
+        "gshare"    => Ok(Box::new(Counters { name: "gshare", table: vec![1; len], history: Some(0) })),
+        _ => Err(format!("Unknown branch predictor '{name}', expected one of {}", NAMES.join(", ")))
+    }
+}
+
+/**
+ * Direct-mapped branch target buffer
+ */
+pub struct Btb {
+    entries: Vec<Option<(u32, u32)>>
+}
+
+impl Btb {
+
+    pub fn new(entries: usize) -> Btb {
+        Btb { entries: vec![None; entries.max(1)] }
+    }
+
+    pub fn lookup(&self, pc: u32) -> Option<u32> {
+        match self.entries[(pc as usize >> 2) % self.entries.len()] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None
+        }
+    }
+
+    pub fn update(&mut self, pc: u32, target: u32) {
+        let len = self.entries.len();
+        self.entries[(pc as usize >> 2) % len] = Some((pc, target));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Accuracy {
+    pub branches: u64,
+    pub branch_hits: u64,
+    pub jumps: u64,
+    pub jump_hits: u64
+}
+
+impl Accuracy {
+    pub fn rate(&self) -> f64 {
+        let total = self.branches + self.jumps;
+        if total == 0 { 0.0 } else { (self.branch_hits + self.jump_hits) as f64 / total as f64 }
+    }
+}
+
+/**
+ * A branch or jump seen by the BranchUnit
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Site {
+    pub code: Word,
+    pub executed: u64,
+    pub taken: u64,
+    // right predictions, indexed like the unit's predictors
+    pub hits: Vec<u64>
+}
+
+pub struct BranchUnit {
+    predictors: Vec<Box<dyn Predictor>>,
+    btb: Btb,
+    accuracy: Vec<Accuracy>,
+    sites: BTreeMap<u32, Site>,
+    // whether the first predictor's mispredictions are charged by the timing model
+    timing: bool
+}
+
+impl BranchUnit {
+
+    pub fn new(predictors: Vec<Box<dyn Predictor>>, btb: Btb, timing: bool) -> BranchUnit {
+        let n = predictors.len();
+        BranchUnit { predictors, btb, accuracy: vec![Accuracy::default(); n], sites: BTreeMap::new(), timing }
+    }
+
+    /**
+     * Checks every predictor against a resolved branch or jump and trains them
+     *
+     * ARGS:
+     *
+     *  pc: Address of the instruction
+     *
+     *  code: The instruction, a conditional branch or a jump
+     *
+     *  next: Address executed after it
+     *
+     * RETURNS:
+     *
+     *  Whether the first predictor mispredicted, if it feeds the timing model
+     */
+    pub fn resolve(&mut self, pc: u32, code: Word, next: u32) -> Option<bool> {
+
+        let fallthrough = pc.wrapping_add(4);
+        let taken = next != fallthrough;
+        let branch = Disasm::is_branch(code);
+        let backward = (code & 0x8000) != 0;
+        let btb = self.btb.lookup(pc);
+
+        let site = self.sites.entry(pc).or_insert_with(|| Site { code, hits: vec![0; self.predictors.len()], ..Default::default() });
+        site.executed += 1;
+        site.taken += taken as u64;
+
+        let mut first_hit = true;
+
+        for (i, p) in self.predictors.iter_mut().enumerate() {
+
+            let predict_taken = if branch { p.predict(pc, backward) } else { true };
+            let predicted = match (predict_taken, btb) {
+                (true, Some(target)) => target,
+                _ => fallthrough
+            };
+            let hit = predicted == next;
+
+            if branch { p.update(pc, taken); }
+
+            let acc = &mut self.accuracy[i];
+            if branch { acc.branches += 1; acc.branch_hits += hit as u64 } else { acc.jumps += 1; acc.jump_hits += hit as u64 }
+            site.hits[i] += hit as u64;
+            if i == 0 { first_hit = hit; }
+        }
+
+        if taken { self.btb.update(pc, next); }
+
+        (self.timing && !self.predictors.is_empty()).then_some(!first_hit)
+    }
+
+    /**
+     * Accuracy of each predictor, by name
+     */
+    pub fn accuracy(&self) -> Vec<(&'static str, Accuracy)> {
+        self.predictors.iter().map(|p| p.name()).zip(self.accuracy.iter().copied()).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn sites(&self) -> &BTreeMap<u32, Site> {
+        &self.sites
+    }
+
+    /**
+     * Writes the accuracy of each predictor and of the most executed branch sites
+     *
+     * ARGS:
+     *
+     *  symbol: Names an address, such as <main+0x8>
+     */
+    pub fn write_report<W: Write>(&self, mut w: W, symbol: &dyn Fn(u32) -> String) -> io::Result<()> {
+
+        let pct = |n: u64, of: u64| if of == 0 { 0.0 } else { n as f64 * 100.0 / of as f64 };
+        let (branches, jumps) = self.accuracy.first().map(|a| (a.branches, a.jumps)).unwrap_or((0, 0));
+
+        writeln!(w, "Branch prediction: {branches} branches, {jumps} jumps, {} BTB entries", self.btb.len())?;
+        for (name, a) in self.accuracy() {
+            writeln!(w, "  {name:<10} branches {:5.1}% ({}/{}), jumps {:5.1}% ({}/{}), overall {:5.1}%", pct(a.branch_hits, a.branches), a.branch_hits, a.branches,
+                pct(a.jump_hits, a.jumps), a.jump_hits, a.jumps, a.rate() * 100.0)?;
+        }
+
+        let mut sites: Vec<(&u32, &Site)> = self.sites.iter().collect();
+        sites.sort_by(|a, b| b.1.executed.cmp(&a.1.executed).then(a.0.cmp(b.0)));
+
+        let shown: Vec<(u32, &Site, String)> = sites.iter().take(SITES_SHOWN).map(|(pc, site)| (**pc, *site, format!("{} {}", Disasm::mnemonic(site.code), symbol(**pc)))).collect();
+        let width = shown.iter().map(|(_, _, name)| name.len()).max().unwrap_or(0).max(4);
+
+        let mut header = format!("\n  {:<8}  {:<width$} {:>10} {:>7}", "pc", "site", "executed", "taken");
+        for p in &self.predictors { header += &format!(" {:>10}", p.name()); }
+        writeln!(w, "{header}")?;
+
+        for (pc, site, name) in &shown {
+            let mut line = format!("  {pc:08x}  {name:<width$} {:>10} {:>6.1}%", site.executed, pct(site.taken, site.executed));
+            for hits in &site.hits { line += &format!(" {:>9.1}%", pct(*hits, site.executed)); }
+            writeln!(w, "{line}")?;
+        }
+        if sites.len() > SITES_SHOWN {
+            writeln!(w, "  ... {} more sites", sites.len() - SITES_SHOWN)?;
+        }
+        w.flush()
+    }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn predictors() {
+    let bne = 0x1443fffe;     //bne $v0, $v1, -2
+    let all = NAMES.iter().map(|n| by_name(n, 4).unwrap()).collect();
+    let mut unit = BranchUnit::new(all, Btb::new(16), true);
+
+    //a loop branch taken 9 times, then falling through
+    for _ in 0..9 { unit.resolve(0x1008, bne, 0x1000); }
+    unit.resolve(0x1008, bne, 0x100c);
+
+    let hits: Vec<u64> = unit.accuracy().iter().map(|(_, a)| a.branch_hits).collect();
+    //not-taken only gets the exit; the others miss the first time, as the BTB is cold,
+    //and the exit; gshare also misses while its history fills up
+    assert_eq!(hits, vec![1, 8, 8, 8, 4]);
+    assert_eq!(unit.sites()[&0x1008].taken, 9);
+
+    //jumps hit once the BTB knows them
+    let j = 0x08000400;
+    assert_eq!(unit.resolve(0x2000, j, 0x1000), Some(true));
+    assert_eq!(unit.resolve(0x2000, j, 0x1000), Some(false));
+    assert_eq!(unit.accuracy()[4].1.jump_hits, 1);
+
+    assert!(by_name("perceptron", 4).is_err());
+    assert!(by_name("2bit", MAX_BITS + 1).is_err());
+}
+
+#[test]
+fn gshare_history() {
+    //alternating branch: 2bit counters cannot learn it, gshare can
+    let bne = 0x1443fffe;
+    let mut unit = BranchUnit::new(vec![by_name("2bit", 6).unwrap(), by_name("gshare", 6).unwrap()], Btb::new(16), false);
+    for i in 0..200 { unit.resolve(0x1008, bne, if i % 2 == 0 { 0x1000 } else { 0x100c }); }
+
+    let acc = unit.accuracy();
+    assert!(acc[0].1.rate() < 0.6);
+    assert!(acc[1].1.rate() > 0.9);
+    assert_eq!(unit.resolve(0x1008, bne, 0x1000), None);
+
+    let mut out = Vec::new();
+    unit.write_report(&mut out, &|pc| format!("<f+0x{:x}>", pc - 0x1000)).unwrap();
+    let report = String::from_utf8(out).unwrap();
+    assert!(report.starts_with("Branch prediction: 201 branches, 0 jumps, 16 BTB entries\n"));
+    assert!(report.contains("00001008  bne <f+0x8>"));
+}
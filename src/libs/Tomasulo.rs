@@ -0,0 +1,689 @@
+/*!
+ *  Tomasulo-style out-of-order execution model
+ *
+ *  Instructions issue in order into a reorder buffer (ROB) and a reservation station
+ *  of their unit, wait there for their operands, execute out of order on one
+ *  functional unit per class, broadcast their results on a single common data bus
+ *  (CDB) and commit in order. Registers are renamed to the ROB entries that will
+ *  write them, so only true dependences wait
+ *
+ *  Like the pipeline model, the functional Core supplies the instruction stream and
+ *  is the reference: every instruction is fetched by stepping the Core. The model
+ *  decodes it with Disasm, and ALU and multiply/divide results and branch outcomes
+ *  are computed again, at execute, with Core::evaluate on the operand values that
+ *  went through renaming and the CDB. Each one is checked against what the Core
+ *  wrote when it commits, and the register files are compared at the end. Loads take
+ *  the value the Core read, and jump links the value the Core wrote
+ *
+ *  Simplifications: fetch stops after a branch or jump until it resolves, so nothing
+ *  runs down a wrong path; loads and stores execute in program order; syscall, rfe,
+ *  hlt, the coprocessor 0 moves and instructions the Core does not know wait for the
+ *  ROB to drain, and fetch waits for them to commit
+ */
+
+use super::Core::Core;
+use super::Definitions::Disasm;
+use super::Definitions::Disasm::{REG_HI, REG_LO};
+use super::Definitions::Decode::{self, Decoded};
+use super::Definitions::Errors::ExecutionError;
+use super::Definitions::Trace::reg_name;
+use super::Definitions::Utils::Word;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+const MULT: usize  = Disasm::index("mult");
+const MULTU: usize = Disasm::index("multu");
+const DIV: usize   = Disasm::index("div");
+const DIVU: usize  = Disasm::index("divu");
+const MEMORY: [usize; 8] = [Disasm::index("lb"), Disasm::index("lbu"), Disasm::index("lh"), Disasm::index("lhu"), Disasm::index("lw"), Disasm::index("sb"), Disasm::index("sh"), Disasm::index("sw")];
+const SERIAL: [usize; 8] = [Disasm::index("syscall"), Disasm::index("rfe"), Disasm::index("hlt"), Disasm::index("lhi"), Disasm::index("llo"), Disasm::index("mfc0"), Disasm::index("mtc0"), Disasm::index("unknown")];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Alu,
+    MulDiv,
+    Mem,
+    // executed alone, without a station
+    Serial
+}
+
+impl Unit {
+
+    fn of(d: &Decoded) -> Unit {
+        match d.opcode {
+            MULT | MULTU | DIV | DIVU => Unit::MulDiv,
+            op if MEMORY.contains(&op) => Unit::Mem,
+            op if SERIAL.contains(&op) => Unit::Serial,
+            _ => Unit::Alu
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Unit::Alu => "alu",
+            Unit::MulDiv => "muldiv",
+            Unit::Mem => "mem",
+            Unit::Serial => "serial"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TomasuloConfig {
+    // ROB entries
+    pub rob: usize,
+    // reservation stations per unit
+    pub alu_stations: usize,
+    pub muldiv_stations: usize,
+    pub mem_stations: usize,
+    pub alu_latency: u64,
+    pub mult_latency: u64,
+    pub div_latency: u64,
+    pub mem_latency: u64,
+    // keep a CycleRecord per cycle, for write_dump
+    pub record: bool
+}
+
+impl Default for TomasuloConfig {
+    fn default() -> TomasuloConfig {
+        TomasuloConfig { rob: 8, alu_stations: 3, muldiv_stations: 2, mem_stations: 3, alu_latency: 1, mult_latency: 12, div_latency: 35, mem_latency: 2, record: false }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Ready(Word),
+    // produced by the ROB entry with this sequence number
+    Waiting(u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Issued,
+    // until the end of this cycle
+    Executing(u64),
+    Written
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    seq: u64,
+    pc: u32,
+    decoded: Decoded,
+    unit: Unit,
+    operands: [Option<(u32, Operand)>; 2],
+    dests: [Option<u32>; 2],
+    values: [Option<Word>; 2],
+    // what the functional Core wrote, and whether it took the branch
+    expected: [Word; 2],
+    taken: bool,
+    // branches and jumps hold fetch until they are written
+    control: bool,
+    state: State
+}
+
+impl Entry {
+    fn value_of(&self, reg: u32) -> Option<Word> {
+        (0..2).find(|i| self.dests[*i] == Some(reg)).and_then(|i| self.values[i])
+    }
+
+    // results not computed by the model are the core's
+    fn use_expected(&mut self) {
+        self.values = [0, 1].map(|i| self.dests[i].map(|_| self.expected[i]));
+    }
+}
+
+/**
+ * The ROB at the end of a cycle, and what moved in it
+ */
+#[derive(Debug, Clone)]
+pub struct CycleRecord {
+    pub cycle: u64,
+    pub issued: Option<u64>,
+    pub started: Vec<u64>,
+    pub broadcast: Option<u64>,
+    pub committed: Option<u64>,
+    rob: Vec<Entry>
+}
+
+/**
+ * A result of the model that differs from the functional Core's
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    // the instruction, None for the final register file check
+    pub seq: Option<u64>,
+    pub pc: u32,
+    // None for a branch outcome, as 0 or 1
+    pub reg: Option<u32>,
+    pub expected: Word,
+    pub got: Option<Word>
+}
+
+impl fmt::Display for Mismatch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let got = self.got.map(|v| format!("{v:08x}")).unwrap_or_else(|| String::from("nothing"));
+    let what = match self.reg {
+        Some(r) => format!("${}", reg_name(r)),
+        None => String::from("branch taken")
+    };
+    match self.seq {
+        Some(seq) => write!(f, "#{seq} at {:08x}: {what} is {got}, the core has {:08x}", self.pc, self.expected),
+        None => write!(f, "at the end: {what} is {got}, the core has {:08x}", self.expected)
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stalls {
+    pub rob_full: u64,
+    pub stations_full: u64,
+    pub control: u64,
+    pub serial: u64
+}
+
+pub struct Tomasulo {
+    config: TomasuloConfig,
+    rob: VecDeque<Entry>,
+    // committed registers, HI and LO last, and the ROB entry that will write each one
+    regs: [Word; 34],
+    rat: [Option<u64>; 34],
+    cycle: u64,
+    retired: u64,
+    broadcasts: u64,
+    next_seq: u64,
+    // fetched from the Core but not issued yet
+    pending: Option<Entry>,
+    // branch, jump or serial instruction fetch waits for
+    blocker: Option<u64>,
+    stalls: Stalls,
+    mismatches: Vec<Mismatch>,
+    history: Vec<CycleRecord>,
+    done: bool,
+    fault: Option<ExecutionError>
+}
+
+impl Tomasulo {
+
+    pub fn new(config: TomasuloConfig) -> Tomasulo {
+        Tomasulo {
+            config, rob: VecDeque::new(), regs: [0; 34], rat: [None; 34],
+            cycle: 0, retired: 0, broadcasts: 0, next_seq: 0, pending: None, blocker: None,
+            stalls: Stalls::default(), mismatches: Vec::new(), history: Vec::new(), done: false, fault: None
+        }
+    }
+
+    /**
+     * Runs core to the end on the model, then checks the register files agree
+     *
+     * RETURNS:
+     *
+     *  The fault that stopped the core, if any
+     */
+    pub fn run(&mut self, core: &mut Core) -> Result<(), ExecutionError> {
+
+        self.regs = registers(core);
+
+        while !self.cycle_once(core) {}
+
+        let actual = registers(core);
+        for (r, (ours, theirs)) in self.regs.iter().zip(actual).enumerate().skip(1) {
+            if *ours != theirs {
+                self.mismatches.push(Mismatch { seq: None, pc: core.get_PC(), reg: Some(r as u32), expected: theirs, got: Some(*ours) });
+            }
+        }
+
+        match self.fault.take() {
+            Some(eobj) => Err(eobj),
+            None => Ok(())
+        }
+    }
+
+    /**
+     * Advances the model by a cycle: commit, execute, write back, issue
+     *
+     * RETURNS:
+     *
+     *  true once the core is done and the ROB has drained
+     */
+    pub fn cycle_once(&mut self, core: &mut Core) -> bool {
+
+        self.cycle += 1;
+
+        let committed = self.commit();
+        let started = self.execute();
+        let broadcast = self.write_back(core);
+        let issued = self.issue(core);
+
+        if self.config.record {
+            self.history.push(CycleRecord { cycle: self.cycle, issued, started, broadcast, committed, rob: self.rob.iter().cloned().collect() });
+        }
+
+        self.done && self.pending.is_none() && self.rob.is_empty()
+    }
+
+    /**
+     * Retires the head of the ROB if it has been written, checking it against the core
+     */
+    fn commit(&mut self) -> Option<u64> {
+
+        if self.rob.front()?.state != State::Written { return None; }
+        let e = self.rob.pop_front()?;
+
+        for i in 0..2 {
+            let r = match e.dests[i] { Some(r) => r as usize, None => continue };
+            if e.values[i] != Some(e.expected[i]) {
+                self.mismatches.push(Mismatch { seq: Some(e.seq), pc: e.pc, reg: Some(r as u32), expected: e.expected[i], got: e.values[i] });
+            }
+            //carry on from the core's value, so one mismatch is reported once
+            self.regs[r] = e.expected[i];
+            if self.rat[r] == Some(e.seq) { self.rat[r] = None; }
+        }
+
+        if self.blocker == Some(e.seq) { self.blocker = None; }
+        self.retired += 1;
+        Some(e.seq)
+    }
+
+    /**
+     * Starts the oldest ready instruction of each idle unit
+     */
+    fn execute(&mut self) -> Vec<u64> {
+
+        let mut started = Vec::new();
+
+        for unit in [Unit::Alu, Unit::MulDiv, Unit::Mem] {
+
+            let busy = self.rob.iter().any(|e| e.unit == unit && matches!(e.state, State::Executing(last) if last >= self.cycle));
+            if busy { continue; }
+
+            let ready = |e: &Entry| e.state == State::Issued && e.operands.iter().flatten().all(|(_, o)| matches!(o, Operand::Ready(_)));
+
+            //memory accesses go in program order
+            let next = match unit {
+                Unit::Mem => self.rob.iter_mut().find(|e| e.unit == unit && e.state != State::Written).filter(|e| ready(e)),
+                _ => self.rob.iter_mut().find(|e| e.unit == unit && ready(e))
+            };
+
+            if let Some(e) = next {
+                let latency = match e.decoded.opcode {
+                    MULT | MULTU => self.config.mult_latency,
+                    DIV | DIVU => self.config.div_latency,
+                    _ if unit == Unit::Mem => self.config.mem_latency,
+                    _ => self.config.alu_latency
+                };
+                e.state = State::Executing(self.cycle + latency.max(1) - 1);
+                started.push(e.seq);
+            }
+        }
+
+        started
+    }
+
+    /**
+     * Finishes the instructions done executing; the oldest one with results takes the CDB
+     */
+    fn write_back(&mut self, core: &mut Core) -> Option<u64> {
+
+        let cycle = self.cycle;
+        let finished = |e: &Entry| matches!(e.state, State::Executing(last) if last < cycle);
+
+        //stores and branches have nothing to broadcast
+        for i in 0..self.rob.len() {
+            if finished(&self.rob[i]) && self.rob[i].dests.iter().all(Option::is_none) {
+                self.finish(i, core);
+            }
+        }
+
+        let i = self.rob.iter().position(finished)?;
+        self.finish(i, core);
+
+        let producer = self.rob[i].clone();
+        for e in self.rob.iter_mut() {
+            for (r, o) in e.operands.iter_mut().flatten() {
+                if *o == Operand::Waiting(producer.seq) {
+                    *o = Operand::Ready(producer.value_of(*r).unwrap_or(0));
+                }
+            }
+        }
+
+        self.broadcasts += 1;
+        Some(producer.seq)
+    }
+
+    /**
+     * Computes the results of ROB entry i and marks it written
+     */
+    fn finish(&mut self, i: usize, core: &mut Core) {
+
+        let e = &mut self.rob[i];
+        if matches!(e.unit, Unit::Alu | Unit::MulDiv) && !e.decoded.jump {
+
+            let operands: Vec<(u32, Word)> = e.operands.iter().flatten().map(|(r, o)| (*r, if let Operand::Ready(v) = o { *v } else { 0 })).collect();
+
+            match core.evaluate(e.decoded.code, &operands) {
+                Ok((values, taken)) => {
+                    e.values = values;
+                    if e.decoded.branch && taken != e.taken {
+                        self.mismatches.push(Mismatch { seq: Some(e.seq), pc: e.pc, reg: None, expected: e.taken as Word, got: Some(taken as Word) });
+                    }
+                }
+                Err(_) => e.values = [None; 2]
+            }
+        } else {
+            e.use_expected();
+        }
+
+        e.state = State::Written;
+        if e.control && self.blocker == Some(e.seq) { self.blocker = None; }
+    }
+
+    /**
+     * Issues the next instruction into the ROB and a station, renaming its registers
+     */
+    fn issue(&mut self, core: &mut Core) -> Option<u64> {
+
+        if self.pending.is_none() && !self.done && self.blocker.is_none() {
+            self.pending = self.fetch(core);
+        }
+
+        let unit = self.pending.as_ref()?.unit;
+
+        if self.blocker.is_some() {
+            self.stalls.control += 1;
+            return None;
+        }
+        if unit == Unit::Serial && !self.rob.is_empty() {
+            self.stalls.serial += 1;
+            return None;
+        }
+        if self.rob.len() >= self.config.rob {
+            self.stalls.rob_full += 1;
+            return None;
+        }
+
+        let stations = match unit {
+            Unit::Alu => self.config.alu_stations,
+            Unit::MulDiv => self.config.muldiv_stations,
+            Unit::Mem => self.config.mem_stations,
+            Unit::Serial => 1
+        };
+        if self.rob.iter().filter(|e| e.unit == unit && e.state != State::Written).count() >= stations {
+            self.stalls.stations_full += 1;
+            return None;
+        }
+
+        let mut e = self.pending.take()?;
+
+        for (r, o) in e.operands.iter_mut().flatten() {
+            *o = match self.rat[*r as usize] {
+                None => Operand::Ready(self.regs[*r as usize]),
+                Some(seq) => match self.rob.iter().find(|p| p.seq == seq) {
+                    Some(p) if p.state == State::Written => Operand::Ready(p.value_of(*r).unwrap_or(0)),
+                    _ => Operand::Waiting(seq)
+                }
+            };
+        }
+        for r in e.dests.iter().flatten() { self.rat[*r as usize] = Some(e.seq); }
+
+        //serial instructions are executed by the core alone
+        if unit == Unit::Serial {
+            e.use_expected();
+            e.state = State::Written;
+            self.blocker = Some(e.seq);
+        } else if e.control {
+            self.blocker = Some(e.seq);
+        }
+
+        let seq = e.seq;
+        self.rob.push_back(e);
+        Some(seq)
+    }
+
+    /**
+     * Steps the core and decodes what it executed
+     */
+    fn fetch(&mut self, core: &mut Core) -> Option<Entry> {
+
+        let pc = core.get_PC();
+        let decoded = Decode::decode(core.fetch(pc).unwrap_or(0));
+        let interrupts = core.stats().interrupts;
+
+        match core.step() {
+            Ok(finished) => self.done = finished,
+            Err(eobj) => {
+                self.done = true;
+                self.fault = Some(eobj);
+                return None;
+            }
+        }
+
+        //an interrupt taken after the instruction saved where it was going in EPC
+        let ctx = core.snapshot(core.get_PC(), 0);
+        let next = if core.stats().interrupts != interrupts { ctx.epc } else { core.get_PC() };
+
+        let dests = Disasm::destinations(decoded.code);
+        let expected = dests.map(|d| match d {
+            Some(REG_HI) => ctx.hi,
+            Some(REG_LO) => ctx.lo,
+            Some(r) => ctx.regs[r as usize],
+            None => 0
+        });
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        Some(Entry {
+            seq, pc, decoded,
+            unit: Unit::of(&decoded),
+            operands: decoded.sources.map(|s| s.map(|r| (r, Operand::Ready(0)))),
+            dests,
+            values: [None; 2],
+            expected,
+            taken: next != pc.wrapping_add(4),
+            control: decoded.branch || decoded.jump,
+            state: State::Issued
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    #[allow(dead_code)]
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    #[allow(dead_code)]
+    pub fn stalls(&self) -> Stalls {
+        self.stalls
+    }
+
+    /**
+     * Results that differ from the functional core's; empty if the model is equivalent
+     */
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    #[allow(dead_code)]
+    pub fn history(&self) -> &[CycleRecord] {
+        &self.history
+    }
+
+    /**
+     * Writes the state of the ROB and stations at the end of every recorded cycle
+     */
+    pub fn write_dump<W: Write>(&self, mut w: W) -> io::Result<()> {
+
+        let seqs = |s: &[u64]| s.iter().map(|n| format!("#{n}")).collect::<Vec<_>>().join(" ");
+        let one = |s: Option<u64>| s.map(|n| format!("#{n}")).unwrap_or_else(|| String::from("-"));
+
+        for record in &self.history {
+
+            writeln!(w, "cycle {}: issue {}, execute {}, cdb {}, commit {}", record.cycle, one(record.issued),
+                if record.started.is_empty() { String::from("-") } else { seqs(&record.started) }, one(record.broadcast), one(record.committed))?;
+
+            for e in &record.rob {
+                let state = match e.state {
+                    State::Issued => String::from("waiting"),
+                    State::Executing(last) if last >= record.cycle => format!("exec {}", last - record.cycle + 1),
+                    State::Executing(_) => String::from("done"),
+                    State::Written => String::from("written")
+                };
+
+                let detail: Vec<String> = match e.state {
+                    State::Written => (0..2).filter_map(|i| e.dests[i].map(|r| format!("${}={:08x}", reg_name(r), e.values[i].unwrap_or(0)))).collect(),
+                    _ => e.operands.iter().flatten().map(|(r, o)| match o {
+                        Operand::Ready(v) => format!("${}={v:08x}", reg_name(*r)),
+                        Operand::Waiting(seq) => format!("${}<-#{seq}", reg_name(*r))
+                    }).collect()
+                };
+
+                let line = format!("  #{:<5} {:08x}  {:<24} {:<6} {:<8} {}", e.seq, e.pc, Disasm::disassemble(e.decoded.code), e.unit.name(), state, detail.join(" "));
+                writeln!(w, "{}", line.trim_end())?;
+            }
+        }
+
+        w.flush()
+    }
+}
+
+/**
+ * GPRs, HI and LO of core
+ */
+fn registers(core: &Core) -> [Word; 34] {
+    let ctx = core.snapshot(core.get_PC(), 0);
+    let mut regs = [0; 34];
+    regs[..32].copy_from_slice(&ctx.regs);
+    regs[REG_HI as usize] = ctx.hi;
+    regs[REG_LO as usize] = ctx.lo;
+    regs
+}
+
+/**
+ * The summary printed by --tomasulo
+ */
+impl fmt::Display for Tomasulo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+
+    let ipc = if self.cycle == 0 { 0.0 } else { self.retired as f64 / self.cycle as f64 };
+
+    writeln!(f, "Tomasulo: {} instructions in {} cycles (IPC {:.2}), ROB of {}", self.retired, self.cycle, ipc, self.config.rob)?;
+    writeln!(f, "  stalls: {} ROB full, {} stations full, {} on branches, {} on serial instructions",
+        self.stalls.rob_full, self.stalls.stations_full, self.stalls.control, self.stalls.serial)?;
+    writeln!(f, "  CDB busy {:.1}% of cycles", if self.cycle == 0 { 0.0 } else { self.broadcasts as f64 * 100.0 / self.cycle as f64 })?;
+
+    match self.mismatches.len() {
+        0 => write!(f, "  equivalent to the functional core"),
+        n => {
+            write!(f, "  {n} results differ from the functional core:")?;
+            for m in self.mismatches.iter().take(10) { write!(f, "\n    {m}")?; }
+            Ok(())
+        }
+    }
+  }
+}
+
+/**
+ *  TESTS
+ */
+
+#[test]
+fn equivalence() {
+    use super::Definitions::Arch::OP;
+    use super::Definitions::Utils;
+    use super::Devices::Interruptor::ClockMode;
+
+    for entry in std::fs::read_dir("testbins").unwrap() {
+        let path = entry.unwrap().path();
+        let path = path.to_str().unwrap();
+
+        let (t, res) = run_on(path, TomasuloConfig::default());
+        assert!(t.mismatches().is_empty(), "{path}: {}", t);
+
+        //and it ends like the core alone
+        let mut c = Core::with_clock(ClockMode::Off);
+        c.load_RELF(path).unwrap();
+        let alone = c.run();
+        assert_eq!(res.is_ok(), alone.is_ok(), "{path}");
+        assert_eq!(t.retired(), c.instr_count(), "{path}");
+    }
+
+    //coprocessor 0 moves, which only the handler may run
+    let handler: [Word; 14] = [
+        OP::NOP,        //skipped on entry
+        0x401a6800,     //mfc0 $k0, $13
+        0x40086000,     //mfc0 $t0, $12
+        0x35090008,     //ori $t1, $t0, 8
+        0x40896000,     //mtc0 $t1, $12
+        0x40106000,     //mfc0 $s0, $12
+        0x40886000,     //mtc0 $t0, $12
+        0x021a8821,     //addu $s1, $s0, $k0
+        0x400a7000,     //mfc0 $t2, $14
+        0x408a7000,     //mtc0 $t2, $14
+        0x241b000a,     //addiu $k1, $zero, 10
+        0x105b0001,     //beq $v0, $k1, 1, syscall 10 halts
+        OP::RFE,
+        OP::HLT
+    ];
+    let program: [Word; 4] = [
+        0x24020001,     //addiu $v0, $zero, 1
+        OP::SYSCALL,
+        0x2402000a,     //addiu $v0, $zero, 10
+        OP::SYSCALL
+    ];
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_image(&Utils::image_of(&[(0, &handler[..]), (0x4000, &program[..])]), 0x4000);
+    let mut t = Tomasulo::new(TomasuloConfig::default());
+    assert!(t.run(&mut c).is_ok());
+    assert!(t.mismatches().is_empty(), "{}", t);
+    assert_eq!(t.retired(), c.instr_count());
+    assert_eq!(c.instr_count(), 28);
+}
+
+#[test]
+fn out_of_order() {
+    //with 3 cycle ALU ops, addi $v1, $at, 1 is issued while addi $at is executing and
+    //waits for it on the CDB
+    let (t, res) = run_on("testbins/test_pipelined_simple.relf", TomasuloConfig { alu_latency: 3, record: true, ..Default::default() });
+    assert!(res.is_err());
+    assert_eq!(t.retired(), 7);
+
+    let mut out = Vec::new();
+    t.write_dump(&mut out).unwrap();
+    let dump = String::from_utf8(out).unwrap();
+    assert!(dump.starts_with("cycle 1: issue #0, execute -, cdb -, commit -\n"));
+    assert!(dump.contains("  #2     00004008  addi $v1, $at, 1         alu    waiting  $at<-#0\n"));
+
+    //with slow memory, younger ALU instructions are written before older loads
+    let (t, res) = run_on("testbins/testingLS.s.relf", TomasuloConfig { mem_latency: 6, record: true, ..Default::default() });
+    assert!(res.is_ok() && t.mismatches().is_empty());
+    assert!(t.history().iter().any(|r| {
+        let oldest_waiting = r.rob.iter().position(|e| e.state != State::Written);
+        oldest_waiting.is_some_and(|i| r.rob[i + 1..].iter().any(|e| e.state == State::Written && e.unit == Unit::Alu))
+    }));
+
+    //a small ROB fills up
+    let (small, _) = run_on("testbins/testingLS.s.relf", TomasuloConfig { rob: 2, mem_latency: 6, ..Default::default() });
+    assert!(small.stalls().rob_full > 0);
+    assert!(small.cycles() > t.cycles());
+    assert!(small.mismatches().is_empty());
+}
+
+#[cfg(test)]
+fn run_on(path: &str, config: TomasuloConfig) -> (Tomasulo, Result<(), ExecutionError>) {
+    use super::Devices::Interruptor::ClockMode;
+
+    let mut c = Core::with_clock(ClockMode::Off);
+    c.load_RELF(path).unwrap();
+    let mut t = Tomasulo::new(config);
+    let res = t.run(&mut c);
+    (t, res)
+}
@@ -1,6 +1,16 @@
 #![allow(non_snake_case)]
 
 mod Memory;
-mod Definitions;
+pub mod Cache;
+pub mod Predictor;
+pub mod Definitions;
 pub mod Devices;
-pub mod Core;
\ No newline at end of file
+pub mod Core;
+pub mod Debugger;
+pub mod Compare;
+pub mod Pipeline;
+pub mod Tomasulo;
+#[cfg(feature = "jit")]
+pub mod Jit;
+pub mod PipelineChart;
+pub mod Bench;
\ No newline at end of file
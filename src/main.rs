@@ -2,31 +2,393 @@
 
 mod libs;
 use libs::Core::Core;
+use libs::Debugger::Debugger;
+use libs::Compare;
+use libs::Cache::{Cache, CacheConfig, Hierarchy};
+use libs::Predictor::{self, BranchUnit, Btb};
+use libs::Pipeline::{Pipeline, PipelineConfig};
+use libs::Tomasulo::{Tomasulo, TomasuloConfig};
+#[cfg(feature = "jit")]
+use libs::Jit::Jit;
+use libs::PipelineChart::{self, ChartFormat};
+use libs::Bench;
+use libs::Definitions::{Arch, Disasm};
+use libs::Definitions::Log::{self, Filter, Level};
+use libs::Definitions::Trace::{Tracer, TraceFormat, TraceReader};
+use libs::Definitions::Timing::TimingModel;
+use libs::Devices::Interruptor::ClockMode;
+use libs::Definitions::Errors::{ExecutionError, Access};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::panic;
+use std::process;
+use std::time::Duration;
 
 //import macro for pack/unpack
 #[macro_use]
 extern crate structure;
 extern crate clap;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 #[clap(
     author = "Axemt <github.com/Axemt>",
     version = "0.92 built on Feb 21, 2022",
     about = "A MIPS R3000 32b emulator",
-    long_about = None
+    long_about = None,
+    subcommand_negates_reqs = true
 )]
 struct Args {
     #[clap(short, long, help = "File to load to memory", required=true)]
-    filepath : String,
-    #[clap(short, long, help = "Set the verbose flag to show internal processing of the emulator", takes_value = false)]
+    filepath : Option<String>,
+    #[clap(short, long, help = "Set the verbose flag to show internal processing of the emulator; same as --log trace", takes_value = false)]
     verbose : bool,
+    #[clap(long, help = "Log the emulator's internals to stderr, e.g. 'info,core=trace,mem=off': a level of off, error, warn, info, debug or trace, for everything or per subsystem (core, mem, clock...)", required = false, conflicts_with = "verbose")]
+    log : Option<String>,
+    #[clap(short, long, help = "Start an interactive debugger instead of running the program", takes_value = false)]
+    debug : bool,
 
     //TODO: See args.entry block
     #[clap(short, long, help = "Set a custom entrypoint (Required for .bin files); If using a hex value, prefix with '0x'", required = false, default_value = "")]
-    entry : String
+    entry : String,
+    #[clap(short, long, help = "Load symbols used in fault reports from a file with '<hex address> <name>' lines", required = false)]
+    symbols : Option<String>,
+    #[clap(long, help = "Write a core dump to this file if the program faults", required = false)]
+    core_dump : Option<String>,
+    #[clap(long, help = "Fire the clock interrupt every this many cycles", required = false, default_value_t = Arch::CLOCK_PERIOD_CYCLES, parse(try_from_str = parse_period))]
+    clock_period : u64,
+    #[clap(long, help = "Fire the clock interrupt every second of wall-clock time instead; runs are not reproducible", takes_value = false)]
+    realtime_clock : bool,
+    #[clap(long, help = "Log every interrupt and keyboard input to this file, to replay the run with --replay", required = false)]
+    record : Option<String>,
+    #[clap(long, help = "Replay the interrupts and keyboard input logged with --record; clock options are taken from the log", required = false, conflicts_with = "record")]
+    replay : Option<String>,
+    #[clap(long, help = "Write a record per retired instruction to this file", required = false)]
+    trace : Option<String>,
+    #[clap(long, help = "Format of the --trace file", possible_values = ["text", "binary"], default_value = "text")]
+    trace_format : String,
+    #[clap(long, help = "Run in lockstep with a reference trace (ours, or a SPIM/MARS-style listing) and stop at the first divergence", required = false, conflicts_with = "debug")]
+    compare : Option<String>,
+    #[clap(long, help = "Print execution statistics when the program ends", takes_value = false)]
+    stats : bool,
+    #[clap(long, help = "Write execution statistics as JSON to this file when the program ends", required = false)]
+    stats_json : Option<String>,
+    #[clap(long, help = "Count cycles with the timing model in this file of 'key = value' lines, or 'flat' for one cycle per instruction", required = false)]
+    timing : Option<String>,
+    #[clap(long, help = "Add an instruction cache, e.g. 'size=4K,ways=2,line=16,policy=lru,latency=1'", required = false)]
+    icache : Option<String>,
+    #[clap(long, help = "Add a data cache, with 'write=back|through' and 'alloc=yes|no' besides the --icache keys", required = false)]
+    dcache : Option<String>,
+    #[clap(long, help = "Add a unified L2 cache behind the instruction and data caches", required = false)]
+    l2 : Option<String>,
+    #[clap(long, help = "Cycles to reach memory past the last cache", default_value = "20")]
+    mem_latency : u64,
+    #[clap(long, help = "Simulate these branch predictors side by side and report their accuracy: not-taken, backward, 1bit, 2bit, gshare", required = false, use_value_delimiter = true)]
+    predictor : Vec<String>,
+    #[clap(long, help = "Predictor tables have 2^N entries, and gshare keeps N bits of history", default_value = "10")]
+    predictor_bits : u32,
+    #[clap(long, help = "Entries of the branch target buffer shared by the predictors", default_value = "64")]
+    btb_entries : usize,
+    #[clap(long, help = "Charge the timing model's mispredict penalty for the first predictor's mispredictions, instead of the taken-branch penalty", takes_value = false, requires = "predictor")]
+    predictor_timing : bool,
+    #[clap(long, help = "Run on the five-stage pipeline model and print its summary at the end", takes_value = false, conflicts_with = "debug")]
+    pipeline : bool,
+    #[clap(long, help = "Disable forwarding in the pipeline model", takes_value = false, requires = "pipeline")]
+    no_forwarding : bool,
+    #[clap(long, help = "Write the pipeline occupancy of every cycle to this file", required = false, requires = "pipeline")]
+    pipeline_log : Option<String>,
+    #[clap(long, help = "Write a chart of instructions against cycles to this file", required = false, requires = "pipeline")]
+    pipeline_chart : Option<String>,
+    #[clap(long, help = "Format of the --pipeline-chart file; vcd is a waveform of the pipeline registers", possible_values = ["text", "html", "vcd"], default_value = "text")]
+    chart_format : String,
+    #[clap(long, help = "Only chart the cycles FIRST:LAST, both included", required = false, requires = "pipeline-chart", parse(try_from_str = parse_cycles))]
+    chart_cycles : Option<(u64, u64)>,
+    #[clap(long, help = "Run on the Tomasulo out-of-order model, checking every result against the functional core, and print its summary at the end", takes_value = false, conflicts_with_all = &["debug", "pipeline"])]
+    tomasulo : bool,
+    #[clap(long, help = "Reorder buffer entries of the Tomasulo model", default_value = "8")]
+    rob : usize,
+    #[clap(long, help = "Write the reorder buffer and stations of every cycle to this file", required = false, requires = "tomasulo")]
+    tomasulo_log : Option<String>,
+    #[cfg(feature = "jit")]
+    #[clap(long, help = "Translate hot basic blocks to native code; the pipeline, Tomasulo and debugger runs still interpret", takes_value = false)]
+    jit : bool,
+    #[cfg(feature = "jit")]
+    #[clap(long, help = "Translate a block once it has been reached this many times", default_value = "50", requires = "jit")]
+    jit_threshold : u32,
+
+    #[clap(subcommand)]
+    command : Option<Command>
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(about = "Load a core dump written with --core-dump into the debugger")]
+    Postmortem {
+        #[clap(help = "Core dump to load")]
+        dump : String
+    },
+    #[clap(about = "Run the benchmark workloads and report guest MIPS; run from the repository root, perf_test reads testbins/")]
+    Bench {
+        #[clap(long, help = "Only run these workloads: perf_test, arith, memory, syscall, interrupts, devices", required = false, use_value_delimiter = true)]
+        only : Vec<String>,
+        #[clap(long, help = "Loop iterations of the synthetic workloads; perf_test runs once per 4096", default_value = "500000")]
+        iterations : u32,
+        #[clap(long, help = "Times each workload is run; the table shows the median and the best run", default_value = "3")]
+        runs : usize,
+        #[clap(long, help = "Write the results as JSON to this file", required = false)]
+        json : Option<String>,
+        #[cfg(feature = "jit")]
+        #[clap(long, help = "Run the workloads with the JIT", takes_value = false)]
+        jit : bool
+    }
+}
+
+// Process exit codes, so scripts can tell apart why a run failed
+const EXIT_LOAD_ERROR: i32     = 2;
+const EXIT_GUEST_FAULT: i32    = 3;
+const EXIT_INTERNAL_ERROR: i32 = 4;
+const EXIT_DIVERGED: i32       = 5;
+
+/**
+ * Reports an error that happened before execution started and exits
+ */
+fn load_failure(kind: &str, emsg: &str) -> ! {
+    eprintln!("LOAD FAILED: {kind}: {emsg}");
+    process::exit(EXIT_LOAD_ERROR)
+}
+
+/**
+ * Prints a report of a guest fault: the error, the faulting PC and instruction,
+ * the nearest symbol and the register file at the time of the fault
+ */
+fn report_fault(cpu: &Core, e: &ExecutionError) {
+
+    let ctx = e.context();
+
+    eprintln!("EXECUTION FAILED: {}: {e}", e.kind());
+    eprintln!("  at {}", cpu.symbolize(ctx.pc));
+
+    match e {
+        ExecutionError::MemError { access: Access::Fetch, .. } => eprintln!("  instruction: <could not be fetched>"),
+        _ => eprintln!("  instruction: 0x{:08x}  {}", ctx.code, Disasm::disassemble(ctx.code))
+    }
+
+    if let ExecutionError::MemError { addr, size, access, .. } = e {
+        eprintln!("  {access} of {size} bytes at {}", cpu.symbolize(*addr));
+    }
+
+    eprintln!("\nRegisters:\n{ctx}");
+    eprintln!("\nBacktrace:\n{}", cpu.backtrace());
+}
+
+/**
+ * Runs the debugger on stdin/stdout until the user quits
+ */
+fn debug(cpu: &mut Core) {
+
+    let stdin = std::io::stdin();
+
+    if let Err(eobj) = Debugger::new().repl(cpu, stdin.lock(), &mut std::io::stdout()) {
+        eprintln!("INTERNAL ERROR: debugger I/O failed: {eobj}");
+        process::exit(EXIT_INTERNAL_ERROR);
+    }
+}
+
+/**
+ * Loads a core dump and opens it in the debugger
+ */
+#[cfg(not(tarpaulin_include))]
+fn postmortem(path: &str) {
+
+    let mut cpu = Box::<Core>::new(Core::new());
+
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(eobj) => load_failure("IOError", &eobj.to_string())
+    };
+
+    match cpu.load_core(BufReader::new(file)) {
+        Ok(Some(fault)) => {
+            println!("Core dump of a run that failed with {}: {}", fault.kind, fault.message);
+            println!("Backtrace:\n{}", cpu.backtrace());
+        }
+        Ok(None) => {}
+        Err(eobj) => load_failure("SnapshotError", &eobj.to_string())
+    }
+
+    debug(&mut cpu);
+}
+
+/**
+ * Writes a core dump of cpu after a fault
+ */
+fn write_core_dump(cpu: &Core, e: &ExecutionError, path: &str) {
+
+    let res = File::create(path).map_err(|eobj| eobj.into()).and_then(|f| cpu.dump_core(BufWriter::new(f), Some(e)));
+
+    match res {
+        Ok(()) => eprintln!("\nCore dumped to {path}"),
+        Err(eobj) => eprintln!("\nCould not write core dump to {path}: {eobj}")
+    }
+}
+
+/**
+ * Writes the input journal of cpu for --record
+ */
+fn write_journal(cpu: &Core, path: &str) {
+
+    let res = File::create(path).map_err(|eobj| eobj.into()).and_then(|f| cpu.save_journal(BufWriter::new(f)));
+
+    if let Err(eobj) = res {
+        eprintln!("Could not write input log to {path}: {eobj}");
+    }
+}
+
+/**
+ * Flushes the --trace file
+ */
+fn finish_trace(cpu: &mut Core) {
+    if let Err(eobj) = cpu.finish_trace() {
+        eprintln!("Could not write trace: {eobj}");
+    }
+}
+
+/**
+ * Runs the bench subcommand
+ */
+#[cfg(not(tarpaulin_include))]
+fn bench(command: &Command) {
+
+    let Command::Bench { only, iterations, runs, json, .. } = command else { return };
+
+    let configure = |_cpu: &mut Core| {
+        #[cfg(feature = "jit")]
+        if let Command::Bench { jit: true, .. } = command {
+            match Jit::new(50) {
+                Ok(jit) => _cpu.set_jit(Some(jit)),
+                Err(emsg) => load_failure("JitError", &emsg)
+            }
+        }
+    };
+
+    let names: Vec<String> = if only.is_empty() { Bench::names().iter().map(|n| n.to_string()).collect() } else { only.clone() };
+    let mut results = Vec::new();
+
+    for name in names {
+        match Bench::measure(name.trim(), *iterations, *runs, &configure) {
+            Ok(m) => results.push(m),
+            Err(emsg) => load_failure("BenchError", &emsg)
+        }
+    }
+
+    print!("{}", Bench::report(&results));
+
+    if let Some(path) = json {
+        if let Err(eobj) = std::fs::write(path, Bench::to_json(&results)) {
+            eprintln!("Could not write benchmark results to {path}: {eobj}");
+        }
+    }
+}
+
+/**
+ * Prints the statistics for --stats and writes them for --stats-json
+ */
+fn report_stats(cpu: &Core, print: bool, json: &Option<String>) {
+
+    if print {
+        println!("\n{}", cpu.stats());
+        #[cfg(feature = "jit")]
+        if let Some(jit) = cpu.jit() { println!("{jit}"); }
+    }
+
+    if let Some(path) = json {
+        if let Err(eobj) = std::fs::write(path, cpu.stats().to_json()) {
+            eprintln!("Could not write statistics to {path}: {eobj}");
+        }
+    }
+}
+
+/**
+ * Prints the accuracy of the --predictor predictors
+ */
+fn report_predictors(cpu: &Core) {
+    if let Some(unit) = cpu.predictors() {
+        println!();
+        let symbol = |pc| match cpu.nearest_symbol(pc) {
+            Some((name, 0)) => format!("<{name}>"),
+            Some((name, off)) => format!("<{name}+0x{off:x}>"),
+            None => String::new()
+        };
+        if let Err(eobj) = unit.write_report(std::io::stdout(), &symbol) {
+            eprintln!("Could not write branch prediction report: {eobj}");
+        }
+    }
+}
+
+/**
+ * Prints the Tomasulo summary and writes the --tomasulo-log file
+ *
+ * RETURNS:
+ *
+ *  true if the model's results differ from the functional core's
+ */
+fn report_tomasulo(t: &Tomasulo, log: &Option<String>) -> bool {
+
+    println!("\n{t}");
+
+    if let Some(path) = log {
+        if let Err(eobj) = File::create(path).and_then(|f| t.write_dump(BufWriter::new(f))) {
+            eprintln!("Could not write Tomasulo log to {path}: {eobj}");
+        }
+    }
+
+    !t.mismatches().is_empty()
+}
+
+/**
+ * Parses the cycles of --clock-period; a clock firing every cycle would never let the guest run
+ */
+fn parse_period(period: &str) -> Result<u64, String> {
+    match period.trim().parse::<u64>() {
+        Ok(0) => Err(String::from("the clock period must be at least 1 cycle")),
+        Ok(cycles) => Ok(cycles),
+        Err(_) => Err(format!("invalid cycle count '{period}'"))
+    }
+}
+
+/**
+ * Parses the FIRST:LAST range of --chart-cycles
+ */
+fn parse_cycles(range: &str) -> Result<(u64, u64), String> {
+    let (first, last) = range.split_once(':').ok_or_else(|| String::from("expected FIRST:LAST"))?;
+    let first = first.trim().parse::<u64>().map_err(|_| format!("invalid cycle '{first}'"))?;
+    let last = last.trim().parse::<u64>().map_err(|_| format!("invalid cycle '{last}'"))?;
+    match first <= last {
+        true => Ok((first, last)),
+        false => Err(String::from("FIRST is after LAST"))
+    }
+}
+
+/**
+ * Prints the pipeline summary and writes the --pipeline-log and --pipeline-chart files
+ */
+fn report_pipeline(p: &Pipeline, log: &Option<String>, chart: &Option<String>, format: ChartFormat, cycles: Option<(u64, u64)>) {
+
+    println!("\n{p}");
+
+    if let Some(path) = log {
+        if let Err(eobj) = File::create(path).and_then(|f| p.write_occupancy(BufWriter::new(f))) {
+            eprintln!("Could not write pipeline log to {path}: {eobj}");
+        }
+    }
+
+    if let Some(path) = chart {
+        let (first, last) = cycles.unwrap_or((1, p.cycles()));
+        if let Err(eobj) = File::create(path).and_then(|f| PipelineChart::export(p.history(), first, last, format, BufWriter::new(f))) {
+            eprintln!("Could not write pipeline chart to {path}: {eobj}");
+        }
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -34,17 +396,106 @@ fn main() {
 
     let args = Args::parse();
 
-    let v = args.verbose;
-    let filepath = args.filepath;
+    let filter = match &args.log {
+        Some(spec) => Filter::parse(spec).unwrap_or_else(|emsg| load_failure("LogError", &emsg)),
+        None => Filter::all(if args.verbose { Level::Trace } else { Level::Off })
+    };
+    if !cfg!(feature = "logging") && filter != Filter::all(Level::Off) {
+        eprintln!("Logging was left out of this build; rebuild with the logging feature for -v and --log");
+    }
+    Log::set_filter(filter);
+
+    if let Some(Command::Postmortem { dump }) = &args.command {
+        postmortem(dump);
+        return;
+    }
+
+    if let Some(command @ Command::Bench { .. }) = &args.command {
+        bench(command);
+        return;
+    }
+
+    let filepath = args.filepath.unwrap();
+
+    let clock = if args.replay.is_some() {
+        ClockMode::Off
+    } else if args.realtime_clock {
+        ClockMode::RealTime(Duration::new(1, 0))
+    } else {
+        ClockMode::Virtual(args.clock_period)
+    };
 
+    let mut cpu = Box::<Core>::new(Core::with_clock(clock));
 
-    let mut cpu = Box::<Core>::new(Core::new(v));
+    if let Some(path) = &args.replay {
+        let res = File::open(path).map_err(|eobj| eobj.into()).and_then(|f| cpu.load_journal(BufReader::new(f)));
+        if let Err(eobj) = res {
+            load_failure("JournalError", &eobj.to_string());
+        }
+    }
+
+    if args.record.is_some() {
+        cpu.set_journaling(true);
+    }
+
+    if args.stats || args.stats_json.is_some() {
+        cpu.set_opcode_counts(true);
+    }
+
+    match args.timing.as_deref() {
+        None => {}
+        Some("flat") => cpu.set_timing(TimingModel::flat()),
+        Some(path) => {
+            let model = std::fs::read_to_string(path).map_err(|eobj| eobj.to_string()).and_then(|text| TimingModel::parse(&text));
+            match model {
+                Ok(model) => cpu.set_timing(model),
+                Err(emsg) => load_failure("TimingError", &emsg)
+            }
+        }
+    }
+
+    if args.icache.is_some() || args.dcache.is_some() || args.l2.is_some() {
+        let cache = |name: &'static str, spec: &Option<String>| spec.as_ref().map(|spec| match CacheConfig::parse(spec) {
+            Ok(config) => Cache::new(name, config),
+            Err(emsg) => load_failure("CacheError", &emsg)
+        });
+        cpu.set_caches(Some(Hierarchy {
+            icache: cache("L1I", &args.icache),
+            dcache: cache("L1D", &args.dcache),
+            l2: cache("L2", &args.l2),
+            memory_latency: args.mem_latency
+        }));
+    }
+
+    if !args.predictor.is_empty() {
+        let predictors = args.predictor.iter().map(|name| match Predictor::by_name(name.trim(), args.predictor_bits) {
+            Ok(p) => p,
+            Err(emsg) => load_failure("PredictorError", &emsg)
+        }).collect();
+        cpu.set_predictors(Some(BranchUnit::new(predictors, Btb::new(args.btb_entries), args.predictor_timing)));
+    }
+
+    #[cfg(feature = "jit")]
+    if args.jit {
+        match Jit::new(args.jit_threshold) {
+            Ok(jit) => cpu.set_jit(Some(jit)),
+            Err(emsg) => load_failure("JitError", &emsg)
+        }
+    }
+
+    if let Some(path) = &args.trace {
+        let format = if args.trace_format == "binary" { TraceFormat::Binary } else { TraceFormat::Text };
+        let res = File::create(path).and_then(|f| Tracer::new(Box::new(BufWriter::new(f)), format));
+        match res {
+            Ok(tracer) => cpu.start_trace(tracer),
+            Err(eobj) => load_failure("IOError", &eobj.to_string())
+        }
+    }
 
     if filepath.ends_with(".relf") {
 
-        match cpu.load_RELF(&filepath) {
-            Err(eobj) => { panic!("{eobj}") }
-            _ => {}
+        if let Err(eobj) = cpu.load_RELF(&filepath) {
+            load_failure(eobj.kind(), &eobj.to_string());
         }
 
     } else { //raw .bin file
@@ -55,28 +506,104 @@ fn main() {
 
             let s = args.entry;
 
-            let entry: u32;
-
-            if s.starts_with("0x") | s.starts_with("0X") {
-                entry = u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"),16).unwrap();
+            let entry = if s.starts_with("0x") | s.starts_with("0X") {
+                u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches("0X"),16)
             } else {
-                entry = s.parse::<u32>().unwrap();
-            }
+                s.parse::<u32>()
+            };
+
+            let entry = match entry {
+                Ok(e) => e,
+                Err(eobj) => load_failure("EntrypointError", &format!("Could not parse entrypoint '{s}': {eobj}"))
+            };
 
-            match cpu.load_bin(&filepath,entry) {
-                Err(eobj) => { panic!("{eobj}") }
-                _ => {}
+            if let Err(eobj) = cpu.load_bin(&filepath,entry) {
+                load_failure("IOError", &eobj.to_string());
             }
 
         } else {
-            panic!("A raw binary file was detected, but no entrypoint was given");
+            load_failure("EntrypointError", "A raw binary file was detected, but no entrypoint was given");
         }
 
     }
 
-    match cpu.run() {
-        Err(eobj) => { panic!("EXECUTION FAILED: {eobj}") }
-        _ => {}
+    if let Some(sympath) = args.symbols {
+        if let Err(eobj) = cpu.load_symbols(&sympath) {
+            load_failure("SymbolError", &eobj.to_string());
+        }
+    }
+
+    if let Some(path) = &args.compare {
+        let outcome = File::open(path).and_then(|f| TraceReader::new(BufReader::new(f)))
+            .and_then(|mut reference| Compare::compare(&mut cpu, &mut reference, &mut std::io::stdout()));
+        finish_trace(&mut cpu);
+        match outcome {
+            Ok(Compare::Outcome::Match(_)) => return,
+            Ok(Compare::Outcome::Diverged(_)) => process::exit(EXIT_DIVERGED),
+            Err(eobj) => load_failure("TraceError", &eobj.to_string())
+        }
     }
 
+    if args.debug {
+        debug(&mut cpu);
+        if let Some(path) = &args.record { write_journal(&cpu, path); }
+        finish_trace(&mut cpu);
+        report_stats(&cpu, args.stats, &args.stats_json);
+        report_predictors(&cpu);
+        return;
+    }
+
+    let mut pipeline = args.pipeline.then(|| Pipeline::new(PipelineConfig { forwarding: !args.no_forwarding, record: args.pipeline_log.is_some() || args.pipeline_chart.is_some() }));
+
+    let mut tomasulo = args.tomasulo.then(|| Tomasulo::new(TomasuloConfig { rob: args.rob.max(1), record: args.tomasulo_log.is_some(), ..Default::default() }));
+
+    // a panic inside the emulator is our bug, not the guest's
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| match (&mut pipeline, &mut tomasulo) {
+        (Some(p), _) => p.run(&mut cpu),
+        (_, Some(t)) => t.run(&mut cpu),
+        _ => cpu.run()
+    }));
+
+    if let (Ok(_), Some(p)) = (&res, &pipeline) {
+        let format = match args.chart_format.as_str() {
+            "html" => ChartFormat::Html,
+            "vcd" => ChartFormat::Vcd,
+            _ => ChartFormat::Text
+        };
+        report_pipeline(p, &args.pipeline_log, &args.pipeline_chart, format, args.chart_cycles);
+    }
+
+    let diverged = match (&res, &tomasulo) {
+        (Ok(_), Some(t)) => report_tomasulo(t, &args.tomasulo_log),
+        _ => false
+    };
+
+    //the log is most useful when the run failed, so write it first
+    if let (Ok(_), Some(path)) = (&res, &args.record) {
+        write_journal(&cpu, path);
+    }
+    if res.is_ok() {
+        finish_trace(&mut cpu);
+        report_stats(&cpu, args.stats, &args.stats_json);
+        report_predictors(&cpu);
+    }
+
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(eobj)) => {
+            report_fault(&cpu, &eobj);
+            if let Some(path) = args.core_dump {
+                write_core_dump(&cpu, &eobj, &path);
+            }
+            process::exit(EXIT_GUEST_FAULT);
+        }
+        Err(_) => {
+            eprintln!("INTERNAL ERROR: the emulator panicked; this is a bug in the emulator, not in the guest program");
+            process::exit(EXIT_INTERNAL_ERROR);
+        }
+    }
+
+    if diverged {
+        process::exit(EXIT_DIVERGED);
+    }
 }